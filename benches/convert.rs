@@ -0,0 +1,56 @@
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use jpeg_driver_rs::convert;
+use jpeg_driver_rs::frame::{Frame, PixelFormat};
+
+const WIDTH: usize = 1920;
+const HEIGHT: usize = 1080;
+const ITERATIONS: u32 = 50;
+
+//1回あたりの平均時間
+fn bench<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn report(name: &str, time: Duration) {
+    let mpix = (WIDTH * HEIGHT) as f64 / time.as_secs_f64() / 1e6;
+    println!("{:<32} {:>8.3} ms {:>8.1} Mpix/s", name, time.as_secs_f64() * 1000.0, mpix);
+}
+
+fn main() {
+    let threads = convert::available_threads();
+    //udmabufの代わり
+    let mut dma_buf = vec![0u8; WIDTH * HEIGHT * 3];
+
+    for format in [PixelFormat::Rgb8, PixelFormat::Bgr8, PixelFormat::Rgba8, PixelFormat::Yuyv8] {
+        let len = WIDTH * HEIGHT * format.bytes_per_pixel();
+        let data: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+        let frame = Frame::new(WIDTH, HEIGHT, format, data).unwrap();
+        println!("{:?} {}x{}", format, WIDTH, HEIGHT);
+
+        //現在の経路: 変換してからバッファにコピー(write_to_buf相当)
+        report("  convert + copy", bench(|| {
+            let rgb = frame.to_rgb8();
+            dma_buf.copy_from_slice(&rgb);
+            black_box(&dma_buf);
+        }));
+
+        report("  direct", bench(|| {
+            convert::frame_to_rgb8(&frame, &mut dma_buf, 1).unwrap();
+            black_box(&dma_buf);
+        }));
+
+        if threads > 1 {
+            report(&format!("  direct ({} threads)", threads), bench(|| {
+                convert::frame_to_rgb8(&frame, &mut dma_buf, threads).unwrap();
+                black_box(&dma_buf);
+            }));
+        }
+    }
+}
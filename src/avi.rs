@@ -0,0 +1,377 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+
+// ヘッダ内の位置
+const RIFF_SIZE_POS: u64 = 4;
+const AVIH_TOTAL_FRAMES_POS: u64 = 48;
+const AVIH_BUFFER_SIZE_POS: u64 = 60;
+const STRH_LENGTH_POS: u64 = 140;
+const STRH_BUFFER_SIZE_POS: u64 = 144;
+const MOVI_SIZE_POS: u64 = 216;
+//'movi'の位置(idx1のオフセットの基準)
+const MOVI_POS: u64 = 220;
+//最初のフレームのチャンクの位置
+const HEADER_LEN: u64 = 224;
+
+const FRAME_ID: &[u8; 4] = b"00dc";
+const AVIF_HASINDEX: u32 = 0x10;
+const AVIIF_KEYFRAME: u32 = 0x10;
+
+/// エンコード済みのJPEGをMotion JPEGのAVIファイルに書き込む
+///
+/// `finish`(またはdrop)でidx1とヘッダのフレーム数を書き込む。
+/// 途中で異常終了したファイルは`AviWriter::recover`で再生できる形に直せる
+pub struct AviWriter<W: Write + Seek = File> {
+    file: BufWriter<W>,
+    //各フレームの(moviからのオフセット, サイズ)
+    index: Vec<(u32, u32)>,
+    //次のチャンクの位置
+    pos: u64,
+    max_frame: u32,
+    finished: bool,
+}
+
+impl<W: Write + Seek> AviWriter<W> {
+    /// 書き込み先(先頭から書く)を指定してヘッダを書き込む
+    pub fn new(writer: W, width: usize, height: usize, fps: f64) -> Result<Self> {
+        if width == 0 || height == 0 || width > u16::MAX as usize || height > u16::MAX as usize {
+            return Err(Error::InvalidConfig(format!("invalid AVI frame size {}x{}", width, height)));
+        }
+        if !(fps.is_finite() && fps > 0.0) {
+            return Err(Error::InvalidConfig(format!("invalid frame rate {}", fps)));
+        }
+
+        let mut file = BufWriter::new(writer);
+        file.write_all(&header(width as u32, height as u32, fps))?;
+
+        Ok(AviWriter {
+            file,
+            index: Vec::new(),
+            pos: HEADER_LEN,
+            max_frame: 0,
+            finished: false,
+        })
+    }
+
+    /// 1フレーム(JPEG)を追加
+    pub fn write_frame(&mut self, jpeg: &[u8]) -> Result<()> {
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            return Err(Error::InvalidBitstream("AVI frame does not start with SOI".to_string()));
+        }
+
+        //idx1を含めてRIFFのサイズがu32に収まるか
+        let chunk_len = 8 + pad(jpeg.len() as u64);
+        let total = self.pos + chunk_len + 8 + (self.index.len() as u64 + 1) * 16;
+        if total > u32::MAX as u64 {
+            return Err(Error::SizeExceeded { what: "AVI file", size: total as usize, limit: u32::MAX as usize });
+        }
+
+        self.file.write_all(FRAME_ID)?;
+        self.file.write_all(&(jpeg.len() as u32).to_le_bytes())?;
+        self.file.write_all(jpeg)?;
+        if jpeg.len() % 2 == 1 {
+            self.file.write_all(&[0])?;
+        }
+
+        self.index.push(((self.pos - MOVI_POS) as u32, jpeg.len() as u32));
+        self.max_frame = self.max_frame.max(jpeg.len() as u32);
+        self.pos += chunk_len;
+        Ok(())
+    }
+
+    /// 書き込んだフレーム数
+    pub fn frames(&self) -> usize {
+        self.index.len()
+    }
+
+    /// バッファをファイルに書き出す
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// idx1とヘッダを書き込んでファイルを閉じる
+    pub fn finish(mut self) -> Result<()> {
+        self.finalize()
+    }
+
+    fn finalize(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        self.file.write_all(&index_chunk(&self.index))?;
+        self.file.flush()?;
+
+        let file = self.file.get_mut();
+        write_sizes(file, self.pos, self.index.len() as u32, self.max_frame)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl AviWriter {
+    /// ファイルを作成してヘッダを書き込む
+    pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize, fps: f64) -> Result<Self> {
+        AviWriter::new(File::create(path)?, width, height, fps)
+    }
+
+    /// 異常終了などでidx1が書かれていないAVIファイルを修復する
+    ///
+    /// moviの中の完全なフレームだけを残して後ろを切り詰め、
+    /// idx1とヘッダを書き直す。残ったフレーム数を返す
+    pub fn recover<P: AsRef<Path>>(path: P) -> Result<usize> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut head = [0u8; HEADER_LEN as usize];
+        file.read_exact(&mut head)
+            .map_err(|_| Error::InvalidBitstream("AVI header is truncated".to_string()))?;
+        if &head[0..4] != b"RIFF" || &head[8..12] != b"AVI " || &head[MOVI_POS as usize..HEADER_LEN as usize] != b"movi" {
+            return Err(Error::InvalidBitstream("not an AVI file written by AviWriter".to_string()));
+        }
+
+        //完全なフレームのチャンクをたどる
+        let mut index = Vec::new();
+        let mut max_frame = 0;
+        let mut pos = HEADER_LEN;
+        while pos + 8 <= file_len {
+            let mut chunk = [0u8; 8];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut chunk)?;
+            if &chunk[0..4] != FRAME_ID {
+                break;
+            }
+            let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+            let end = pos + 8 + pad(size as u64);
+            if end > file_len {
+                break;
+            }
+            index.push(((pos - MOVI_POS) as u32, size));
+            max_frame = max_frame.max(size);
+            pos = end;
+        }
+
+        file.set_len(pos)?;
+        file.seek(SeekFrom::Start(pos))?;
+        file.write_all(&index_chunk(&index))?;
+        write_sizes(&mut file, pos, index.len() as u32, max_frame)?;
+        file.sync_all()?;
+        Ok(index.len())
+    }
+}
+
+impl<W: Write + Seek> Drop for AviWriter<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finalize() {
+            log::warn!("failed to finalize AVI file: {}", e);
+        }
+    }
+}
+
+//チャンクは2バイト境界にそろえる
+fn pad(len: u64) -> u64 {
+    (len + 1) & !1
+}
+
+fn index_chunk(index: &[(u32, u32)]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + index.len() * 16);
+    out.extend_from_slice(b"idx1");
+    out.extend_from_slice(&((index.len() * 16) as u32).to_le_bytes());
+    for &(offset, size) in index {
+        out.extend_from_slice(FRAME_ID);
+        out.extend_from_slice(&AVIIF_KEYFRAME.to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&size.to_le_bytes());
+    }
+    out
+}
+
+//movi_end: moviの終わり(idx1の位置)
+fn write_sizes<W: Write + Seek>(file: &mut W, movi_end: u64, frames: u32, max_frame: u32) -> Result<()> {
+    let riff_size = movi_end + 8 + frames as u64 * 16 - 8;
+    let fields = [
+        (RIFF_SIZE_POS, riff_size as u32),
+        (AVIH_TOTAL_FRAMES_POS, frames),
+        (AVIH_BUFFER_SIZE_POS, max_frame),
+        (STRH_LENGTH_POS, frames),
+        (STRH_BUFFER_SIZE_POS, max_frame),
+        (MOVI_SIZE_POS, (movi_end - MOVI_POS) as u32),
+    ];
+    for (pos, value) in fields {
+        file.seek(SeekFrom::Start(pos))?;
+        file.write_all(&value.to_le_bytes())?;
+    }
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+//フレーム数などは0のまま書き、finishで埋める
+fn header(width: u32, height: u32, fps: f64) -> Vec<u8> {
+    //フレームレートはrate/scaleで表す
+    let scale = 1000u32;
+    let rate = (fps * scale as f64).round() as u32;
+    let usec_per_frame = (1_000_000.0 / fps).round() as u32;
+
+    let mut h = Vec::with_capacity(HEADER_LEN as usize);
+    let u32le = |h: &mut Vec<u8>, v: u32| h.extend_from_slice(&v.to_le_bytes());
+    let u16le = |h: &mut Vec<u8>, v: u16| h.extend_from_slice(&v.to_le_bytes());
+
+    h.extend_from_slice(b"RIFF");
+    u32le(&mut h, 0);
+    h.extend_from_slice(b"AVI ");
+
+    h.extend_from_slice(b"LIST");
+    u32le(&mut h, 192);
+    h.extend_from_slice(b"hdrl");
+
+    //avih
+    h.extend_from_slice(b"avih");
+    u32le(&mut h, 56);
+    u32le(&mut h, usec_per_frame);
+    u32le(&mut h, 0); //dwMaxBytesPerSec
+    u32le(&mut h, 0); //dwPaddingGranularity
+    u32le(&mut h, AVIF_HASINDEX);
+    u32le(&mut h, 0); //dwTotalFrames
+    u32le(&mut h, 0); //dwInitialFrames
+    u32le(&mut h, 1); //dwStreams
+    u32le(&mut h, 0); //dwSuggestedBufferSize
+    u32le(&mut h, width);
+    u32le(&mut h, height);
+    h.extend_from_slice(&[0; 16]);
+
+    h.extend_from_slice(b"LIST");
+    u32le(&mut h, 116);
+    h.extend_from_slice(b"strl");
+
+    //strh
+    h.extend_from_slice(b"strh");
+    u32le(&mut h, 56);
+    h.extend_from_slice(b"vids");
+    h.extend_from_slice(b"MJPG");
+    u32le(&mut h, 0); //dwFlags
+    u16le(&mut h, 0); //wPriority
+    u16le(&mut h, 0); //wLanguage
+    u32le(&mut h, 0); //dwInitialFrames
+    u32le(&mut h, scale);
+    u32le(&mut h, rate);
+    u32le(&mut h, 0); //dwStart
+    u32le(&mut h, 0); //dwLength
+    u32le(&mut h, 0); //dwSuggestedBufferSize
+    u32le(&mut h, u32::MAX); //dwQuality
+    u32le(&mut h, 0); //dwSampleSize
+    u16le(&mut h, 0);
+    u16le(&mut h, 0);
+    u16le(&mut h, width as u16);
+    u16le(&mut h, height as u16);
+
+    //strf (BITMAPINFOHEADER)
+    h.extend_from_slice(b"strf");
+    u32le(&mut h, 40);
+    u32le(&mut h, 40);
+    u32le(&mut h, width);
+    u32le(&mut h, height);
+    u16le(&mut h, 1); //biPlanes
+    u16le(&mut h, 24); //biBitCount
+    h.extend_from_slice(b"MJPG");
+    u32le(&mut h, width * height * 3);
+    h.extend_from_slice(&[0; 16]);
+
+    h.extend_from_slice(b"LIST");
+    u32le(&mut h, 4);
+    h.extend_from_slice(b"movi");
+
+    debug_assert_eq!(h.len() as u64, HEADER_LEN);
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn le32(data: &[u8], pos: u64) -> u32 {
+        let pos = pos as usize;
+        u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    //SOIで始まる長さlenのダミーフレーム
+    fn frame(len: usize) -> Vec<u8> {
+        let mut jpeg = vec![0xAB; len];
+        jpeg[..2].copy_from_slice(&[0xFF, 0xD8]);
+        jpeg
+    }
+
+    fn write_avi(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut writer = AviWriter::new(Cursor::new(&mut data), 64, 48, 30.0).unwrap();
+        for jpeg in frames {
+            writer.write_frame(jpeg).unwrap();
+        }
+        assert_eq!(writer.frames(), frames.len());
+        writer.finish().unwrap();
+        data
+    }
+
+    //ヘッダの合計とidx1が各フレームを指しているか確認
+    fn check_avi(data: &[u8], frames: &[Vec<u8>]) {
+        let max_frame = frames.iter().map(Vec::len).max().unwrap_or(0) as u32;
+        assert_eq!(le32(data, RIFF_SIZE_POS) as usize, data.len() - 8);
+        assert_eq!(le32(data, AVIH_TOTAL_FRAMES_POS) as usize, frames.len());
+        assert_eq!(le32(data, STRH_LENGTH_POS) as usize, frames.len());
+        assert_eq!(le32(data, AVIH_BUFFER_SIZE_POS), max_frame);
+        assert_eq!(le32(data, STRH_BUFFER_SIZE_POS), max_frame);
+
+        let idx1 = MOVI_POS + le32(data, MOVI_SIZE_POS) as u64;
+        assert_eq!(&data[idx1 as usize..idx1 as usize + 4], b"idx1");
+        assert_eq!(le32(data, idx1 + 4) as usize, frames.len() * 16);
+        for (i, jpeg) in frames.iter().enumerate() {
+            let entry = idx1 + 8 + i as u64 * 16;
+            assert_eq!(&data[entry as usize..entry as usize + 4], FRAME_ID);
+            assert_eq!(le32(data, entry + 4), AVIIF_KEYFRAME);
+            let chunk = (MOVI_POS + le32(data, entry + 8) as u64) as usize;
+            assert_eq!(le32(data, entry + 12) as usize, jpeg.len());
+            assert_eq!(&data[chunk..chunk + 4], FRAME_ID);
+            assert_eq!(&data[chunk + 8..chunk + 8 + jpeg.len()], &jpeg[..]);
+        }
+    }
+
+    #[test]
+    fn writer_fills_header_and_index() {
+        //奇数長のフレームはパディングされる
+        let frames = vec![frame(100), frame(51), frame(200)];
+        let data = write_avi(&frames);
+        assert_eq!(data.len() as u64, HEADER_LEN + (8 + 100) + (8 + 52) + (8 + 200) + 8 + 3 * 16);
+        check_avi(&data, &frames);
+    }
+
+    #[test]
+    fn writer_rejects_non_jpeg_frame() {
+        let mut data = Vec::new();
+        let mut writer = AviWriter::new(Cursor::new(&mut data), 64, 48, 30.0).unwrap();
+        assert!(writer.write_frame(b"not a jpeg").is_err());
+        assert_eq!(writer.frames(), 0);
+    }
+
+    #[test]
+    fn recover_keeps_complete_frames() {
+        let frames = vec![frame(100), frame(51), frame(200)];
+        let data = write_avi(&frames);
+
+        //idx1が無く、3フレーム目が途中で切れたファイル
+        let cut = HEADER_LEN as usize + (8 + 100) + (8 + 52) + 8 + 150;
+        let path = std::env::temp_dir().join(format!("avi_recover_{}.avi", std::process::id()));
+        std::fs::write(&path, &data[..cut]).unwrap();
+
+        let recovered = AviWriter::recover(&path);
+        let fixed = std::fs::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(recovered.unwrap(), 2);
+        check_avi(&fixed.unwrap(), &frames[..2]);
+    }
+}
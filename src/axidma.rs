@@ -5,17 +5,13 @@ use std::io::{self, Read};
 use std::ptr;
 use std::os::unix::io::RawFd;
 use serde::{Serialize, Deserialize};
-use anyhow::{anyhow, Result, Context};
+use crate::error::{Error, Result};
 
 use crate::udma::{Udma,Owner};
-use crate::uio::Uio;
+use crate::uio::{Uio, PAGE_SIZE};
+use crate::devlock::LockMode;
+use crate::hwinfo::IpInfo;
 
-use xipdriver_rs::json_as_map;
-use xipdriver_rs::json_as_str;
-use xipdriver_rs::json_as_u32;
-
-
-const PAGE_SIZE: usize = 0x1000;
 
 const MM2S_DMACR: usize = 0x00;
 const MM2S_DMASR: usize = 0x04;
@@ -28,6 +24,12 @@ const S2MM_DA: usize = 0x48;
 const S2MM_DA_MSB: usize = 0x4C;
 const S2MM_LENGTH: usize = 0x58;
 
+//転送長のレジスタの最大値(Width of Buffer Length Register = 26bit)
+const MAX_LENGTH: usize = (1 << 26) - 1;
+
+// DMASRのエラービット(DMAIntErr, DMASlvErr, DMADecErr, SGIntErr, SGSlvErr, SGDecErr)
+const DMASR_ERR_MASK: u32 = 0x770;
+
 pub struct Adma {
     // fd: RawFd,
     // mem: *mut u32,
@@ -37,17 +39,29 @@ pub struct Adma {
 
 impl Adma {
     pub fn new(hw_info: &serde_json::Value) -> Result<Self> {
-        //vfrmbufのハードウェア情報を取得
-        let hw_object = json_as_map!(hw_info);
-        let uio_name = json_as_str!(hw_object["uio"]);
-        let udmabuf_name = json_as_str!(hw_object["udmabuf"][0]);
+        Adma::new_with_lock(hw_info, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    pub fn new_with_lock(hw_info: &serde_json::Value, lock_mode: LockMode) -> Result<Self> {
+        //ハードウェア情報を検証して取得
+        let info = IpInfo::parse_entry(hw_info, true)?;
+        Adma::from_info(&info, lock_mode)
+    }
+
+    /// 検証済みのハードウェア情報からオープン
+    pub fn from_info(info: &IpInfo, lock_mode: LockMode) -> Result<Self> {
+        let uio_name = &info.uio;
+        let udmabuf_name = info.first_udmabuf().ok_or_else(|| {
+            Error::InvalidConfig(format!("{} has no udmabuf", info.uio))
+        })?;
 
         //uioをオープン
         //let dev_name = Adma::check_axi_dma_uio_num(uio_name)?;
-        let uio = Uio::new(&uio_name,PAGE_SIZE)?;
+        let uio = Uio::new_with_lock(&uio_name,PAGE_SIZE,lock_mode)?;
 
         //u-dma-bufferをオープン
-        let mut udmabuf = Udma::new(udmabuf_name)?;
+        let mut udmabuf = Udma::open_with_lock(udmabuf_name,lock_mode)?;
 
         Ok(Adma {
             // fd,
@@ -137,6 +151,13 @@ impl Adma {
         self.write_mem32(S2MM_LENGTH, length);
     }
 
+    /// 受信バッファの大きさをS2MMの転送長に設定して受信を開始する
+    ///
+    /// レジスタの最大値を超えるバッファはその長さで切り詰める
+    pub fn set_s2mm_length_to_buf(&self) {
+        self.set_s2mm_length(self.buf.size.min(MAX_LENGTH) as u32);
+    }
+
     pub fn read_ctrl(&self) -> u32 {
         self.read_mem32(S2MM_DMACR)
     }
@@ -161,6 +182,15 @@ impl Adma {
         self.read_mem32(S2MM_DA)
     }
 
+    /// S2MMのステータスがエラーを示していないか確認
+    pub fn check_s2mm_error(&self) -> Result<()> {
+        let status = self.read_status();
+        if status & DMASR_ERR_MASK != 0 {
+            return Err(Error::DmaStatus { status });
+        }
+        Ok(())
+    }
+
 
     pub fn start(&mut self) -> Result<()>{
         self.buf.change_owner(Owner::Device)?;
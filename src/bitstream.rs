@@ -0,0 +1,298 @@
+use crate::error::{Error, Result};
+
+// JPEGのマーカー
+pub const SOI: u8 = 0xD8;
+pub const EOI: u8 = 0xD9;
+pub const SOS: u8 = 0xDA;
+pub const DQT: u8 = 0xDB;
+pub const DRI: u8 = 0xDD;
+pub const DHT: u8 = 0xC4;
+pub const SOF0: u8 = 0xC0;
+pub const APP0: u8 = 0xE0;
+pub const APP1: u8 = 0xE1;
+pub const COM: u8 = 0xFE;
+pub const RST0: u8 = 0xD0;
+
+/// マーカーセグメント
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub marker: u8,
+    /// マーカー(0xFF)の位置
+    pub offset: usize,
+    /// 長さフィールドを含むセグメントの長さ(マーカーの2バイトは含まない)
+    pub len: usize,
+}
+
+impl Segment {
+    /// 長さフィールドの後ろのデータの範囲
+    pub fn payload(&self) -> std::ops::Range<usize> {
+        self.offset + 4..self.offset + 2 + self.len
+    }
+
+    /// マーカーを含むセグメント全体の範囲
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.offset..self.offset + 2 + self.len
+    }
+}
+
+/// SOFの成分情報
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Component {
+    pub id: u8,
+    /// 水平サンプリング係数
+    pub h: u8,
+    /// 垂直サンプリング係数
+    pub v: u8,
+    /// 量子化テーブルID
+    pub tq: u8,
+}
+
+/// SOFのフレームヘッダ
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameHeader {
+    pub marker: u8,
+    pub precision: u8,
+    pub width: u16,
+    pub height: u16,
+    pub components: Vec<Component>,
+}
+
+impl FrameHeader {
+    /// MCUの幅と高さ(画素)
+    pub fn mcu_size(&self) -> (usize, usize) {
+        let h = self.components.iter().map(|c| c.h).max().unwrap_or(1) as usize;
+        let v = self.components.iter().map(|c| c.v).max().unwrap_or(1) as usize;
+        (h * 8, v * 8)
+    }
+}
+
+/// 解析したJPEGの構造
+#[derive(Debug, Clone, PartialEq)]
+pub struct JpegInfo {
+    /// SOSまでのマーカーセグメント(SOSを含む)
+    pub segments: Vec<Segment>,
+    pub frame: FrameHeader,
+    /// DRIで指定されたリスタートインターバル(MCU数)
+    pub restart_interval: Option<u16>,
+    /// エントロピー符号化データの範囲(SOSの直後からEOIの直前まで)
+    pub scan_data: std::ops::Range<usize>,
+}
+
+impl JpegInfo {
+    /// 指定したマーカーのセグメント
+    pub fn find(&self, marker: u8) -> impl Iterator<Item = &Segment> + '_ {
+        self.segments.iter().filter(move |s| s.marker == marker)
+    }
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidBitstream(msg)
+}
+
+/// JPEGのマーカー構造を解析する
+///
+/// SOI/EOI, SOF, DQT, DHT, SOSがそろっていなければエラー
+pub fn parse(data: &[u8]) -> Result<JpegInfo> {
+    if data.len() < 4 || data[0] != 0xFF || data[1] != SOI {
+        return Err(invalid("missing SOI marker".to_string()));
+    }
+
+    let mut segments = Vec::new();
+    let mut frame = None;
+    let mut restart_interval = None;
+    let mut pos = 2;
+
+    //SOSまでのセグメント
+    loop {
+        //マーカー前の0xFFの埋め草を読み飛ばす
+        while pos + 1 < data.len() && data[pos] == 0xFF && data[pos + 1] == 0xFF {
+            pos += 1;
+        }
+        if pos + 4 > data.len() {
+            return Err(invalid(format!("stream truncated at {} bytes before SOS", data.len())));
+        }
+        if data[pos] != 0xFF {
+            return Err(invalid(format!("marker expected at offset {}, found {:#04x}", pos, data[pos])));
+        }
+
+        let marker = data[pos + 1];
+        if marker == EOI {
+            return Err(invalid(format!("unexpected EOI at offset {} before SOS", pos)));
+        }
+        let len = u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        if len < 2 || pos + 2 + len > data.len() {
+            return Err(invalid(format!(
+                "segment {:#04x} at offset {} has invalid length {}", marker, pos, len
+            )));
+        }
+        let segment = Segment { marker, offset: pos, len };
+        let payload = &data[segment.payload()];
+
+        match marker {
+            0xC0..=0xCF if marker != DHT && marker != 0xC8 && marker != 0xCC => {
+                if frame.is_some() {
+                    return Err(invalid(format!("multiple SOF segments (second at offset {})", pos)));
+                }
+                frame = Some(parse_sof(marker, payload, pos)?);
+            }
+            DRI => {
+                if payload.len() < 2 {
+                    return Err(invalid(format!("DRI at offset {} is too short", pos)));
+                }
+                let interval = u16::from_be_bytes([payload[0], payload[1]]);
+                restart_interval = if interval == 0 { None } else { Some(interval) };
+            }
+            _ => {}
+        }
+
+        segments.push(segment);
+        pos = segment.offset + 2 + len;
+        if marker == SOS {
+            break;
+        }
+    }
+
+    let frame = frame.ok_or_else(|| invalid("missing SOF segment".to_string()))?;
+    if !segments.iter().any(|s| s.marker == DQT) {
+        return Err(invalid("missing DQT segment".to_string()));
+    }
+    if !segments.iter().any(|s| s.marker == DHT) {
+        return Err(invalid("missing DHT segment".to_string()));
+    }
+
+    //エントロピー符号化データの終わり(RST以外のマーカー)を探す
+    let scan_start = pos;
+    let mut p = scan_start;
+    let scan_end = loop {
+        if p + 1 >= data.len() {
+            return Err(invalid(format!("missing EOI marker (stream truncated at {} bytes)", data.len())));
+        }
+        if data[p] == 0xFF {
+            match data[p + 1] {
+                0x00 | 0xFF => p += 1,
+                m if (RST0..RST0 + 8).contains(&m) => p += 2,
+                _ => break p,
+            }
+        } else {
+            p += 1;
+        }
+    };
+
+    if data[scan_end + 1] != EOI {
+        return Err(invalid(format!(
+            "expected EOI after scan data at offset {}, found marker {:#04x}", scan_end, data[scan_end + 1]
+        )));
+    }
+
+    Ok(JpegInfo { segments, frame, restart_interval, scan_data: scan_start..scan_end })
+}
+
+/// JPEGを解析し、SOFのサイズが期待どおりか確認する
+pub fn validate(data: &[u8], width: usize, height: usize) -> Result<JpegInfo> {
+    let info = parse(data)?;
+    if info.frame.width as usize != width || info.frame.height as usize != height {
+        return Err(invalid(format!(
+            "SOF size {}x{} does not match configured frame {}x{}",
+            info.frame.width, info.frame.height, width, height
+        )));
+    }
+    Ok(info)
+}
+
+fn parse_sof(marker: u8, payload: &[u8], offset: usize) -> Result<FrameHeader> {
+    if payload.len() < 6 {
+        return Err(invalid(format!("SOF at offset {} is too short", offset)));
+    }
+    let precision = payload[0];
+    let height = u16::from_be_bytes([payload[1], payload[2]]);
+    let width = u16::from_be_bytes([payload[3], payload[4]]);
+    let count = payload[5] as usize;
+    if payload.len() < 6 + count * 3 {
+        return Err(invalid(format!("SOF at offset {} is truncated", offset)));
+    }
+    if width == 0 || height == 0 || count == 0 {
+        return Err(invalid(format!("SOF at offset {} has empty size {}x{}", offset, width, height)));
+    }
+
+    let components = payload[6..6 + count * 3]
+        .chunks_exact(3)
+        .map(|c| Component { id: c[0], h: c[1] >> 4, v: c[1] & 0x0F, tq: c[2] })
+        .collect();
+
+    Ok(FrameHeader { marker, precision, width, height, components })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soft_jpeg::SoftJpegEncoder;
+
+    fn encode(width: usize, height: usize) -> Vec<u8> {
+        let mut encoder = SoftJpegEncoder::new();
+        encoder.config(width, height);
+        encoder.encode(&vec![0x80; width * height * 3]).unwrap()
+    }
+
+    fn message(result: Result<JpegInfo>) -> String {
+        match result {
+            Err(Error::InvalidBitstream(msg)) => msg,
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    //指定したマーカーのセグメントをすべて取り除く
+    fn remove_segments(jpeg: &[u8], marker: u8) -> Vec<u8> {
+        let info = parse(jpeg).unwrap();
+        let mut out = jpeg.to_vec();
+        for segment in info.find(marker).collect::<Vec<_>>().into_iter().rev() {
+            out.drain(segment.range());
+        }
+        out
+    }
+
+    #[test]
+    fn parse_accepts_encoder_output() {
+        let jpeg = encode(32, 16);
+        let info = validate(&jpeg, 32, 16).unwrap();
+        assert_eq!(info.frame.components.len(), 3);
+        assert_eq!(info.segments.last().unwrap().marker, SOS);
+        assert_eq!(info.scan_data.end, jpeg.len() - 2);
+    }
+
+    #[test]
+    fn parse_rejects_truncated_stream() {
+        let jpeg = encode(32, 16);
+        let info = parse(&jpeg).unwrap();
+        let sos = info.find(SOS).next().unwrap().offset;
+        assert!(message(parse(&jpeg[..sos + 2])).contains("truncated"));
+        assert!(message(parse(&jpeg[..1])).contains("SOI"));
+    }
+
+    #[test]
+    fn parse_rejects_missing_eoi() {
+        let jpeg = encode(32, 16);
+        assert!(message(parse(&jpeg[..jpeg.len() - 2])).contains("missing EOI"));
+    }
+
+    #[test]
+    fn parse_rejects_missing_tables() {
+        let jpeg = encode(32, 16);
+        assert!(message(parse(&remove_segments(&jpeg, DQT))).contains("missing DQT"));
+        assert!(message(parse(&remove_segments(&jpeg, DHT))).contains("missing DHT"));
+    }
+
+    #[test]
+    fn parse_rejects_missing_sos() {
+        let jpeg = encode(32, 16);
+        let sos = parse(&jpeg).unwrap().find(SOS).next().unwrap().offset;
+        let mut out = jpeg[..sos].to_vec();
+        out.extend_from_slice(&[0xFF, EOI]);
+        assert!(message(parse(&out)).contains("before SOS"));
+    }
+
+    #[test]
+    fn validate_rejects_size_mismatch() {
+        let jpeg = encode(32, 16);
+        assert!(message(validate(&jpeg, 16, 32)).contains("does not match"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+
+use crate::error::{Error, Result};
+use crate::frame::PixelFormat;
+use crate::hwinfo::{param, EncoderHwInfo, IpInfo};
+use crate::uio::{Uio, PAGE_SIZE};
+
+//対応しているJPEGエンコーダIPのメジャーバージョン
+const SUPPORTED_ENCODER_MAJOR: u32 = 1;
+//v_frmbuf_rdのレジスタ配置が同じになった最初のメジャーバージョン
+const MIN_VFRMBUF_MAJOR: u32 = 2;
+
+//出力サイズのレジスタのデフォルトのオフセット
+const DEFAULT_OUT_LENGTH_OFFSET: usize = 0x04;
+
+/// IPのバージョン
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IpVersion {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl IpVersion {
+    /// VLNV(`vendor:library:name:major.minor`)からバージョンを取り出す
+    pub fn from_vlnv(vlnv: &str) -> Option<Self> {
+        let version = vlnv.rsplit(':').next()?;
+        let (major, minor) = version.split_once('.').unwrap_or((version, "0"));
+        Some(IpVersion { major: major.trim().parse().ok()?, minor: minor.trim().parse().ok()? })
+    }
+
+    /// バージョンレジスタの値から
+    pub fn from_reg(val: u32) -> Self {
+        IpVersion { major: val >> 16, minor: val & 0xffff }
+    }
+}
+
+impl fmt::Display for IpVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// エンコーダのパイプラインでできること
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// JPEGエンコーダIPのバージョン(レジスタまたはVLNVから。不明ならNone)
+    pub version: Option<IpVersion>,
+    /// フレームバッファのバージョン(VLNVから)
+    pub vfrmbuf_version: Option<IpVersion>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// ハードウェアがそのまま読み出せる入力フォーマット
+    pub native_formats: Vec<PixelFormat>,
+    /// `encode_frame`で受け付ける入力フォーマット(ソフトウェアで変換するものを含む)
+    pub input_formats: Vec<PixelFormat>,
+    /// 量子化テーブル(品質)を設定できるか
+    pub quality_control: bool,
+    /// フレームバッファの割り込みが接続されているか
+    pub interrupts: bool,
+    /// スケーラで解像度を変えられるか
+    pub scaling: bool,
+    /// 1クロックあたりの画素数(ROIの幅はこの倍数)
+    pub samples_per_clock: u32,
+    /// 出力サイズのレジスタのオフセット
+    pub out_length_offset: usize,
+    /// エンコーダのソフトリセットのレジスタのオフセット(なければNone)
+    pub soft_reset_offset: Option<usize>,
+}
+
+impl Capabilities {
+    /// hwinfoのパラメータとバージョンレジスタ(あれば)から調べる
+    ///
+    /// 対応していないリビジョンのIPの場合はエラー
+    pub fn detect(hw_info: &EncoderHwInfo, encoder_uio: &Uio) -> Result<Self> {
+        let encoder = &hw_info.encoder;
+        let version = match reg_offset(encoder, param::VERSION_OFFSET)? {
+            Some(offset) => Some(IpVersion::from_reg(encoder_uio.read_mem32(offset))),
+            None => vlnv_version(encoder),
+        };
+        let vfrmbuf_version = vlnv_version(&hw_info.vfrmbuf);
+        check_versions(&hw_info.encoder_name, version, &hw_info.vfrmbuf_name, vfrmbuf_version)?;
+
+        let scaling = hw_info.scaler.as_ref()
+            .is_some_and(|s| s.param_u32(param::HSC_OFFSET).is_some());
+
+        Ok(Capabilities {
+            version,
+            vfrmbuf_version,
+            max_width: hw_info.max_width(),
+            max_height: hw_info.max_height(),
+            native_formats: vec![PixelFormat::Rgb8],
+            input_formats: vec![
+                PixelFormat::Rgb8, PixelFormat::Bgr8, PixelFormat::Rgba8, PixelFormat::Gray8, PixelFormat::Yuyv8,
+            ],
+            quality_control: encoder.param_bool(param::HAS_QTABLE),
+            interrupts: hw_info.vfrmbuf.param_bool(param::HAS_INTERRUPT),
+            scaling,
+            samples_per_clock: hw_info.vfrmbuf.param_u32(param::SAMPLES_PER_CLOCK).unwrap_or(1).max(1),
+            out_length_offset: reg_offset(encoder, param::OUT_LENGTH_OFFSET)?.unwrap_or(DEFAULT_OUT_LENGTH_OFFSET),
+            soft_reset_offset: reg_offset(encoder, param::SOFT_RESET_OFFSET)?,
+        })
+    }
+}
+
+//対応しているリビジョンか(バージョンが不明なものは通す)
+fn check_versions(
+    encoder_name: &str, version: Option<IpVersion>, vfrmbuf_name: &str, vfrmbuf_version: Option<IpVersion>,
+) -> Result<()> {
+    if let Some(version) = version {
+        if version.major != SUPPORTED_ENCODER_MAJOR {
+            return Err(Error::IncompatibleHardware(format!(
+                "{} version {} is not supported (expected {}.x)", encoder_name, version, SUPPORTED_ENCODER_MAJOR
+            )));
+        }
+    }
+    if let Some(version) = vfrmbuf_version {
+        if version.major < MIN_VFRMBUF_MAJOR {
+            return Err(Error::IncompatibleHardware(format!(
+                "{} version {} is older than {}.0", vfrmbuf_name, version, MIN_VFRMBUF_MAJOR
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn vlnv_version(info: &IpInfo) -> Option<IpVersion> {
+    info.vlnv.as_deref().and_then(IpVersion::from_vlnv)
+}
+
+//パラメータで指定されたレジスタのオフセット(レジスタ空間内の4バイト境界であること)
+fn reg_offset(info: &IpInfo, key: &str) -> Result<Option<usize>> {
+    match info.param_u32(key).map(|v| v as usize) {
+        Some(offset) if offset >= PAGE_SIZE || !offset.is_multiple_of(4) => Err(Error::IncompatibleHardware(format!(
+            "{} {:#x} is not a register offset", key, offset
+        ))),
+        offset => Ok(offset),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ip(params: serde_json::Value) -> IpInfo {
+        IpInfo::parse_entry(&json!({ "uio": "uio0", "params": params }), false).unwrap()
+    }
+
+    #[test]
+    fn from_vlnv_reads_major_and_minor() {
+        let v = |major, minor| Some(IpVersion { major, minor });
+        assert_eq!(IpVersion::from_vlnv("xilinx.com:hls:v_frmbuf_rd:2.4"), v(2, 4));
+        assert_eq!(IpVersion::from_vlnv("user:hls:jpeg_encoder:1"), v(1, 0));
+        assert_eq!(IpVersion::from_vlnv("1.2"), v(1, 2));
+        assert_eq!(IpVersion::from_vlnv("xilinx.com:hls:v_frmbuf_rd:x.y"), None);
+        assert_eq!(IpVersion::from_vlnv("xilinx.com:hls:v_frmbuf_rd:"), None);
+        assert_eq!(IpVersion::from_reg(0x0002_0001), IpVersion { major: 2, minor: 1 });
+    }
+
+    #[test]
+    fn reg_offset_checks_alignment_and_range() {
+        let info = ip(json!({ "A": "0x10", "B": 6, "C": PAGE_SIZE, "D": PAGE_SIZE - 4 }));
+        assert_eq!(reg_offset(&info, "A").unwrap(), Some(0x10));
+        assert_eq!(reg_offset(&info, "NONE").unwrap(), None);
+        assert_eq!(reg_offset(&info, "D").unwrap(), Some(PAGE_SIZE - 4));
+        assert!(matches!(reg_offset(&info, "B"), Err(Error::IncompatibleHardware(_))));
+        assert!(matches!(reg_offset(&info, "C"), Err(Error::IncompatibleHardware(_))));
+    }
+
+    #[test]
+    fn check_versions_gates_unsupported_revisions() {
+        let v = |major, minor| Some(IpVersion { major, minor });
+        assert!(check_versions("enc", v(1, 3), "vfb", v(2, 0)).is_ok());
+        assert!(check_versions("enc", None, "vfb", None).is_ok());
+        assert!(check_versions("enc", v(1, 0), "vfb", v(3, 1)).is_ok());
+        match check_versions("enc", v(2, 0), "vfb", v(2, 0)) {
+            Err(Error::IncompatibleHardware(msg)) => assert!(msg.contains("enc version 2.0")),
+            other => panic!("unexpected {:?}", other),
+        }
+        match check_versions("enc", v(1, 0), "vfb", v(1, 9)) {
+            Err(Error::IncompatibleHardware(msg)) => assert!(msg.contains("vfb version 1.9")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
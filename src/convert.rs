@@ -0,0 +1,204 @@
+use std::thread;
+
+use crate::error::{Error, Result};
+use crate::frame::{Frame, PixelFormat};
+
+//これより画素数が少ない場合はスレッドを分けない
+const MIN_PIXELS_PER_THREAD: usize = 0x10000;
+
+/// 使用可能なCPU数
+pub fn available_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// 1行をRGB8に変換する
+///
+/// `dst`の長さ(画素数 * 3)の分だけ変換する。固定長のチャンク単位で処理して
+/// 境界チェックを外し、自動ベクトル化されやすい形にしている
+pub fn row_to_rgb8(format: PixelFormat, src: &[u8], dst: &mut [u8]) {
+    match format {
+        PixelFormat::Rgb8 => {
+            let len = dst.len();
+            dst.copy_from_slice(&src[..len]);
+        }
+        PixelFormat::Bgr8 => {
+            for (d, s) in dst.chunks_exact_mut(3).zip(src.chunks_exact(3)) {
+                d[0] = s[2];
+                d[1] = s[1];
+                d[2] = s[0];
+            }
+        }
+        PixelFormat::Rgba8 => {
+            for (d, s) in dst.chunks_exact_mut(3).zip(src.chunks_exact(4)) {
+                d[0] = s[0];
+                d[1] = s[1];
+                d[2] = s[2];
+            }
+        }
+        PixelFormat::Gray8 => {
+            for (d, &y) in dst.chunks_exact_mut(3).zip(src.iter()) {
+                d[0] = y;
+                d[1] = y;
+                d[2] = y;
+            }
+        }
+        PixelFormat::Yuyv8 => {
+            //2画素ごとにU, Vを共有する(BT.601, リミテッドレンジ)
+            for (d, s) in dst.chunks_exact_mut(6).zip(src.chunks_exact(4)) {
+                let u = s[1] as i32 - 128;
+                let v = s[3] as i32 - 128;
+                let r = 409 * v + 128;
+                let g = -100 * u - 208 * v + 128;
+                let b = 516 * u + 128;
+
+                let y0 = 298 * (s[0] as i32 - 16);
+                let y1 = 298 * (s[2] as i32 - 16);
+                d[0] = clamp8((y0 + r) >> 8);
+                d[1] = clamp8((y0 + g) >> 8);
+                d[2] = clamp8((y0 + b) >> 8);
+                d[3] = clamp8((y1 + r) >> 8);
+                d[4] = clamp8((y1 + g) >> 8);
+                d[5] = clamp8((y1 + b) >> 8);
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn clamp8(v: i32) -> u8 {
+    v.clamp(0, 255) as u8
+}
+
+/// 画像をRGB8に変換して`dst`に書き込む
+///
+/// `src_stride`/`dst_stride`は1行のバイト数。`threads`が2以上なら行を分けて並列に変換する
+#[allow(clippy::too_many_arguments)]
+pub fn convert_to_rgb8(
+    src: &[u8],
+    format: PixelFormat,
+    width: usize,
+    height: usize,
+    src_stride: usize,
+    dst: &mut [u8],
+    dst_stride: usize,
+    threads: usize,
+) -> Result<()> {
+    let src_row = width * format.bytes_per_pixel();
+    let dst_row = width * 3;
+    if src_stride < src_row || dst_stride < dst_row {
+        return Err(Error::InvalidConfig(format!(
+            "stride is smaller than a row (src {} < {} or dst {} < {})", src_stride, src_row, dst_stride, dst_row
+        )));
+    }
+    if height == 0 {
+        return Ok(());
+    }
+    let src_len = src_stride * (height - 1) + src_row;
+    let dst_len = dst_stride * (height - 1) + dst_row;
+    if src.len() < src_len {
+        return Err(Error::InvalidImage(format!("source needs {} bytes, got {}", src_len, src.len())));
+    }
+    if dst.len() < dst_len {
+        return Err(Error::SizeExceeded { what: "converted frame", size: dst_len, limit: dst.len() });
+    }
+
+    let convert_rows = |src: &[u8], dst: &mut [u8]| {
+        for (s, d) in src.chunks(src_stride).zip(dst.chunks_mut(dst_stride)) {
+            row_to_rgb8(format, &s[..src_row], &mut d[..dst_row]);
+        }
+    };
+
+    let threads = threads.clamp(1, (width * height / MIN_PIXELS_PER_THREAD).max(1));
+    if threads == 1 {
+        convert_rows(&src[..src_len], &mut dst[..dst_len]);
+        return Ok(());
+    }
+
+    //行の帯に分けてスレッドごとに変換
+    let rows = height.div_ceil(threads);
+    thread::scope(|scope| {
+        let bands = src[..src_len].chunks(src_stride * rows).zip(dst[..dst_len].chunks_mut(dst_stride * rows));
+        for (s, d) in bands {
+            scope.spawn(move || convert_rows(s, d));
+        }
+    });
+    Ok(())
+}
+
+/// フレームをRGB8に変換して`dst`に行を詰めて書き込む
+pub fn frame_to_rgb8(frame: &Frame, dst: &mut [u8], threads: usize) -> Result<()> {
+    convert_to_rgb8(frame.data(), frame.format(), frame.width(), frame.height(), frame.stride(), dst, frame.width() * 3, threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert_row(format: PixelFormat, src: &[u8], pixels: usize) -> Vec<u8> {
+        let mut dst = vec![0; pixels * 3];
+        row_to_rgb8(format, src, &mut dst);
+        dst
+    }
+
+    #[test]
+    fn kernels_reorder_channels() {
+        assert_eq!(convert_row(PixelFormat::Rgb8, &[1, 2, 3, 4, 5, 6], 2), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(convert_row(PixelFormat::Bgr8, &[1, 2, 3, 4, 5, 6], 2), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(convert_row(PixelFormat::Rgba8, &[1, 2, 3, 0xFF, 4, 5, 6, 0], 2), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(convert_row(PixelFormat::Gray8, &[7, 200], 2), [7, 7, 7, 200, 200, 200]);
+    }
+
+    #[test]
+    fn yuyv_uses_bt601_limited_range() {
+        //黒と白で同じU, Vを共有する
+        assert_eq!(convert_row(PixelFormat::Yuyv8, &[16, 128, 235, 128], 2), [0, 0, 0, 255, 255, 255]);
+        //中間のグレー
+        assert_eq!(convert_row(PixelFormat::Yuyv8, &[126, 128, 126, 128], 2), [128, 128, 128, 128, 128, 128]);
+        //赤(Y=81, Cb=90, Cr=240)
+        assert_eq!(convert_row(PixelFormat::Yuyv8, &[81, 90, 81, 240], 2), [255, 0, 0, 255, 0, 0]);
+        //範囲外はクリップする
+        assert_eq!(convert_row(PixelFormat::Yuyv8, &[0, 128, 255, 128], 2), [0, 0, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    fn threaded_conversion_matches_single_thread() {
+        //4スレッドに分かれる大きさで、高さはスレッド数で割り切れない
+        let (width, height) = (520, 511);
+        assert!(width * height >= 4 * MIN_PIXELS_PER_THREAD && height % 4 != 0);
+
+        for format in [PixelFormat::Bgr8, PixelFormat::Yuyv8] {
+            let src_stride = width * format.bytes_per_pixel() + 12;
+            let dst_stride = width * 3 + 8;
+            let src: Vec<u8> = (0..src_stride * height).map(|i| (i * 31 + i / 7) as u8).collect();
+
+            let mut single = vec![0xAA; dst_stride * height];
+            let mut threaded = vec![0xAA; dst_stride * height];
+            convert_to_rgb8(&src, format, width, height, src_stride, &mut single, dst_stride, 1).unwrap();
+            convert_to_rgb8(&src, format, width, height, src_stride, &mut threaded, dst_stride, 4).unwrap();
+            assert!(single == threaded);
+
+            //行の後ろのパディングには書き込まない
+            assert!(threaded.chunks(dst_stride).all(|row| row[width * 3..].iter().all(|&b| b == 0xAA)));
+            let mut row = vec![0; width * 3];
+            row_to_rgb8(format, &src[src_stride * (height - 1)..], &mut row);
+            assert_eq!(threaded[dst_stride * (height - 1)..][..width * 3], row[..]);
+        }
+    }
+
+    #[test]
+    fn convert_checks_strides_and_lengths() {
+        let mut dst = vec![0; 12];
+        assert!(matches!(
+            convert_to_rgb8(&[0; 12], PixelFormat::Rgb8, 2, 2, 5, &mut dst, 6, 1),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            convert_to_rgb8(&[0; 11], PixelFormat::Rgb8, 2, 2, 6, &mut dst, 6, 1),
+            Err(Error::InvalidImage(_))
+        ));
+        assert!(matches!(
+            convert_to_rgb8(&[0; 12], PixelFormat::Rgb8, 2, 2, 6, &mut dst[..11], 6, 1),
+            Err(Error::SizeExceeded { .. })
+        ));
+    }
+}
@@ -0,0 +1,130 @@
+use libc::{fcntl, F_OFD_GETLK, F_OFD_SETLK, F_OFD_SETLKW, F_UNLCK, F_WRLCK, SEEK_SET};
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::error::{Error, Result};
+
+//ロック取得の再試行間隔
+const RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// デバイスロックの取得方法
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum LockMode {
+    /// すぐに取得できなければエラー
+    #[default]
+    NoWait,
+    /// 取得できるまで待つ
+    Wait,
+    /// 指定時間まで待ち、取得できなければエラー
+    Timeout(Duration),
+}
+
+fn new_flock(l_type: i32) -> libc::flock {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = l_type as libc::c_short;
+    lock.l_whence = SEEK_SET as libc::c_short;
+    lock.l_start = 0;
+    lock.l_len = 0;
+    lock
+}
+
+/// ロックを保持しているプロセスのpidを調べる
+///
+/// OFDロックの保持者はpidがわからない(-1になる)のでNone
+fn holder_pid(fd: RawFd) -> Option<i32> {
+    let mut lock = new_flock(F_WRLCK);
+    let ret = unsafe { fcntl(fd, F_OFD_GETLK, &mut lock) };
+    if ret < 0 || lock.l_type == F_UNLCK as libc::c_short || lock.l_pid <= 0 {
+        return None;
+    }
+    Some(lock.l_pid)
+}
+
+fn busy_error(fd: RawFd, dev_path: &str) -> Error {
+    Error::DeviceBusy { path: dev_path.to_string(), pid: holder_pid(fd) }
+}
+
+/// オープンしたデバイスファイルに排他ロックをかける
+///
+/// OFDロックなので、ロックはプロセスではなくオープンしたファイルに属する。
+/// 同じプロセスで同じデバイスを再度オープンしてもロックは取れず、
+/// 他のfdをcloseしてもこのfdのロックは解放されない。
+/// ロックはこのfdをcloseしたとき(プロセス終了時を含む)に解放される
+pub(crate) fn lock_device(fd: RawFd, dev_path: &str, mode: LockMode) -> Result<()> {
+    let lock = new_flock(F_WRLCK);
+
+    match mode {
+        LockMode::Wait => {
+            loop {
+                if unsafe { fcntl(fd, F_OFD_SETLKW, &lock) } == 0 {
+                    return Ok(());
+                }
+                let err = std::io::Error::last_os_error();
+                //シグナルで中断された場合は再試行
+                if err.raw_os_error() != Some(libc::EINTR) {
+                    return Err(Error::Open { path: dev_path.to_string(), source: err });
+                }
+            }
+        }
+        LockMode::NoWait | LockMode::Timeout(_) => {
+            let start = Instant::now();
+            loop {
+                if unsafe { fcntl(fd, F_OFD_SETLK, &lock) } == 0 {
+                    return Ok(());
+                }
+                let err = std::io::Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EACCES) | Some(libc::EAGAIN) => {}
+                    _ => {
+                        return Err(Error::Open { path: dev_path.to_string(), source: err });
+                    }
+                }
+
+                match mode {
+                    LockMode::Timeout(timeout) if start.elapsed() < timeout => {
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                    _ => return Err(busy_error(fd, dev_path)),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    #[test]
+    fn second_open_in_same_process_is_busy() {
+        let path = std::env::temp_dir().join(format!("devlock_test_{}", std::process::id()));
+        let first = File::create(&path).unwrap();
+        let second = File::options().read(true).write(true).open(&path).unwrap();
+        let third = File::options().read(true).write(true).open(&path).unwrap();
+        let dev_path = path.to_str().unwrap();
+
+        lock_device(first.as_raw_fd(), dev_path, LockMode::NoWait).unwrap();
+        assert!(matches!(
+            lock_device(second.as_raw_fd(), dev_path, LockMode::NoWait),
+            Err(Error::DeviceBusy { .. })
+        ));
+        assert!(matches!(
+            lock_device(second.as_raw_fd(), dev_path, LockMode::Timeout(Duration::from_millis(30))),
+            Err(Error::DeviceBusy { .. })
+        ));
+
+        //失敗したfdをcloseしても最初のロックは残る
+        drop(second);
+        assert!(matches!(
+            lock_device(third.as_raw_fd(), dev_path, LockMode::NoWait),
+            Err(Error::DeviceBusy { .. })
+        ));
+
+        drop(first);
+        lock_device(third.as_raw_fd(), dev_path, LockMode::NoWait).unwrap();
+        drop(third);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
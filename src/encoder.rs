@@ -0,0 +1,136 @@
+use log::warn;
+use std::fs::File;
+use std::io::Write;
+
+use crate::error::Result;
+use crate::frame::Frame;
+use crate::jpeg_encoder::JpegEncoder;
+use crate::soft_jpeg::SoftJpegEncoder;
+
+/// ハードウェア/ソフトウェアのエンコーダ共通のインターフェース
+pub trait Encoder {
+    /// 画像サイズを設定
+    fn config(&mut self, frame_width: usize, frame_height: usize) -> Result<()>;
+
+    /// 品質(1〜100)を設定
+    fn set_quality(&mut self, quality: u32) -> Result<()>;
+
+    /// RGB8の画像データをJPEGにエンコード
+    fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>>;
+
+    /// RGB8の画像データをJPEGにエンコードしてファイルに出力
+    fn encode_file(&mut self, img_data: &[u8], o_file_name: &str) -> Result<()> {
+        let out = self.encode(img_data)?;
+
+        let mut file = File::create(o_file_name)?;
+        file.write_all(&out)?;
+        Ok(())
+    }
+
+    /// フレームをRGB8に変換してエンコード
+    fn encode_frame(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+        self.encode(&frame.to_rgb8())
+    }
+
+    /// ハードウェアエンコーダかどうか
+    fn is_hardware(&self) -> bool;
+}
+
+impl<E: Encoder + ?Sized> Encoder for Box<E> {
+    fn config(&mut self, frame_width: usize, frame_height: usize) -> Result<()> {
+        (**self).config(frame_width, frame_height)
+    }
+
+    fn set_quality(&mut self, quality: u32) -> Result<()> {
+        (**self).set_quality(quality)
+    }
+
+    fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>> {
+        (**self).encode(img_data)
+    }
+
+    fn encode_file(&mut self, img_data: &[u8], o_file_name: &str) -> Result<()> {
+        (**self).encode_file(img_data, o_file_name)
+    }
+
+    fn encode_frame(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+        (**self).encode_frame(frame)
+    }
+
+    fn is_hardware(&self) -> bool {
+        (**self).is_hardware()
+    }
+}
+
+impl Encoder for JpegEncoder {
+    fn config(&mut self, frame_width: usize, frame_height: usize) -> Result<()> {
+        JpegEncoder::config(self, frame_width, frame_height)
+    }
+
+    fn set_quality(&mut self, quality: u32) -> Result<()> {
+        JpegEncoder::set_quality(self, quality)
+    }
+
+    fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>> {
+        JpegEncoder::encode(self, img_data)
+    }
+
+    fn encode_file(&mut self, img_data: &[u8], o_file_name: &str) -> Result<()> {
+        JpegEncoder::encode_file(self, img_data, o_file_name)
+    }
+
+    fn encode_frame(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+        JpegEncoder::encode_frame(self, frame)
+    }
+
+    fn is_hardware(&self) -> bool {
+        true
+    }
+}
+
+impl Encoder for SoftJpegEncoder {
+    fn config(&mut self, frame_width: usize, frame_height: usize) -> Result<()> {
+        SoftJpegEncoder::config(self, frame_width, frame_height);
+        Ok(())
+    }
+
+    fn set_quality(&mut self, quality: u32) -> Result<()> {
+        SoftJpegEncoder::set_quality(self, quality)
+    }
+
+    fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>> {
+        SoftJpegEncoder::encode(self, img_data)
+    }
+
+    fn is_hardware(&self) -> bool {
+        false
+    }
+}
+
+/// 使用可能なエンコーダを開く
+///
+/// ハードウェアエンコーダが開けない場合(ビットストリーム未ロード、hwinfoに
+/// `jpeg_encoder`が無いなど)はソフトウェアエンコーダを返す
+pub fn open(hw_json_path: &str) -> Box<dyn Encoder + Send> {
+    match JpegEncoder::new(hw_json_path) {
+        Ok(encoder) => Box::new(encoder),
+        Err(e) => {
+            warn!("hardware jpeg encoder unavailable, falling back to software: {:#}", e);
+            Box::new(SoftJpegEncoder::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_falls_back_to_software() {
+        let mut encoder = open("/nonexistent.json");
+        assert!(!encoder.is_hardware());
+        encoder.config(16, 8).unwrap();
+        let out = encoder.encode(&[0x80; 16 * 8 * 3]).unwrap();
+        assert!(crate::bitstream::validate(&out, 16, 8).is_ok());
+    }
+}
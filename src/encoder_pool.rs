@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::devlock::LockMode;
+use crate::error::{Error, Result};
+use crate::jpeg_encoder::JpegEncoder;
+
+//完了待ちのポーリング間隔
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// プールで使うエンコーダ(エンコードを開始し、完了をポーリングできるもの)
+pub trait PooledEncoder {
+    /// RGB8の画像データのエンコードを開始
+    fn start_encode(&mut self, img_data: &[u8]) -> Result<()>;
+
+    /// エンコードが完了していれば結果を返す(実行中ならNone)
+    fn poll_encode(&mut self) -> Result<Option<Vec<u8>>>;
+
+    /// 統計に表示する名前
+    fn name(&self) -> &str;
+}
+
+impl PooledEncoder for JpegEncoder {
+    fn start_encode(&mut self, img_data: &[u8]) -> Result<()> {
+        JpegEncoder::start_encode(self, img_data)
+    }
+
+    fn poll_encode(&mut self) -> Result<Option<Vec<u8>>> {
+        JpegEncoder::poll_encode(self)
+    }
+
+    fn name(&self) -> &str {
+        &self.hw_info().hier
+    }
+}
+
+/// エンコード済みのフレーム
+#[derive(Debug)]
+pub struct EncodedFrame {
+    /// `submit`で割り当てられた通し番号
+    pub seq: u64,
+    /// エンコードしたインスタンスの番号
+    pub instance: usize,
+    pub data: Vec<u8>,
+}
+
+/// インスタンスごとの統計
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceStats {
+    /// 階層名
+    pub hier: String,
+    /// エンコードに成功したフレーム数
+    pub frames: u64,
+    /// エンコードに失敗したフレーム数(開始の失敗を含む)
+    pub errors: u64,
+    /// エンコードに使った時間の合計
+    pub busy: Duration,
+    /// プール作成からの経過時間に対する使用率(0.0〜1.0)
+    pub utilization: f64,
+}
+
+struct Slot<E> {
+    encoder: E,
+    //エンコード中のフレームの通し番号と開始時刻
+    job: Option<(u64, Instant)>,
+    frames: u64,
+    errors: u64,
+    busy: Duration,
+}
+
+/// 複数のハードウェアエンコーダに負荷を分散するプール
+///
+/// 空いているインスタンス(`Adma::is_idle`)にフレームを割り当て、
+/// 結果は`submit`した順に`next_frame`で受け取る
+pub struct EncoderPool<E: PooledEncoder = JpegEncoder> {
+    slots: Vec<Slot<E>>,
+    //エンコード済みで、順番待ちの結果
+    done: BTreeMap<u64, (usize, Result<Vec<u8>>)>,
+    //空きインスタンス待ちのフレーム
+    queue: VecDeque<(u64, Vec<u8>)>,
+    next_seq: u64,
+    next_output: u64,
+    created: Instant,
+}
+
+impl EncoderPool {
+    /// hwinfoにあるすべてのパイプラインをオープンし、同じサイズで設定する
+    pub fn open_all(hw_json_path: &str, frame_width: usize, frame_height: usize) -> Result<Self> {
+        let mut encoders = Vec::new();
+        for hw_info in JpegEncoder::enumerate(hw_json_path)? {
+            let mut encoder = JpegEncoder::from_hw_info(hw_info, LockMode::NoWait)?;
+            encoder.config(frame_width, frame_height)?;
+            encoders.push(encoder);
+        }
+        EncoderPool::new(encoders)
+    }
+}
+
+impl<E: PooledEncoder> EncoderPool<E> {
+    /// オープン済みのエンコーダからプールを作成
+    pub fn new(encoders: Vec<E>) -> Result<Self> {
+        if encoders.is_empty() {
+            return Err(Error::InvalidConfig("encoder pool needs at least one encoder".to_string()));
+        }
+
+        let slots = encoders
+            .into_iter()
+            .map(|encoder| Slot { encoder, job: None, frames: 0, errors: 0, busy: Duration::ZERO })
+            .collect();
+
+        Ok(EncoderPool {
+            slots,
+            done: BTreeMap::new(),
+            queue: VecDeque::new(),
+            next_seq: 0,
+            next_output: 0,
+            created: Instant::now(),
+        })
+    }
+
+    /// インスタンス数
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// インスタンスが無いかどうか(`new`で作ったプールは常にfalse)
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// フレームを投入し、通し番号を返す
+    ///
+    /// 空いているインスタンスがあればすぐにエンコードを開始し、
+    /// 無ければ空くまでキューに積んでおく
+    pub fn submit(&mut self, img_data: Vec<u8>) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.queue.push_back((seq, img_data));
+        self.poll();
+        seq
+    }
+
+    /// 完了したエンコードを回収し、空いたインスタンスに次のフレームを割り当てる
+    pub fn poll(&mut self) {
+        for (instance, slot) in self.slots.iter_mut().enumerate() {
+            //完了の確認
+            if let Some((seq, started)) = slot.job {
+                let result = match slot.encoder.poll_encode() {
+                    Ok(None) => continue,
+                    Ok(Some(data)) => {
+                        slot.frames += 1;
+                        Ok(data)
+                    }
+                    Err(e) => {
+                        slot.errors += 1;
+                        Err(e)
+                    }
+                };
+                slot.job = None;
+                slot.busy += started.elapsed();
+                self.done.insert(seq, (instance, result));
+            }
+
+            //空いていれば次のフレームを開始
+            if let Some((seq, img_data)) = self.queue.pop_front() {
+                match slot.encoder.start_encode(&img_data) {
+                    Ok(()) => slot.job = Some((seq, Instant::now())),
+                    Err(e) => {
+                        slot.errors += 1;
+                        self.done.insert(seq, (instance, Err(e)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// 次の順番のフレームがそろっていれば返す
+    pub fn try_next_frame(&mut self) -> Option<Result<EncodedFrame>> {
+        let (instance, result) = self.done.remove(&self.next_output)?;
+        let seq = self.next_output;
+        self.next_output += 1;
+        Some(result.map(|data| EncodedFrame { seq, instance, data }))
+    }
+
+    /// 次の順番のフレームを待って返す
+    ///
+    /// 投入済みのフレームをすべて返し終わっていれば`None`
+    pub fn next_frame(&mut self) -> Option<Result<EncodedFrame>> {
+        if self.next_output >= self.next_seq {
+            return None;
+        }
+        loop {
+            self.poll();
+            if let Some(frame) = self.try_next_frame() {
+                return Some(frame);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    /// 投入済みのフレームをすべて待ち、順番に返す
+    pub fn flush(&mut self) -> Vec<Result<EncodedFrame>> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.next_frame() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// インスタンスごとの統計
+    pub fn stats(&self) -> Vec<InstanceStats> {
+        let elapsed = self.created.elapsed().as_secs_f64();
+        self.slots
+            .iter()
+            .map(|slot| {
+                //エンコード中の時間も含める
+                let busy = slot.busy + slot.job.map_or(Duration::ZERO, |(_, started)| started.elapsed());
+                InstanceStats {
+                    hier: slot.encoder.name().to_string(),
+                    frames: slot.frames,
+                    errors: slot.errors,
+                    busy,
+                    utilization: if elapsed > 0.0 { (busy.as_secs_f64() / elapsed).min(1.0) } else { 0.0 },
+                }
+            })
+            .collect()
+    }
+
+    /// プールを解体してエンコーダを取り出す
+    pub fn into_encoders(self) -> Vec<E> {
+        self.slots.into_iter().map(|slot| slot.encoder).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //指定した回数のポーリングの後に入力をそのまま返すエンコーダ
+    struct MockEncoder {
+        name: String,
+        delay: u32,
+        job: Option<(u32, Vec<u8>)>,
+    }
+
+    impl MockEncoder {
+        fn new(name: &str, delay: u32) -> Self {
+            MockEncoder { name: name.to_string(), delay, job: None }
+        }
+    }
+
+    impl PooledEncoder for MockEncoder {
+        //空のデータは開始に失敗する
+        fn start_encode(&mut self, img_data: &[u8]) -> Result<()> {
+            if img_data.is_empty() {
+                return Err(Error::InvalidConfig("empty frame".to_string()));
+            }
+            self.job = Some((self.delay, img_data.to_vec()));
+            Ok(())
+        }
+
+        //0xEEで始まるデータはエンコードに失敗する
+        fn poll_encode(&mut self) -> Result<Option<Vec<u8>>> {
+            match self.job.take() {
+                Some((0, data)) if data[0] == 0xEE => Err(Error::InvalidBitstream("corrupt".to_string())),
+                Some((0, data)) => Ok(Some(data)),
+                Some((n, data)) => {
+                    self.job = Some((n - 1, data));
+                    Ok(None)
+                }
+                None => Err(Error::InvalidConfig("no encode in progress".to_string())),
+            }
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    #[test]
+    fn output_keeps_submit_order() {
+        //1つ目のインスタンスが遅く、後から投入したフレームが先に終わる
+        let mut pool = EncoderPool::new(vec![MockEncoder::new("slow", 20), MockEncoder::new("fast", 0)]).unwrap();
+        for i in 0..6u8 {
+            assert_eq!(pool.submit(vec![i]), i as u64);
+        }
+
+        let frames: Vec<EncodedFrame> = pool.flush().into_iter().map(|f| f.unwrap()).collect();
+        assert_eq!(frames.iter().map(|f| f.seq).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(frames.iter().map(|f| f.data[0]).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5]);
+        assert_eq!(frames[0].instance, 0);
+        assert!(frames[1..].iter().any(|f| f.instance == 1));
+        assert!(pool.next_frame().is_none());
+
+        let stats = pool.stats();
+        assert_eq!(stats.iter().map(|s| s.frames).sum::<u64>(), 6);
+        assert_eq!(stats.iter().map(|s| s.errors).sum::<u64>(), 0);
+        assert_eq!(stats[0].hier, "slow");
+    }
+
+    #[test]
+    fn failures_come_back_in_sequence() {
+        let mut pool = EncoderPool::new(vec![MockEncoder::new("a", 3), MockEncoder::new("b", 1)]).unwrap();
+        pool.submit(vec![1]);
+        pool.submit(Vec::new());
+        pool.submit(vec![0xEE]);
+        pool.submit(vec![4]);
+
+        let frames = pool.flush();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0].as_ref().unwrap().data, [1]);
+        assert!(matches!(frames[1], Err(Error::InvalidConfig(_))));
+        assert!(matches!(frames[2], Err(Error::InvalidBitstream(_))));
+        assert_eq!(frames[3].as_ref().unwrap().seq, 3);
+
+        //失敗は成功と別に数える
+        let stats = pool.stats();
+        assert_eq!(stats.iter().map(|s| s.frames).sum::<u64>(), 2);
+        assert_eq!(stats.iter().map(|s| s.errors).sum::<u64>(), 2);
+    }
+}
@@ -0,0 +1,80 @@
+use std::io;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::hwinfo::HwInfoIssue;
+use crate::udma::Owner;
+
+/// ドライバ共通のエラー
+#[derive(Debug, Error)]
+pub enum Error {
+    /// UIO / u-dma-buf デバイスが見つからない
+    #[error("device not found: {0}")]
+    DeviceNotFound(String),
+
+    /// 他のプロセスがデバイスを使用中
+    #[error("device busy: {path} is held by {}",
+            .pid.map_or("another process".to_string(), |pid| format!("pid {}", pid)))]
+    DeviceBusy { path: String, pid: Option<i32> },
+
+    /// デバイスファイルのオープン・ロックに失敗
+    #[error("failed to open {path}: {source}")]
+    Open { path: String, #[source] source: io::Error },
+
+    /// mmapに失敗
+    #[error("failed to mmap {path}: {source}")]
+    Mmap { path: String, #[source] source: io::Error },
+
+    /// u-dma-bufのキャッシュ制御ファイルの操作に失敗
+    #[error("cache sync failed on {name}: {source}")]
+    CacheSync { name: String, #[source] source: io::Error },
+
+    /// u-dma-bufのオーナー変更に失敗
+    #[error("failed to change owner of {name} to {expected:?} (owner is {actual:?})")]
+    OwnerChange { name: String, expected: Owner, actual: Owner },
+
+    /// DMAのステータスレジスタがエラーを示している
+    #[error("DMA error: status {status:#010x}")]
+    DmaStatus { status: u32 },
+
+    /// ハードウェアの完了待ちがタイムアウト
+    #[error("timed out after {timeout:?} waiting for {what}")]
+    Timeout { what: &'static str, timeout: Duration },
+
+    /// サイズが上限を超えている
+    #[error("{what} size {size} exceeds {limit}")]
+    SizeExceeded { what: &'static str, size: usize, limit: usize },
+
+    /// 設定値が不正
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    /// エンコード結果のJPEGが不正
+    #[error("invalid JPEG bitstream: {0}")]
+    InvalidBitstream(String),
+
+    /// 入力画像が不正
+    #[error("invalid input image: {0}")]
+    InvalidImage(String),
+
+    /// 対応していないIPのリビジョン
+    #[error("incompatible hardware: {0}")]
+    IncompatibleHardware(String),
+
+    /// ハードウェア情報(hwinfo)のファイルの読み込み・解析に失敗
+    #[error("failed to read hwinfo {path}: {message}")]
+    HwInfoRead { path: String, message: String },
+
+    /// hwinfoの検証で見つかった問題(見つかったものすべて)
+    #[error("invalid hwinfo: {}", join_issues(.0))]
+    InvalidHwInfo(Vec<HwInfoIssue>),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+fn join_issues(issues: &[HwInfoIssue]) -> String {
+    issues.iter().map(|issue| issue.to_string()).collect::<Vec<_>>().join("; ")
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,210 @@
+use std::borrow::Cow;
+
+use crate::convert;
+use crate::error::{Error, Result};
+
+/// 画素の並び
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PixelFormat {
+    /// R, G, Bの順に8bitずつ(エンコーダの入力形式)
+    Rgb8,
+    /// B, G, Rの順に8bitずつ
+    Bgr8,
+    /// R, G, B, Aの順に8bitずつ(エンコード時にAは捨てる)
+    Rgba8,
+    /// 8bitグレースケール
+    Gray8,
+    /// Y0, U, Y1, Vの順の4:2:2(幅は偶数)
+    Yuyv8,
+}
+
+impl PixelFormat {
+    /// 1画素のバイト数
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 => 4,
+            PixelFormat::Yuyv8 => 2,
+            PixelFormat::Gray8 => 1,
+        }
+    }
+
+    /// 幅`width`、高さ`height`の画像のバイト数(詰め物なし)
+    ///
+    /// usizeに収まらなければエラー
+    pub fn frame_len(&self, width: usize, height: usize) -> Result<usize> {
+        width
+            .checked_mul(height)
+            .and_then(|n| n.checked_mul(self.bytes_per_pixel()))
+            .ok_or_else(|| Error::InvalidImage(format!("{}x{} {:?} frame is too large", width, height, self)))
+    }
+}
+
+/// 画像中の矩形領域
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Roi {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Roi {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Roi { x, y, width, height }
+    }
+
+    /// 幅`width`、高さ`height`の画像に収まっているか
+    pub fn fits(&self, width: usize, height: usize) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self.x.checked_add(self.width).is_some_and(|r| r <= width)
+            && self.y.checked_add(self.height).is_some_and(|b| b <= height)
+    }
+}
+
+/// サイズと画素形式を持つ画像
+///
+/// データは所有する(`Vec<u8>`)か、呼び出し側のバッファを借用する(`&[u8]`)。
+/// データの長さは作成時に確認するので、フィールドは読み出し専用にしている
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame<'a> {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    stride: usize,
+    data: Cow<'a, [u8]>,
+}
+
+impl<'a> Frame<'a> {
+    /// データの長さがサイズと形式に合っているか確認して作成(行の詰め物なし)
+    pub fn new(width: usize, height: usize, format: PixelFormat, data: impl Into<Cow<'a, [u8]>>) -> Result<Self> {
+        let data = data.into();
+        let expected = format.frame_len(width, height)?;
+        if data.len() != expected {
+            return Err(Error::InvalidImage(format!(
+                "{}x{} {:?} frame needs {} bytes, got {}", width, height, format, expected, data.len()
+            )));
+        }
+        Frame::with_stride(width, height, format, width * format.bytes_per_pixel(), data)
+    }
+
+    /// 行末に詰め物がある画像を作成
+    ///
+    /// 最後の行は詰め物が無くてもよい
+    pub fn with_stride(
+        width: usize, height: usize, format: PixelFormat, stride: usize, data: impl Into<Cow<'a, [u8]>>,
+    ) -> Result<Self> {
+        let data = data.into();
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidImage(format!("empty frame size {}x{}", width, height)));
+        }
+        if format == PixelFormat::Yuyv8 && !width.is_multiple_of(2) {
+            return Err(Error::InvalidImage(format!("YUYV frame width {} must be even", width)));
+        }
+        let row = format.frame_len(width, 1)?;
+        if stride < row {
+            return Err(Error::InvalidImage(format!("stride {} is smaller than a row ({} bytes)", stride, row)));
+        }
+        let expected = stride.checked_mul(height - 1).and_then(|n| n.checked_add(row)).ok_or_else(|| {
+            Error::InvalidImage(format!("{}x{} frame with stride {} is too large", width, height, stride))
+        })?;
+        if data.len() < expected {
+            return Err(Error::InvalidImage(format!(
+                "{}x{} {:?} frame with stride {} needs {} bytes, got {}",
+                width, height, format, stride, expected, data.len()
+            )));
+        }
+        Ok(Frame { width, height, format, stride, data })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    /// 1行のバイト数(行末の詰め物を含む)
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// 画像データ(最後の行の後ろに余分なデータがあることもある)
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// 画像データを取り出す(借用していればコピーする)
+    pub fn into_data(self) -> Vec<u8> {
+        self.data.into_owned()
+    }
+
+    /// 1行のバイト数(詰め物を除く)
+    pub fn row_bytes(&self) -> usize {
+        self.width * self.format.bytes_per_pixel()
+    }
+
+    /// 行を詰めたRGB8のデータ(詰め物の無いRGB8ならコピーしない)
+    pub fn to_rgb8(&self) -> Cow<'_, [u8]> {
+        let len = self.width * self.height * 3;
+        if self.format == PixelFormat::Rgb8 && self.stride == self.row_bytes() {
+            return Cow::Borrowed(&self.data[..len]);
+        }
+        let mut rgb = vec![0; len];
+        //データの長さは作成時に確認済みで、フィールドは外から変更できない
+        convert::frame_to_rgb8(self, &mut rgb, 1).expect("frame data is smaller than its size");
+        Cow::Owned(rgb)
+    }
+
+    /// RGB8に変換
+    pub fn into_rgb8(self) -> Frame<'a> {
+        let data = match self.to_rgb8() {
+            Cow::Borrowed(_) => return self,
+            Cow::Owned(data) => data,
+        };
+        Frame { width: self.width, height: self.height, format: PixelFormat::Rgb8, stride: self.width * 3, data: data.into() }
+    }
+
+    /// データを所有する画像にする(借用していればコピーする)
+    pub fn into_owned(self) -> Frame<'static> {
+        Frame {
+            width: self.width,
+            height: self.height,
+            format: self.format,
+            stride: self.stride,
+            data: Cow::Owned(self.data.into_owned()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn with_stride_checks_data_length() {
+        //最後の行は詰め物が無くてもよい
+        assert!(Frame::with_stride(2, 2, PixelFormat::Rgb8, 8, vec![0; 14]).is_ok());
+        assert!(Frame::with_stride(2, 2, PixelFormat::Rgb8, 8, vec![0; 13]).is_err());
+        assert!(Frame::with_stride(2, 2, PixelFormat::Rgb8, 5, vec![0; 16]).is_err());
+        assert!(Frame::with_stride(usize::MAX, 2, PixelFormat::Rgb8, 8, vec![0; 16]).is_err());
+        assert!(Frame::new(3, 1, PixelFormat::Yuyv8, vec![0; 6]).is_err());
+    }
+
+    #[test]
+    fn to_rgb8_removes_padding_and_converts() {
+        let frame = Frame::with_stride(1, 2, PixelFormat::Bgr8, 4, vec![1, 2, 3, 0, 4, 5, 6]).unwrap();
+        assert_eq!(frame.to_rgb8()[..], [3, 2, 1, 6, 5, 4]);
+
+        let data = [1, 2, 3, 4, 5, 6];
+        let frame = Frame::new(2, 1, PixelFormat::Rgb8, &data[..]).unwrap();
+        assert!(matches!(frame.to_rgb8(), Cow::Borrowed(_)));
+        assert_eq!(frame.into_owned().into_data(), data);
+    }
+}
@@ -0,0 +1,623 @@
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+/// hwinfoの検証で見つかった問題
+#[derive(Debug, Clone, PartialEq)]
+pub struct HwInfoIssue {
+    /// 問題のあるフィールドのパス(例: `$["jpeg_encoder/axi_dma"].udmabuf[0]`)
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for HwInfoIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// hwinfoのファイルを読み込む
+pub fn read(path: &str) -> Result<Value> {
+    xipdriver_rs::hwinfo::read(path).map_err(|e| Error::HwInfoRead {
+        path: path.to_string(),
+        message: format!("{:#}", e),
+    })
+}
+
+/// ドライバが読むIPパラメータ(`params`)の名前
+///
+/// 値は数値または数値の文字列。真偽値は`1`/`true`を真とする
+pub mod param {
+    /// jpeg_encoder: 量子化テーブルのレジスタ(0x100/0x200)を持つか
+    pub const HAS_QTABLE: &str = "HAS_QTABLE";
+    /// jpeg_encoder: バージョンレジスタのオフセット(上位16bitがメジャー、下位16bitがマイナー)
+    pub const VERSION_OFFSET: &str = "VERSION_OFFSET";
+    /// jpeg_encoder: 出力サイズのレジスタのオフセット(省略時は0x04)
+    pub const OUT_LENGTH_OFFSET: &str = "OUT_LENGTH_OFFSET";
+    /// jpeg_encoder: ソフトリセットのレジスタのオフセット(1を書いてリセット、0で解除)
+    pub const SOFT_RESET_OFFSET: &str = "SOFT_RESET_OFFSET";
+    /// jpeg_encoder: リセット線につながったAXI GPIO(hwinfoのキー、または同じ階層のIP名)
+    pub const RESET_GPIO: &str = "RESET_GPIO";
+    /// jpeg_encoder: リセット線のAXI GPIOのチャンネル(1または2。省略時は1)
+    pub const RESET_GPIO_CHANNEL: &str = "RESET_GPIO_CHANNEL";
+    /// jpeg_encoder: リセット線のビット(0〜31。省略時は0)
+    pub const RESET_GPIO_BIT: &str = "RESET_GPIO_BIT";
+    /// jpeg_encoder: リセット線がアクティブハイか(省略時はアクティブロー)
+    pub const RESET_ACTIVE_HIGH: &str = "RESET_ACTIVE_HIGH";
+
+    /// v_frmbuf_rd: 割り込みが接続されているか
+    pub const HAS_INTERRUPT: &str = "HAS_INTERRUPT";
+    /// v_frmbuf_rd, v_proc_ss: 1クロックあたりの画素数(省略時は1)
+    pub const SAMPLES_PER_CLOCK: &str = "SAMPLES_PER_CLOCK";
+    /// 最大の幅と高さ(`MAX_COLS`/`MAX_ROWS`を優先する)
+    pub const MAX_COLS: &str = "MAX_COLS";
+    pub const MAX_ROWS: &str = "MAX_ROWS";
+    pub const MAX_WIDTH: &str = "MAX_WIDTH";
+    pub const MAX_HEIGHT: &str = "MAX_HEIGHT";
+
+    /// v_proc_ss: サブシステム内の各コアのオフセット(ないコアは省略する)
+    ///
+    /// 4バイト境界で、コアのレジスタがすべて`ADDR_RANGE`に収まること
+    pub const RESET_OFFSET: &str = "RESET_OFFSET";
+    pub const HSC_OFFSET: &str = "HSC_OFFSET";
+    pub const VSC_OFFSET: &str = "VSC_OFFSET";
+    pub const CSC_OFFSET: &str = "CSC_OFFSET";
+    /// v_proc_ss: スケーリングの方式(0: bilinear, 1: bicubic, 2: polyphase。省略時は2)
+    pub const SCALE_MODE: &str = "SCALE_MODE";
+    /// v_proc_ss: 位相の数(省略時は64)
+    pub const PHASES: &str = "PHASES";
+    /// v_proc_ss: 水平と垂直のタップ数(12以下の偶数。省略時は6)
+    pub const H_SCALER_TAPS: &str = "H_SCALER_TAPS";
+    pub const V_SCALER_TAPS: &str = "V_SCALER_TAPS";
+    /// v_proc_ss: サブシステム全体のアドレス範囲(省略時は0x40000)
+    pub const ADDR_RANGE: &str = "ADDR_RANGE";
+}
+
+/// hwinfo中の1つのIPの情報
+#[derive(Debug, Clone, Deserialize)]
+pub struct IpInfo {
+    /// UIOデバイス名
+    pub uio: String,
+    /// u-dma-bufのデバイス名
+    #[serde(default)]
+    pub udmabuf: Vec<String>,
+    /// IPのVLNV(vendor:library:name:version)
+    #[serde(default)]
+    pub vlnv: Option<String>,
+    /// IPのパラメータ
+    #[serde(default)]
+    pub params: BTreeMap<String, Value>,
+}
+
+impl IpInfo {
+    /// hwinfo全体から`key`のエントリを検証して読み込む
+    ///
+    /// エラーのパスは`$["key"]`から始まる
+    pub fn parse(hw_json: &Value, key: &str, needs_udmabuf: bool) -> Result<Self> {
+        IpInfo::parse_at(&hw_json[key], &entry_path(key), needs_udmabuf)
+    }
+
+    /// IPのエントリだけを検証して読み込む
+    ///
+    /// hwinfo上のキーがわからないので、エラーのパスはエントリを`$`とする
+    pub fn parse_entry(hw_info: &Value, needs_udmabuf: bool) -> Result<Self> {
+        IpInfo::parse_at(hw_info, "$", needs_udmabuf)
+    }
+
+    fn parse_at(value: &Value, path: &str, needs_udmabuf: bool) -> Result<Self> {
+        let mut issues = Vec::new();
+        validate_ip(value, path, needs_udmabuf, &mut issues);
+        if !issues.is_empty() {
+            return Err(Error::InvalidHwInfo(issues));
+        }
+        deserialize_ip(value, path)
+    }
+
+    /// 最初のu-dma-bufの名前
+    pub fn first_udmabuf(&self) -> Option<&str> {
+        self.udmabuf.first().map(|s| s.as_str())
+    }
+
+    /// 数値のパラメータを取得(文字列で書かれた数値も受け付ける)
+    pub fn param_u32(&self, key: &str) -> Option<u32> {
+        self.params.get(key).and_then(value_as_u32)
+    }
+
+    /// 文字列のパラメータを取得
+    pub fn param_str(&self, key: &str) -> Option<&str> {
+        self.params.get(key).and_then(|v| v.as_str())
+    }
+
+    /// 真偽値のパラメータを取得(`1`/`true`を真とする)
+    pub fn param_bool(&self, key: &str) -> bool {
+        match self.params.get(key) {
+            Some(Value::Bool(b)) => *b,
+            Some(v) => match value_as_u32(v) {
+                Some(n) => n != 0,
+                None => v.as_str().is_some_and(|s| s.trim().eq_ignore_ascii_case("true")),
+            },
+            None => false,
+        }
+    }
+
+    /// 最大の幅(`MAX_COLS`または`MAX_WIDTH`)
+    pub fn max_width(&self) -> Option<u32> {
+        self.param_u32(param::MAX_COLS).or_else(|| self.param_u32(param::MAX_WIDTH))
+    }
+
+    /// 最大の高さ(`MAX_ROWS`または`MAX_HEIGHT`)
+    pub fn max_height(&self) -> Option<u32> {
+        self.param_u32(param::MAX_ROWS).or_else(|| self.param_u32(param::MAX_HEIGHT))
+    }
+}
+
+/// JPEGエンコーダのパイプラインを構成するIPの情報
+#[derive(Debug, Clone)]
+pub struct EncoderHwInfo {
+    /// 階層名
+    pub hier: String,
+    pub encoder_name: String,
+    pub encoder: IpInfo,
+    pub vfrmbuf_name: String,
+    pub vfrmbuf: IpInfo,
+    pub dma_name: String,
+    pub dma: IpInfo,
+    /// フレームバッファとエンコーダの間のVideo Processing Subsystem(なければNone)
+    pub scaler_name: Option<String>,
+    pub scaler: Option<IpInfo>,
+    /// エンコーダのパラメータ`RESET_GPIO`で指定されたAXI GPIO(なければNone)
+    pub reset_gpio_name: Option<String>,
+    pub reset_gpio: Option<IpInfo>,
+}
+
+impl EncoderHwInfo {
+    /// 階層`hier`以下のJPEGエンコーダのパイプラインを探して検証する
+    ///
+    /// 見つかった問題はまとめて`Error::InvalidHwInfo`で返す
+    pub fn from_json(hw_json: &Value, hier: &str) -> Result<Self> {
+        //必要なIPをすべて探し、見つからないものは問題としてまとめる
+        let mut issues = Vec::new();
+        let encoder_name = require_ip(hw_json, hier, "jpeg_encoder", &mut issues);
+        let vfrmbuf_name = require_ip(hw_json, hier, "v_frmbuf_rd", &mut issues);
+        let dma_name = require_ip(hw_json, hier, "axi_dma", &mut issues);
+        let scaler_name = optional_ip(hw_json, hier, "v_proc_ss", &mut issues);
+        let (encoder_name, vfrmbuf_name, dma_name) = match (encoder_name, vfrmbuf_name, dma_name) {
+            (Some(encoder), Some(vfrmbuf), Some(dma)) if issues.is_empty() => (encoder, vfrmbuf, dma),
+            (encoder, vfrmbuf, dma) => {
+                //見つかったIPのエントリの問題も一緒に返す
+                let found = [(encoder, false), (vfrmbuf, true), (dma, true)];
+                for (name, needs_udmabuf) in found.iter().filter_map(|(n, u)| n.as_ref().map(|n| (n, *u))) {
+                    validate_ip(&hw_json[name], &entry_path(name), needs_udmabuf, &mut issues);
+                }
+                return Err(Error::InvalidHwInfo(issues));
+            }
+        };
+        EncoderHwInfo::from_names(hw_json, hier, encoder_name, vfrmbuf_name, dma_name, scaler_name)
+    }
+
+    /// hwinfo中のJPEGエンコーダのパイプラインをすべて探す
+    ///
+    /// `jpeg_encoder`, `v_frmbuf_rd`, `axi_dma`がそろっている階層を返す(階層名順)
+    pub fn find_all(hw_json: &Value) -> Result<Vec<Self>> {
+        let keys = match hw_json.as_object() {
+            Some(obj) => obj.keys(),
+            None => {
+                return Err(Error::InvalidHwInfo(vec![HwInfoIssue {
+                    path: "$".to_string(),
+                    message: "expected an object".to_string(),
+                }]));
+            }
+        };
+
+        //IPを含む階層の候補
+        let hiers: BTreeSet<&str> = keys
+            .filter_map(|key| key.rsplit_once('/').map(|(hier, _)| hier))
+            .collect();
+
+        let mut pipelines = Vec::new();
+        let mut issues = Vec::new();
+        for hier in hiers {
+            //その階層の直下にあるIPだけを対象にする
+            let mut find = |hw_name: &str| {
+                optional_ip(hw_json, hier, hw_name, &mut issues)
+                    .filter(|name| name.rsplit_once('/').map(|(h, _)| h) == Some(hier))
+            };
+            if let (Some(encoder_name), Some(vfrmbuf_name), Some(dma_name)) =
+                (find("jpeg_encoder"), find("v_frmbuf_rd"), find("axi_dma"))
+            {
+                let scaler_name = find("v_proc_ss");
+                pipelines.push(EncoderHwInfo::from_names(
+                    hw_json, hier, encoder_name, vfrmbuf_name, dma_name, scaler_name,
+                )?);
+            }
+        }
+
+        if !issues.is_empty() {
+            return Err(Error::InvalidHwInfo(issues));
+        }
+        Ok(pipelines)
+    }
+
+    fn from_names(hw_json: &Value, hier: &str, encoder_name: String,
+                  vfrmbuf_name: String, dma_name: String, scaler_name: Option<String>) -> Result<Self> {
+        let mut issues = Vec::new();
+        validate_ip(&hw_json[&encoder_name], &entry_path(&encoder_name), false, &mut issues);
+        validate_ip(&hw_json[&vfrmbuf_name], &entry_path(&vfrmbuf_name), true, &mut issues);
+        validate_ip(&hw_json[&dma_name], &entry_path(&dma_name), true, &mut issues);
+        if let Some(scaler_name) = &scaler_name {
+            validate_ip(&hw_json[scaler_name], &entry_path(scaler_name), false, &mut issues);
+        }
+        if !issues.is_empty() {
+            return Err(Error::InvalidHwInfo(issues));
+        }
+
+        //リセット用のAXI GPIOはエンコーダのパラメータで指定されたものだけを使う
+        let encoder = deserialize_ip(&hw_json[&encoder_name], &entry_path(&encoder_name))?;
+        let reset_gpio_name = reset_gpio_key(hw_json, hier, &encoder_name, &encoder, &mut issues);
+        if let Some(reset_gpio_name) = &reset_gpio_name {
+            validate_ip(&hw_json[reset_gpio_name], &entry_path(reset_gpio_name), false, &mut issues);
+        }
+        if !issues.is_empty() {
+            return Err(Error::InvalidHwInfo(issues));
+        }
+
+        Ok(EncoderHwInfo {
+            hier: hier.to_string(),
+            encoder,
+            vfrmbuf: deserialize_ip(&hw_json[&vfrmbuf_name], &entry_path(&vfrmbuf_name))?,
+            dma: deserialize_ip(&hw_json[&dma_name], &entry_path(&dma_name))?,
+            scaler: match &scaler_name {
+                Some(name) => Some(deserialize_ip(&hw_json[name], &entry_path(name))?),
+                None => None,
+            },
+            encoder_name,
+            vfrmbuf_name,
+            dma_name,
+            scaler_name,
+            reset_gpio: match &reset_gpio_name {
+                Some(name) => Some(deserialize_ip(&hw_json[name], &entry_path(name))?),
+                None => None,
+            },
+            reset_gpio_name,
+        })
+    }
+
+    /// 入力できる最大の幅
+    pub fn max_width(&self) -> Option<u32> {
+        min_option(self.encoder.max_width(), self.max_input_width())
+    }
+
+    /// 入力できる最大の高さ
+    pub fn max_height(&self) -> Option<u32> {
+        min_option(self.encoder.max_height(), self.max_input_height())
+    }
+
+    /// スケーラを使う場合の入力の最大の幅
+    pub fn max_input_width(&self) -> Option<u32> {
+        min_option(self.vfrmbuf.max_width(), self.scaler.as_ref().and_then(|s| s.max_width()))
+    }
+
+    /// スケーラを使う場合の入力の最大の高さ
+    pub fn max_input_height(&self) -> Option<u32> {
+        min_option(self.vfrmbuf.max_height(), self.scaler.as_ref().and_then(|s| s.max_height()))
+    }
+}
+
+fn min_option(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+//検証済みのエントリを読み込む
+fn deserialize_ip(value: &Value, path: &str) -> Result<IpInfo> {
+    IpInfo::deserialize(value).map_err(|e| {
+        Error::InvalidHwInfo(vec![HwInfoIssue { path: path.to_string(), message: e.to_string() }])
+    })
+}
+
+//階層`hier`にある`hw_name`のIPのキーを探す
+//
+//階層の直下にそれらしいキーもVLNVもなければNone。match_hwの失敗(曖昧な一致など)は問題として返す
+fn lookup_ip(hw_json: &Value, hier: &str, hw_name: &str) -> std::result::Result<Option<String>, HwInfoIssue> {
+    let prefix = format!("{}/", hier);
+    let exists = hw_json.as_object().is_some_and(|obj| {
+        obj.iter().any(|(key, value)| {
+            key.strip_prefix(&prefix).is_some_and(|ip| {
+                ip.contains(hw_name) || value["vlnv"].as_str().is_some_and(|vlnv| vlnv.contains(hw_name))
+            })
+        })
+    });
+    if !exists {
+        return Ok(None);
+    }
+    xipdriver_rs::hwinfo::match_hw(hw_json, hier, hw_name)
+        .map(Some)
+        .map_err(|e| HwInfoIssue { path: ip_path(hier, hw_name), message: format!("{:#}", e) })
+}
+
+//必要なIPを探し、見つからなければ問題に追加する
+fn require_ip(hw_json: &Value, hier: &str, hw_name: &str, issues: &mut Vec<HwInfoIssue>) -> Option<String> {
+    match lookup_ip(hw_json, hier, hw_name) {
+        Ok(Some(name)) => Some(name),
+        Ok(None) => {
+            issues.push(HwInfoIssue {
+                path: ip_path(hier, hw_name),
+                message: format!("missing {} in hierarchy {:?}", hw_name, hier),
+            });
+            None
+        }
+        Err(issue) => {
+            issues.push(issue);
+            None
+        }
+    }
+}
+
+//なくてもよいIPを探す。見つからなければNone、探すのに失敗したら問題に追加する
+fn optional_ip(hw_json: &Value, hier: &str, hw_name: &str, issues: &mut Vec<HwInfoIssue>) -> Option<String> {
+    lookup_ip(hw_json, hier, hw_name).unwrap_or_else(|issue| {
+        issues.push(issue);
+        None
+    })
+}
+
+//エンコーダのパラメータ`RESET_GPIO`からAXI GPIOのキーを求め、チャンネルとビットも検証する
+//
+//IP名だけの場合はエンコーダと同じ階層のものとする
+fn reset_gpio_key(hw_json: &Value, hier: &str, encoder_name: &str, encoder: &IpInfo,
+                  issues: &mut Vec<HwInfoIssue>) -> Option<String> {
+    let param_path = |key: &str| format!("{}.params.{}", entry_path(encoder_name), key);
+    let name = match encoder.params.get(param::RESET_GPIO) {
+        None => return None,
+        Some(Value::String(name)) => name.trim(),
+        Some(_) => {
+            issues.push(HwInfoIssue {
+                path: param_path(param::RESET_GPIO),
+                message: "expected the hwinfo key or name of an AXI GPIO".to_string(),
+            });
+            return None;
+        }
+    };
+
+    let key = if name.contains('/') { name.to_string() } else { format!("{}/{}", hier, name) };
+    if hw_json.get(&key).is_none() {
+        issues.push(HwInfoIssue {
+            path: param_path(param::RESET_GPIO),
+            message: format!("no hwinfo entry {:?}", key),
+        });
+        return None;
+    }
+
+    if let Some(v) = encoder.params.get(param::RESET_GPIO_CHANNEL) {
+        if !matches!(value_as_u32(v), Some(1 | 2)) {
+            issues.push(HwInfoIssue {
+                path: param_path(param::RESET_GPIO_CHANNEL),
+                message: format!("expected 1 or 2, got {}", v),
+            });
+        }
+    }
+    if let Some(v) = encoder.params.get(param::RESET_GPIO_BIT) {
+        if value_as_u32(v).is_none_or(|bit| bit >= 32) {
+            issues.push(HwInfoIssue {
+                path: param_path(param::RESET_GPIO_BIT),
+                message: format!("expected a bit number from 0 to 31, got {}", v),
+            });
+        }
+    }
+    Some(key)
+}
+
+//見つからなかったIPを指すパス(階層名/IP名)
+fn ip_path(hier: &str, hw_name: &str) -> String {
+    entry_path(&format!("{}/{}", hier, hw_name))
+}
+
+fn entry_path(name: &str) -> String {
+    format!("$[{:?}]", name)
+}
+
+fn value_as_u32(value: &Value) -> Option<u32> {
+    match value {
+        Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+        Value::String(s) => {
+            let s = s.trim();
+            match s.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => s.parse().ok(),
+            }
+        }
+        _ => None,
+    }
+}
+
+//IPのエントリを検証し、問題があればissuesに追加
+fn validate_ip(value: &Value, path: &str, needs_udmabuf: bool, issues: &mut Vec<HwInfoIssue>) {
+    let mut issue = |path: String, message: &str| {
+        issues.push(HwInfoIssue { path, message: message.to_string() });
+    };
+
+    let obj = match value {
+        Value::Object(obj) => obj,
+        Value::Null => return issue(path.to_string(), "missing entry"),
+        _ => return issue(path.to_string(), "expected an object"),
+    };
+
+    match obj.get("uio") {
+        None => issue(format!("{}.uio", path), "missing field"),
+        Some(Value::String(s)) if s.is_empty() => issue(format!("{}.uio", path), "empty string"),
+        Some(Value::String(_)) => {}
+        Some(_) => issue(format!("{}.uio", path), "expected a string"),
+    }
+
+    match obj.get("udmabuf") {
+        None if needs_udmabuf => issue(format!("{}.udmabuf", path), "missing field"),
+        None => {}
+        Some(Value::Array(bufs)) => {
+            if needs_udmabuf && bufs.is_empty() {
+                issue(format!("{}.udmabuf[0]", path), "missing element");
+            }
+            for (i, buf) in bufs.iter().enumerate() {
+                if !buf.is_string() {
+                    issue(format!("{}.udmabuf[{}]", path, i), "expected a string");
+                }
+            }
+        }
+        Some(_) => issue(format!("{}.udmabuf", path), "expected an array"),
+    }
+
+    if let Some(vlnv) = obj.get("vlnv") {
+        if !vlnv.is_string() && !vlnv.is_null() {
+            issue(format!("{}.vlnv", path), "expected a string");
+        }
+    }
+
+    match obj.get("params") {
+        None | Some(Value::Null) => {}
+        Some(Value::Object(params)) => {
+            for key in [param::MAX_COLS, param::MAX_ROWS, param::MAX_WIDTH, param::MAX_HEIGHT] {
+                if let Some(v) = params.get(key) {
+                    if value_as_u32(v).is_none() {
+                        issue(format!("{}.params.{}", path, key), "expected an unsigned integer");
+                    }
+                }
+            }
+        }
+        Some(_) => issue(format!("{}.params", path), "expected an object"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn issues(e: Error) -> Vec<HwInfoIssue> {
+        match e {
+            Error::InvalidHwInfo(issues) => issues,
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn from_json_reports_every_missing_ip() {
+        let hw = json!({ "jpeg_encoder/jpeg_encoder_0": { "udmabuf": [] } });
+        let paths: Vec<String> = issues(EncoderHwInfo::from_json(&hw, "jpeg_encoder").unwrap_err())
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect();
+        assert_eq!(paths, [
+            r#"$["jpeg_encoder/v_frmbuf_rd"]"#,
+            r#"$["jpeg_encoder/axi_dma"]"#,
+            r#"$["jpeg_encoder/jpeg_encoder_0"].uio"#,
+        ]);
+    }
+
+    #[test]
+    fn from_json_accepts_complete_pipeline() {
+        let hw = json!({
+            "jpeg_encoder/jpeg_encoder_0": { "uio": "jpeg" },
+            "jpeg_encoder/v_frmbuf_rd_0": { "uio": "vfb", "udmabuf": ["udmabuf0"] },
+            "jpeg_encoder/axi_dma_0": { "uio": "dma", "udmabuf": ["udmabuf1"] },
+        });
+        let info = EncoderHwInfo::from_json(&hw, "jpeg_encoder").unwrap();
+        assert_eq!(info.dma_name, "jpeg_encoder/axi_dma_0");
+        assert!(info.scaler.is_none());
+    }
+
+    #[test]
+    fn from_json_finds_optional_scaler() {
+        let hw = json!({
+            "jpeg_encoder/jpeg_encoder_0": { "uio": "jpeg" },
+            "jpeg_encoder/v_frmbuf_rd_0": { "uio": "vfb", "udmabuf": ["udmabuf0"] },
+            "jpeg_encoder/axi_dma_0": { "uio": "dma", "udmabuf": ["udmabuf1"] },
+            "jpeg_encoder/v_proc_ss_0": { "uio": "vpss" },
+        });
+        let info = EncoderHwInfo::from_json(&hw, "jpeg_encoder").unwrap();
+        assert_eq!(info.scaler_name.as_deref(), Some("jpeg_encoder/v_proc_ss_0"));
+        assert_eq!(info.scaler.unwrap().uio, "vpss");
+    }
+
+    #[test]
+    fn from_json_ignores_unselected_gpio() {
+        let hw = json!({
+            "enc/jpeg_encoder_0": { "uio": "jpeg" },
+            "enc/v_frmbuf_rd_0": { "uio": "vfb", "udmabuf": ["udmabuf0"] },
+            "enc/axi_dma_0": { "uio": "dma", "udmabuf": ["udmabuf1"] },
+            "enc/axi_gpio_0": { "uio": "leds" },
+        });
+        let info = EncoderHwInfo::from_json(&hw, "enc").unwrap();
+        assert!(info.reset_gpio_name.is_none());
+        assert!(info.reset_gpio.is_none());
+    }
+
+    #[test]
+    fn from_json_uses_reset_gpio_param() {
+        let mut hw = json!({
+            "enc/jpeg_encoder_0": {
+                "uio": "jpeg",
+                "params": { "RESET_GPIO": "axi_gpio_1", "RESET_GPIO_CHANNEL": 2, "RESET_GPIO_BIT": "3" },
+            },
+            "enc/v_frmbuf_rd_0": { "uio": "vfb", "udmabuf": ["udmabuf0"] },
+            "enc/axi_dma_0": { "uio": "dma", "udmabuf": ["udmabuf1"] },
+            "enc/axi_gpio_0": { "uio": "leds" },
+            "enc/axi_gpio_1": { "uio": "reset" },
+        });
+        let info = EncoderHwInfo::from_json(&hw, "enc").unwrap();
+        assert_eq!(info.reset_gpio_name.as_deref(), Some("enc/axi_gpio_1"));
+        assert_eq!(info.reset_gpio.unwrap().uio, "reset");
+
+        //hwinfoのキーでも指定できる
+        hw["enc/jpeg_encoder_0"]["params"]["RESET_GPIO"] = json!("enc/axi_gpio_0");
+        let info = EncoderHwInfo::from_json(&hw, "enc").unwrap();
+        assert_eq!(info.reset_gpio.unwrap().uio, "leds");
+    }
+
+    #[test]
+    fn from_json_reports_bad_reset_gpio_params() {
+        let hw = json!({
+            "enc/jpeg_encoder_0": {
+                "uio": "jpeg",
+                "params": { "RESET_GPIO": "axi_gpio_9", "RESET_GPIO_CHANNEL": 3, "RESET_GPIO_BIT": 32 },
+            },
+            "enc/v_frmbuf_rd_0": { "uio": "vfb", "udmabuf": ["udmabuf0"] },
+            "enc/axi_dma_0": { "uio": "dma", "udmabuf": ["udmabuf1"] },
+        });
+        let paths: Vec<String> = issues(EncoderHwInfo::from_json(&hw, "enc").unwrap_err())
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect();
+        assert_eq!(paths, [r#"$["enc/jpeg_encoder_0"].params.RESET_GPIO"#]);
+
+        let mut hw = hw;
+        hw["enc/axi_gpio_9"] = json!({ "uio": "reset" });
+        let paths: Vec<String> = issues(EncoderHwInfo::from_json(&hw, "enc").unwrap_err())
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect();
+        assert_eq!(paths, [
+            r#"$["enc/jpeg_encoder_0"].params.RESET_GPIO_CHANNEL"#,
+            r#"$["enc/jpeg_encoder_0"].params.RESET_GPIO_BIT"#,
+        ]);
+    }
+
+    #[test]
+    fn parse_uses_hwinfo_key_in_paths() {
+        let hw = json!({ "cam/axi_dma_1": { "uio": "dma" } });
+        let paths: Vec<String> = issues(IpInfo::parse(&hw, "cam/axi_dma_1", true).unwrap_err())
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect();
+        assert_eq!(paths, [r#"$["cam/axi_dma_1"].udmabuf"#]);
+
+        let entry_paths: Vec<String> = issues(IpInfo::parse_entry(&hw["cam/axi_dma_1"], true).unwrap_err())
+            .into_iter()
+            .map(|issue| issue.path)
+            .collect();
+        assert_eq!(entry_paths, ["$.udmabuf"]);
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::Write;
+
+use image::error::{EncodingError, ImageFormatHint, UnsupportedError, UnsupportedErrorKind};
+use image::{ColorType, DynamicImage, GrayImage, ImageError, ImageFormat, ImageResult, RgbImage, RgbaImage};
+
+use crate::encoder::Encoder;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, PixelFormat};
+use crate::jpeg_encoder::JpegEncoder;
+
+//image::ImageBufferの8bitの画像を借用する(空の画像はエラー)
+macro_rules! impl_frame_from {
+    ($img:ty, $format:expr) => {
+        impl<'a> TryFrom<&'a $img> for Frame<'a> {
+            type Error = Error;
+
+            fn try_from(img: &'a $img) -> Result<Self> {
+                Frame::new(img.width() as usize, img.height() as usize, $format, img.as_raw().as_slice())
+            }
+        }
+
+        impl TryFrom<$img> for Frame<'static> {
+            type Error = Error;
+
+            fn try_from(img: $img) -> Result<Self> {
+                Frame::new(img.width() as usize, img.height() as usize, $format, img.into_raw())
+            }
+        }
+    };
+}
+
+impl_frame_from!(RgbImage, PixelFormat::Rgb8);
+impl_frame_from!(RgbaImage, PixelFormat::Rgba8);
+impl_frame_from!(GrayImage, PixelFormat::Gray8);
+
+impl<'a> TryFrom<&'a DynamicImage> for Frame<'a> {
+    type Error = Error;
+
+    /// 8bitのRGB/RGBA/グレースケールは借用し、それ以外はRGB8に変換する
+    fn try_from(img: &'a DynamicImage) -> Result<Self> {
+        match img {
+            DynamicImage::ImageRgb8(img) => Frame::try_from(img),
+            DynamicImage::ImageRgba8(img) => Frame::try_from(img),
+            DynamicImage::ImageLuma8(img) => Frame::try_from(img),
+            img => Frame::try_from(img.to_rgb8()),
+        }
+    }
+}
+
+impl JpegEncoder {
+    /// `image`の画像をエンコード(`Vfb`の形式のRGB8に変換する)
+    pub fn encode_image(&mut self, img: &DynamicImage) -> Result<Vec<u8>> {
+        self.encode_frame(&Frame::try_from(img)?)
+    }
+
+    /// `RgbImage`をエンコード
+    pub fn encode_rgb_image(&mut self, img: &RgbImage) -> Result<Vec<u8>> {
+        //RGB8はコピーせずにそのまま渡せる
+        self.check_frame_size(img.width() as usize, img.height() as usize)?;
+        self.encode(img.as_raw())
+    }
+}
+
+/// `image::ImageEncoder`としてエンコーダを使うためのラッパー
+///
+/// `write_image`ごとに画像サイズで`config`してからエンコードし、`writer`に書き出す
+pub struct JpegImageEncoder<'a, E: Encoder, W: Write> {
+    encoder: &'a mut E,
+    writer: W,
+}
+
+impl<'a, E: Encoder, W: Write> JpegImageEncoder<'a, E, W> {
+    pub fn new(encoder: &'a mut E, writer: W) -> Self {
+        JpegImageEncoder { encoder, writer }
+    }
+}
+
+impl<E: Encoder, W: Write> image::ImageEncoder for JpegImageEncoder<'_, E, W> {
+    fn write_image(mut self, buf: &[u8], width: u32, height: u32, color_type: ColorType) -> ImageResult<()> {
+        let format = match color_type {
+            ColorType::Rgb8 => PixelFormat::Rgb8,
+            ColorType::Rgba8 => PixelFormat::Rgba8,
+            ColorType::L8 => PixelFormat::Gray8,
+            _ => {
+                return Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+                    ImageFormatHint::Exact(ImageFormat::Jpeg),
+                    UnsupportedErrorKind::Color(color_type.into()),
+                )))
+            }
+        };
+
+        let frame = Frame::new(width as usize, height as usize, format, buf).map_err(encoding_error)?;
+        self.encoder.config(frame.width(), frame.height()).map_err(encoding_error)?;
+        let out = self.encoder.encode_frame(&frame).map_err(encoding_error)?;
+
+        self.writer.write_all(&out)?;
+        Ok(())
+    }
+}
+
+fn encoding_error(e: Error) -> ImageError {
+    match e {
+        Error::Io(e) => ImageError::IoError(e),
+        e => ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_borrows_image_buffer() {
+        let img = RgbImage::from_pixel(4, 2, image::Rgb([1, 2, 3]));
+        let frame = Frame::try_from(&img).unwrap();
+        assert_eq!(frame.data().as_ptr(), img.as_raw().as_ptr());
+        assert_eq!((frame.width(), frame.height(), frame.stride()), (4, 2, 12));
+    }
+
+    #[test]
+    fn dynamic_image_converts_other_formats() {
+        let img = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(2, 2, image::Rgb([0xFFFF, 0, 0x8080])));
+        let frame = Frame::try_from(&img).unwrap();
+        assert_eq!(frame.format(), PixelFormat::Rgb8);
+        assert_eq!(frame.data()[..3], [0xFF, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn empty_image_is_rejected() {
+        assert!(Frame::try_from(&RgbImage::new(0, 0)).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::frame::{Frame, PixelFormat};
+
+/// `.dat`ファイルの1行の値の並び
+///
+/// 既存の`.dat`ファイルは1行に1画素を`R,B,G`の順で書いている
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum DatOrder {
+    /// R, B, Gの順(既存の`.dat`ファイル)
+    #[default]
+    Rbg,
+    /// R, G, Bの順
+    Rgb,
+}
+
+/// PPM(P3/P6)またはPGM(P2/P5)を読み込む
+///
+/// PPMはRGB8、PGMはGray8になる。最大値が255以外なら8bitに変換する
+pub fn read_pnm<P: AsRef<Path>>(path: P) -> Result<Frame<'static>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    parse_pnm(&data)
+}
+
+/// メモリ上のPPM/PGMを解析する
+pub fn parse_pnm(data: &[u8]) -> Result<Frame<'static>> {
+    let mut pos = 0;
+    let magic = next_token(data, &mut pos)?;
+    let (format, ascii) = match magic {
+        b"P2" => (PixelFormat::Gray8, true),
+        b"P3" => (PixelFormat::Rgb8, true),
+        b"P5" => (PixelFormat::Gray8, false),
+        b"P6" => (PixelFormat::Rgb8, false),
+        _ => return Err(invalid(format!("unsupported PNM type {:?}", String::from_utf8_lossy(magic)))),
+    };
+
+    let width = parse_header_num(data, &mut pos, "width")?;
+    let height = parse_header_num(data, &mut pos, "height")?;
+    let maxval = parse_header_num(data, &mut pos, "maxval")?;
+    if maxval == 0 || maxval > 0xFFFF {
+        return Err(invalid(format!("invalid PNM maxval {}", maxval)));
+    }
+    let samples = format.frame_len(width, height)?;
+
+    let values: Vec<usize> = if ascii {
+        (0..samples)
+            .map(|_| parse_header_num(data, &mut pos, "sample"))
+            .collect::<Result<_>>()?
+    } else {
+        //ヘッダの後ろは空白1文字だけ
+        pos += 1;
+        let bytes = if maxval > 255 { 2 } else { 1 };
+        let len = samples.checked_mul(bytes).ok_or_else(|| invalid(format!("PNM size {}x{} is too large", width, height)))?;
+        let body = pos.checked_add(len).and_then(|end| data.get(pos..end)).ok_or_else(|| {
+            invalid(format!("PNM data is truncated ({} of {} bytes)", data.len().saturating_sub(pos), len))
+        })?;
+        if bytes == 2 {
+            body.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize).collect()
+        } else {
+            body.iter().map(|&b| b as usize).collect()
+        }
+    };
+
+    let data: Vec<u8> = values
+        .into_iter()
+        .map(|v| if maxval == 255 { v.min(255) as u8 } else { ((v.min(maxval) * 255 + maxval / 2) / maxval) as u8 })
+        .collect();
+    Frame::new(width, height, format, data)
+}
+
+/// ヘッダの無いRGB/BGRなどの生データを、サイズと形式を指定して読み込む
+pub fn read_raw<P: AsRef<Path>>(path: P, width: usize, height: usize, format: PixelFormat) -> Result<Frame<'static>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Frame::new(width, height, format, data)
+}
+
+/// 1行に1画素(`0〜255`の値3つをカンマ区切り)の`.dat`ファイルを読み込んでRGB8にする
+///
+/// 空行は読み飛ばす。画素数が`width * height`と合わなければエラー
+pub fn read_dat<P: AsRef<Path>>(path: P, width: usize, height: usize, order: DatOrder) -> Result<Frame<'static>> {
+    parse_dat(BufReader::new(File::open(path)?), width, height, order)
+}
+
+/// `.dat`形式のデータを読み込む
+pub fn parse_dat<R: BufRead>(reader: R, width: usize, height: usize, order: DatOrder) -> Result<Frame<'static>> {
+    let mut buf = Vec::with_capacity(PixelFormat::Rgb8.frame_len(width, height)?);
+
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let values = line
+            .split(',')
+            .map(|v| v.trim().parse::<u8>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("line {}: {}", i + 1, e)))?;
+        if values.len() != 3 {
+            return Err(invalid(format!("line {}: expected 3 values, got {}", i + 1, values.len())));
+        }
+
+        let rgb = match order {
+            DatOrder::Rbg => [values[0], values[2], values[1]],
+            DatOrder::Rgb => [values[0], values[1], values[2]],
+        };
+        buf.extend_from_slice(&rgb);
+    }
+
+    Frame::new(width, height, PixelFormat::Rgb8, buf)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidImage(msg)
+}
+
+//空白とコメント(#から行末)を読み飛ばして次のトークンを返す
+fn next_token<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8]> {
+    loop {
+        match data.get(*pos) {
+            Some(b'#') => {
+                while data.get(*pos).is_some_and(|&c| c != b'\n') {
+                    *pos += 1;
+                }
+            }
+            Some(c) if c.is_ascii_whitespace() => *pos += 1,
+            Some(_) => break,
+            None => return Err(invalid("PNM data is truncated".to_string())),
+        }
+    }
+    let start = *pos;
+    while data.get(*pos).is_some_and(|c| !c.is_ascii_whitespace()) {
+        *pos += 1;
+    }
+    Ok(&data[start..*pos])
+}
+
+fn parse_header_num(data: &[u8], pos: &mut usize, what: &str) -> Result<usize> {
+    let token = next_token(data, pos)?;
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid(format!("invalid PNM {} {:?}", what, String::from_utf8_lossy(token))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_p6_with_comments() {
+        let mut data = b"P6\n# created by test\n2 1 # size\n255\n".to_vec();
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+        let frame = parse_pnm(&data).unwrap();
+        assert_eq!((frame.width(), frame.height(), frame.format()), (2, 1, PixelFormat::Rgb8));
+        assert_eq!(frame.data(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn parse_p5_scales_maxval() {
+        //最大値15は0〜255に、16bitのサンプルは上位バイトから読む
+        let mut data = b"P5 3 1 15\n".to_vec();
+        data.extend_from_slice(&[0, 15, 8]);
+        let frame = parse_pnm(&data).unwrap();
+        assert_eq!(frame.format(), PixelFormat::Gray8);
+        assert_eq!(frame.data(), [0, 255, 136]);
+
+        let mut data = b"P5 2 1 65535\n".to_vec();
+        data.extend_from_slice(&[0xFF, 0xFF, 0x80, 0x00]);
+        assert_eq!(parse_pnm(&data).unwrap().data(), [255, 128]);
+    }
+
+    #[test]
+    fn parse_p3_ascii() {
+        let frame = parse_pnm(b"P3\n1 1\n255\n10 20 30\n").unwrap();
+        assert_eq!(frame.data(), [10, 20, 30]);
+    }
+
+    #[test]
+    fn parse_pnm_rejects_truncated_data() {
+        let mut data = b"P6 2 2 255\n".to_vec();
+        data.extend_from_slice(&[0; 11]);
+        assert!(matches!(parse_pnm(&data), Err(Error::InvalidImage(_))));
+        assert!(matches!(parse_pnm(b"P3 1 1 255 1 2"), Err(Error::InvalidImage(_))));
+        assert!(matches!(parse_pnm(b"P6 2"), Err(Error::InvalidImage(_))));
+    }
+
+    #[test]
+    fn parse_pnm_rejects_overflowing_size() {
+        let data = format!("P6 {} {} 255\n", usize::MAX / 2, 3);
+        assert!(matches!(parse_pnm(data.as_bytes()), Err(Error::InvalidImage(_))));
+    }
+
+    #[test]
+    fn parse_dat_orders() {
+        let dat = "10,20,30\n\n40, 50, 60\n";
+        let rbg = parse_dat(dat.as_bytes(), 2, 1, DatOrder::Rbg).unwrap();
+        assert_eq!(rbg.data(), [10, 30, 20, 40, 60, 50]);
+        let rgb = parse_dat(dat.as_bytes(), 2, 1, DatOrder::Rgb).unwrap();
+        assert_eq!(rgb.data(), [10, 20, 30, 40, 50, 60]);
+    }
+
+    #[test]
+    fn parse_dat_rejects_bad_lines() {
+        assert!(parse_dat("1,2\n".as_bytes(), 1, 1, DatOrder::Rgb).is_err());
+        assert!(parse_dat("1,2,300\n".as_bytes(), 1, 1, DatOrder::Rgb).is_err());
+        assert!(parse_dat("1,2,3\n".as_bytes(), 2, 1, DatOrder::Rgb).is_err());
+    }
+}
@@ -1,20 +1,57 @@
-use crate::uio::Uio;
+use crate::uio::{Uio, PAGE_SIZE};
 use crate::axidma::Adma;
 use crate::vfrmbuf::Vfb;
-use xipdriver_rs::json_as_map;
-use xipdriver_rs::json_as_str;
-use xipdriver_rs::json_as_u32;
-use anyhow::{anyhow, Result, Context};
+use crate::vfrmbuf_wr::Vfbw;
+use crate::devlock::LockMode;
+use crate::hwinfo::{self, param, EncoderHwInfo};
+use crate::capabilities::Capabilities;
+use crate::error::{Error, Result};
+use crate::quant::{self, QuantTables};
+use crate::bitstream::{self, JpegInfo};
+use crate::metadata::{self, Metadata};
+use crate::frame::{Frame, Roi};
+use crate::scaler::{Scaler, ScalerConfig};
 use log::info;
 use std::fs::File;
 use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
 
-const PAGE_SIZE:usize = 0x1000;
+//デフォルトの階層名
+pub const DEFAULT_HIER: &str = "jpeg_encoder";
+
+// レジスタオフセット定義(出力サイズのレジスタは`Capabilities`で決める)
+//量子化テーブルはhwinfoで`param::HAS_QTABLE`が指定されたIPだけが持つ。
+//64エントリを自然順で4バイトずつ並べたもの(下位8bitが値)
+const JPEG_QTABLE_LUMA: usize = 0x100;
+const JPEG_QTABLE_CHROMA: usize = 0x200;
+
+//AXI GPIOのデータとトライステートのレジスタ(チャンネル2は+0x8)
+const GPIO_DATA: usize = 0x0;
+const GPIO_TRI: usize = 0x4;
+const GPIO2_OFFSET: usize = 0x8;
+
+//リセットを保持する時間(proc_sys_resetや遅いクロックの同期リセットでも取りこぼさない長さ)
+const RESET_HOLD: Duration = Duration::from_micros(100);
+
+//エンコード完了待ちのデフォルトのタイムアウト
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
 
 pub struct JpegEncoder{
     pub uio:Uio,
     pub vfrmbuf:Vfb,
-    pub adma:Adma
+    pub adma:Adma,
+    /// フレームバッファとエンコーダの間のスケーラ(hwinfoの階層にある場合)
+    pub scaler:Option<Scaler>,
+    reset_gpio: Option<Uio>,
+    hw_info: EncoderHwInfo,
+    capabilities: Capabilities,
+    timeout: Duration,
+    encode_started: Option<Instant>,
+    qtables: Option<QuantTables>,
+    frame_size: Option<(usize, usize)>,
+    output_size: Option<(usize, usize)>,
+    convert_threads: usize,
     
     // buf_vfrmbuf:Udma,
     // buf_adma:Udma
@@ -22,103 +59,518 @@ pub struct JpegEncoder{
 
 impl JpegEncoder{
     pub fn new(hw_json_path:&str) -> Result<Self>{
+        JpegEncoder::open(hw_json_path, DEFAULT_HIER, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    ///
+    /// 他のプロセスが同じハードウェアを使用中の場合、`LockMode::NoWait`ではエラーになる
+    pub fn new_with_lock(hw_json_path:&str, lock_mode: LockMode) -> Result<Self>{
+        JpegEncoder::open(hw_json_path, DEFAULT_HIER, lock_mode)
+    }
+
+    /// 階層名を指定してオープン
+    pub fn with_hier(hw_json_path:&str, jpeg_hier:&str) -> Result<Self>{
+        JpegEncoder::open(hw_json_path, jpeg_hier, LockMode::NoWait)
+    }
+
+    /// 階層名とロックの取得方法を指定してオープン
+    pub fn open(hw_json_path:&str, jpeg_hier:&str, lock_mode: LockMode) -> Result<Self>{
         //ハードウェア情報の読み込み
-        let hw_json = xipdriver_rs::hwinfo::read(hw_json_path)?;
-
-        let jpeg_hier = "jpeg_encoder";
-
-        //ハードウェア名を取得
-        let jpeg_uio_name = xipdriver_rs::hwinfo::match_hw(
-            &hw_json,
-            jpeg_hier,
-            "jpeg_encoder"
-        )?;        
-        
-        let uio_obj = json_as_map!(hw_json[jpeg_uio_name]);
-        let uio_name = json_as_str!(uio_obj["uio"]);     
-        
-        let jpeg_vfbr_name = xipdriver_rs::hwinfo::match_hw(
-            &hw_json,
-            jpeg_hier,
-            "v_frmbuf_rd"
-        )?;
-        
-        let jpeg_dma_name = xipdriver_rs::hwinfo::match_hw(
-            &hw_json,
-            jpeg_hier,
-            "axi_dma"
-        )?;
-        
+        let hw_json = hwinfo::read(hw_json_path)?;
+
+        //パイプラインを構成するIPの情報を検証して取得
+        let hw_info = EncoderHwInfo::from_json(&hw_json, jpeg_hier)?;
+        JpegEncoder::from_hw_info(hw_info, lock_mode)
+    }
+
+    /// hwinfoにあるJPEGエンコーダのパイプラインをすべて列挙
+    ///
+    /// 返り値を`from_hw_info`に渡すと、それぞれを別のインスタンスとしてオープンできる
+    pub fn enumerate(hw_json_path:&str) -> Result<Vec<EncoderHwInfo>>{
+        let hw_json = hwinfo::read(hw_json_path)?;
+        EncoderHwInfo::find_all(&hw_json)
+    }
+
+    /// 検証済みのハードウェア情報からオープン
+    pub fn from_hw_info(hw_info: EncoderHwInfo, lock_mode: LockMode) -> Result<Self>{
         //uioをオープン
-        let mut uio = Uio::new(&uio_name,PAGE_SIZE)?;
+        let uio = Uio::new_with_lock(&hw_info.encoder.uio,PAGE_SIZE,lock_mode)?;
+
+        //バージョンと機能を調べ、対応していないIPならここでエラーにする
+        let capabilities = Capabilities::detect(&hw_info, &uio)?;
 
         //video frame buffer をオープン
-        let mut vfrmbuf =  Vfb::new(&hw_json[jpeg_vfbr_name])?;
+        let vfrmbuf = Vfb::from_info(&hw_info.vfrmbuf,lock_mode)?;
 
         //AXI DMAをオープン
-        let mut adma = Adma::new(&hw_json[jpeg_dma_name])?;
+        let adma = Adma::from_info(&hw_info.dma,lock_mode)?;
+
+        //スケーラがあればオープン
+        let scaler = match &hw_info.scaler {
+            Some(info) => Some(Scaler::from_info(info,lock_mode)?),
+            None => None,
+        };
+
+        //リセット線のAXI GPIOが指定されていればオープン
+        let reset_gpio = match &hw_info.reset_gpio {
+            Some(info) => Some(Uio::new_with_lock(&info.uio,PAGE_SIZE,lock_mode)?),
+            None => None,
+        };
 
         Ok(JpegEncoder{
             uio,
             vfrmbuf,
-            adma
+            adma,
+            scaler,
+            reset_gpio,
+            hw_info,
+            capabilities,
+            timeout: DEFAULT_TIMEOUT,
+            encode_started: None,
+            qtables: None,
+            frame_size: None,
+            output_size: None,
+            convert_threads: 1,
         })
             
     }
 
-    pub fn config(&mut self){
+    /// ハードウェア情報を取得
+    pub fn hw_info(&self) -> &EncoderHwInfo {
+        &self.hw_info
+    }
+
+    /// オープン時に調べたハードウェアの機能
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    /// 画像サイズを設定
+    ///
+    /// hwinfoのIPパラメータに最大サイズがあれば、それを超えるとエラーになる。
+    /// スケーラがある場合は拡大縮小せずに通す設定にする
+    pub fn config(&mut self, frame_width: usize, frame_height: usize) -> Result<()>{
+        check_max("frame width", frame_width, self.hw_info.max_width())?;
+        check_max("frame height", frame_height, self.hw_info.max_height())?;
+
+        if let Some(scaler) = &mut self.scaler {
+            scaler.configure(&ScalerConfig::passthrough(frame_width, frame_height))?;
+        }
+        self.apply_config(frame_width, frame_height, (frame_width, frame_height))
+    }
+
+    /// スケーラで入力の解像度・色空間を変換してエンコードするように設定
+    ///
+    /// 入力サイズはフレームバッファとスケーラ、出力サイズはエンコーダの最大サイズで確認する
+    pub fn config_scaled(&mut self, config: &ScalerConfig) -> Result<()>{
+        check_max("frame width", config.in_width, self.hw_info.max_input_width())?;
+        check_max("frame height", config.in_height, self.hw_info.max_input_height())?;
+        check_max("output width", config.out_width, self.hw_info.encoder.max_width())?;
+        check_max("output height", config.out_height, self.hw_info.encoder.max_height())?;
+
+        let scaler = self.scaler.as_mut().ok_or_else(|| {
+            Error::InvalidConfig(format!("{} has no video processing subsystem", self.hw_info.hier))
+        })?;
+        scaler.configure(config)?;
+        self.apply_config(config.in_width, config.in_height, (config.out_width, config.out_height))
+    }
+
+    //フレームバッファとDMAを設定し、スケーラを開始する
+    fn apply_config(&mut self, frame_width: usize, frame_height: usize, output_size: (usize, usize)) -> Result<()>{
         self.vfrmbuf.set_phys_addr();
-        self.vfrmbuf.set_format(1280,720);
+        self.vfrmbuf.set_format(frame_width,frame_height);
 
         self.adma.s2mm_reset();
         self.adma.set_s2mm_addr();
+
+        //スケーラは自動再スタートでフレームを待ち続ける
+        if let Some(scaler) = &self.scaler {
+            scaler.start()?;
+        }
+
+        self.frame_size = Some((frame_width, frame_height));
+        self.output_size = Some(output_size);
+        Ok(())
     }
 
-    pub fn encode(&mut self,img_data: &[u8]) -> Result<Vec<u8>>{
-        //self.vfrmbuf.buf.write_to_buf(&img_data).unwrap();
+    /// 設定した画像サイズ(未設定ならNone)
+    pub fn frame_size(&self) -> Option<(usize, usize)> {
+        self.frame_size
+    }
+
+    /// エンコードされるJPEGの画像サイズ(スケーラを使う場合は出力サイズ)
+    pub fn output_size(&self) -> Option<(usize, usize)> {
+        self.output_size
+    }
+
+    /// パイプライン全体をリセットし、直前の設定を適用し直す
+    ///
+    /// フレームバッファとスケーラを停止し、DMAの両チャンネルとエンコーダのコアをリセットする。
+    /// コアはhwinfoの`RESET_GPIO`で指定されたAXI GPIOのリセット線、なければソフトリセットのレジスタでリセットする
+    pub fn reset(&mut self) -> Result<()>{
+        //フレームバッファを止め、読み出し中のフレームがあれば終わるのを待つ
+        self.vfrmbuf.stop();
+        if let Err(e) = self.vfrmbuf.wait_idle(self.timeout) {
+            log::warn!("frame buffer did not become idle before reset: {}", e);
+        }
+        self.vfrmbuf.clear_region();
+
+        let scaler_config = self.scaler.as_ref().and_then(|s| s.config().copied());
+        if let Some(scaler) = &mut self.scaler {
+            scaler.stop();
+            scaler.reset();
+        }
+
+        self.adma.mm2s_reset();
+        self.adma.s2mm_reset();
+        self.reset_core()?;
+        self.encode_started = None;
+
+        //直前の設定を適用し直す
+        match (scaler_config, self.frame_size) {
+            (Some(config), _) if config != ScalerConfig::passthrough(config.in_width, config.in_height) => {
+                self.config_scaled(&config)?
+            }
+            (_, Some((width, height))) => self.config(width, height)?,
+            _ => {}
+        }
+        if let Some(tables) = self.qtables {
+            self.set_quant_tables(&tables)?;
+        }
+        Ok(())
+    }
+
+    //エンコーダのコアをリセット
+    //
+    //AXI GPIOは`xipdriver_rs::axigpio::AxiGpio`ではなくUIOで直接操作する。
+    //AxiGpioはチャンネル全体を書き込むので同じGPIOの他のビットを壊し、デバイスロックも取らないため
+    fn reset_core(&mut self) -> Result<()>{
+        if let Some(gpio) = &self.reset_gpio {
+            //チャンネルとビットはhwinfoの検証で範囲を確認済み
+            let encoder = &self.hw_info.encoder;
+            let offset = if encoder.param_u32(param::RESET_GPIO_CHANNEL) == Some(2) { GPIO2_OFFSET } else { 0 };
+            let mask = 1u32 << encoder.param_u32(param::RESET_GPIO_BIT).unwrap_or(0);
+
+            //リセット線のビットだけを出力にして書き換え、他のビットはそのままにする
+            gpio.write_mem32(offset + GPIO_TRI, gpio.read_mem32(offset + GPIO_TRI) & !mask);
+            let data = gpio.read_mem32(offset + GPIO_DATA);
+            let (assert, release) = if encoder.param_bool(param::RESET_ACTIVE_HIGH) {
+                (data | mask, data & !mask)
+            } else {
+                (data & !mask, data | mask)
+            };
+            gpio.write_mem32(offset + GPIO_DATA, assert);
+            thread::sleep(RESET_HOLD);
+            gpio.write_mem32(offset + GPIO_DATA, release);
+        } else if let Some(offset) = self.capabilities.soft_reset_offset {
+            self.uio.write_mem32(offset, 1);
+            thread::sleep(RESET_HOLD);
+            self.uio.write_mem32(offset, 0);
+        } else {
+            log::warn!("{} has no reset line or soft reset register; only the DMA was reset", self.hw_info.encoder_name);
+        }
+        Ok(())
+    }
+
+    /// 量子化テーブルのレジスタがあるかどうか
+    pub fn has_quant_tables(&self) -> bool {
+        self.capabilities.quality_control
+    }
+
+    /// 品質(1〜100)を設定
+    ///
+    /// 標準テーブルをIJG方式でスケーリングしてレジスタに書き込む
+    pub fn set_quality(&mut self, quality: u32) -> Result<()> {
+        quant::check_quality(quality)?;
+        self.set_quant_tables(&QuantTables::from_quality(quality))
+    }
+
+    /// 輝度・色差の量子化テーブル(自然順)をレジスタに書き込む
+    pub fn set_quant_tables(&mut self, tables: &QuantTables) -> Result<()> {
+        if !self.has_quant_tables() {
+            return Err(Error::InvalidConfig(format!(
+                "{} has no quantization table registers", self.hw_info.encoder_name
+            )));
+        }
+        if tables.luma.contains(&0) || tables.chroma.contains(&0) {
+            return Err(Error::InvalidConfig("quantization table entries must be non-zero".to_string()));
+        }
+
+        for (i, (&luma, &chroma)) in tables.luma.iter().zip(tables.chroma.iter()).enumerate() {
+            self.uio.write_mem32(JPEG_QTABLE_LUMA + i * 4, luma as u32);
+            self.uio.write_mem32(JPEG_QTABLE_CHROMA + i * 4, chroma as u32);
+        }
+        self.qtables = Some(*tables);
+        Ok(())
+    }
+
+    /// 設定した量子化テーブルを取得(未設定ならNone)
+    pub fn quant_tables(&self) -> Option<&QuantTables> {
+        self.qtables.as_ref()
+    }
+
+    /// エンコード完了待ちのタイムアウトを設定
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// `encode_frame`で色変換に使うスレッド数を設定(1で並列化しない)
+    pub fn set_convert_threads(&mut self, threads: usize) {
+        self.convert_threads = threads.max(1);
+    }
+
+    /// エンコード中かどうか
+    pub fn is_busy(&self) -> bool {
+        self.encode_started.is_some()
+    }
+
+    /// エンコードを開始して、完了を待たずに戻る
+    ///
+    /// 結果は`poll_encode`または`finish_encode`で受け取る
+    pub fn start_encode(&mut self,img_data: &[u8]) -> Result<()>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
 
         //dma をスタート
         self.adma.start()?;
         //画像データ書き込み開始
-        self.vfrmbuf.start(&img_data)?;
+        self.vfrmbuf.start(img_data)?;
         //エンコードデータ読み込みスタート
-        self.adma.set_s2mm_length(0x200000);
+        self.adma.set_s2mm_length_to_buf();
 
-        //完了するまで待ち
-        while !self.adma.is_idle(){}
+        self.encode_started = Some(Instant::now());
+        Ok(())
+    }
+
+    /// フレームをRGB8に変換しながらバッファに書き込み、エンコードを開始する
+    pub fn start_encode_frame(&mut self,frame: &Frame) -> Result<()>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
+        self.check_frame_size(frame.width(), frame.height())?;
+
+        self.adma.start()?;
+        self.vfrmbuf.start_frame(frame, self.convert_threads)?;
+        self.adma.set_s2mm_length_to_buf();
+
+        self.encode_started = Some(Instant::now());
+        Ok(())
+    }
+
+    /// エンコードが完了していれば結果を返す
+    ///
+    /// 完了していなければ`Ok(None)`、DMAエラーやタイムアウトの場合はエラーを返す
+    pub fn poll_encode(&mut self) -> Result<Option<Vec<u8>>>{
+        let started = match self.encode_started {
+            Some(started) => started,
+            None => return Err(Error::InvalidConfig("no encode in progress".to_string())),
+        };
+
+        if let Err(e) = self.adma.check_s2mm_error() {
+            self.encode_started = None;
+            return Err(e);
+        }
+        if !self.adma.is_idle() {
+            if started.elapsed() > self.timeout {
+                self.encode_started = None;
+                return Err(Error::Timeout { what: "AXI DMA S2MM", timeout: self.timeout });
+            }
+            return Ok(None);
+        }
+        self.encode_started = None;
 
         //エンコードデータのサイズを取得
-        let len = self.uio.read_mem32(0x04) as usize;        
-        
-        
-        Ok(self.adma.buf.read_from_buf(len).unwrap())
+        let len = self.uio.read_mem32(self.capabilities.out_length_offset) as usize;        
+        let out = self.adma.buf.read_from_buf(len)?;
+
+        //設定した量子化テーブルが実際に使われたか確認
+        if let Some(tables) = &self.qtables {
+            quant::verify_dqt(&out, tables)?;
+        }
 
+        Ok(Some(out))
     }
 
+    /// エンコードの完了を待って結果を返す
+    pub fn finish_encode(&mut self) -> Result<Vec<u8>>{
+        //完了するまで待ち
+        loop {
+            if let Some(out) = self.poll_encode()? {
+                return Ok(out);
+            }
+        }
+    }
 
-    pub fn encode_file(&mut self,img_data: &[u8],o_file_name:&str)->Result<()>{
+    pub fn encode(&mut self,img_data: &[u8]) -> Result<Vec<u8>>{
+        //self.vfrmbuf.buf.write_to_buf(&img_data).unwrap();
+
+        self.start_encode(img_data)?;
+        self.finish_encode()
+    }
+
+    /// フレームをエンコード
+    ///
+    /// サイズが`config`と異なればエラー。RGB8以外はバッファに書き込みながらRGB8に変換する
+    pub fn encode_frame(&mut self,frame: &Frame) -> Result<Vec<u8>>{
+        self.start_encode_frame(frame)?;
+        self.finish_encode()
+    }
+
+    //入力画像のサイズが設定と一致するか
+    pub(crate) fn check_frame_size(&self, width: usize, height: usize) -> Result<()>{
+        if self.frame_size != Some((width, height)) {
+            return Err(Error::InvalidConfig(format!(
+                "frame size {}x{} does not match configured size {:?}", width, height, self.frame_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// フレームを書き込み、フレームバッファを連続モードで開始する(動画のストリーミング用)
+    ///
+    /// 以降は`encode_streamed`で1フレームずつ結果を受け取り、`update_stream`で入力を書き換える
+    pub fn start_streaming(&mut self,frame: &Frame) -> Result<()>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
+        self.check_frame_size(frame.width(), frame.height())?;
+        self.vfrmbuf.write_frame(frame, self.convert_threads)?;
+        self.vfrmbuf.start_continuous()
+    }
+
+    /// 連続モードの入力フレームをその場で書き換える
+    pub fn update_stream(&mut self,frame: &Frame) -> Result<()>{
+        self.check_frame_size(frame.width(), frame.height())?;
+        self.vfrmbuf.update_frame(frame, self.convert_threads)
+    }
+
+    /// 連続モードで次のフレームのエンコード結果を受け取る
+    pub fn encode_streamed(&mut self) -> Result<Vec<u8>>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
+        if !self.vfrmbuf.is_continuous() {
+            return Err(Error::InvalidConfig("streaming is not started".to_string()));
+        }
 
-        //dma をスタート
         self.adma.start()?;
-        //画像データ書き込み開始
-        self.vfrmbuf.start(&img_data)?;
-        //エンコードデータ読み込みスタート
-        self.adma.set_s2mm_length(0x200000);
+        self.adma.set_s2mm_length_to_buf();
+        self.encode_started = Some(Instant::now());
+        self.finish_encode()
+    }
 
-        //完了するまで待ち
-        while !self.adma.is_idle(){}
+    /// 連続モードを解除し、フレームバッファがアイドルになるのを待つ
+    pub fn stop_streaming(&mut self) -> Result<()>{
+        self.vfrmbuf.stop_continuous(self.timeout)
+    }
 
-        //エンコードデータのサイズを取得
-        let len = self.uio.read_mem32(0x04) as usize;        
-        let out = self.adma.buf.read_from_buf(len).unwrap();
+    /// フレームのうち`roi`の領域だけをエンコード
+    ///
+    /// フレーム全体をバッファに書き込み、フレームバッファには領域だけを読み出させる。
+    /// 同じフレームの別の領域は`encode_region`で続けてエンコードできる
+    pub fn encode_roi(&mut self,frame: &Frame,roi: Roi) -> Result<Vec<u8>>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
+        self.check_frame_size(frame.width(), frame.height())?;
+        self.vfrmbuf.write_frame(frame, self.convert_threads)?;
+        self.encode_region(roi)
+    }
+
+    /// 直前に書き込んだフレームのうち`roi`の領域をエンコード
+    ///
+    /// 領域の開始位置は8バイト境界(xが8の倍数)であること
+    pub fn encode_region(&mut self,roi: Roi) -> Result<Vec<u8>>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
+        self.vfrmbuf.set_region(roi)?;
+
+        let result = self.adma.start().and_then(|_| self.vfrmbuf.start_buffered());
+        if let Err(e) = result {
+            self.vfrmbuf.clear_region();
+            return Err(e);
+        }
+        self.adma.set_s2mm_length_to_buf();
+        self.encode_started = Some(Instant::now());
+
+        let out = self.finish_encode();
+        self.vfrmbuf.clear_region();
+        out
+    }
+
+    /// フレームバッファライタが取り込んだフレームをエンコード
+    ///
+    /// 取り込み用のu-dma-bufの物理アドレスをフレームバッファに渡し、CPUでのコピーをしない。
+    /// 取り込みのサイズは`config`と同じであること
+    pub fn encode_captured(&mut self,capture: &Vfbw) -> Result<Vec<u8>>{
+        if self.is_busy() {
+            return Err(Error::InvalidConfig("previous encode has not finished".to_string()));
+        }
+        let (width, height) = capture.size();
+        self.check_frame_size(width, height)?;
+
+        let result = self.adma.start()
+            .and_then(|_| self.vfrmbuf.start_external(capture.phys_addr(), capture.stride()));
+        if let Err(e) = result {
+            self.vfrmbuf.clear_region();
+            return Err(e);
+        }
+        self.adma.set_s2mm_length_to_buf();
+        self.encode_started = Some(Instant::now());
+
+        let out = self.finish_encode();
+        self.vfrmbuf.clear_region();
+        out
+    }
+
+    /// 1フレームを取り込み、そのままコピーせずにエンコード
+    ///
+    /// 取り込みの完了待ちにもエンコードのタイムアウトを使う
+    pub fn capture_and_encode(&mut self,capture: &mut Vfbw) -> Result<Vec<u8>>{
+        capture.capture(self.timeout)?;
+        self.encode_captured(capture)
+    }
+
+    /// エンコードし、出力のJPEGを検証してから返す
+    ///
+    /// SOI/EOI, DQT/DHT/SOSがあり、SOFのサイズが`config`と一致しなければエラー
+    pub fn encode_validated(&mut self,img_data: &[u8]) -> Result<Vec<u8>>{
+        let out = self.encode(img_data)?;
+        self.validate(&out)?;
+        Ok(out)
+    }
+
+    /// エンコード結果のJPEGを設定した画像サイズと照らし合わせて検証する
+    pub fn validate(&self, jpeg: &[u8]) -> Result<JpegInfo>{
+        let (width, height) = self.output_size
+            .ok_or_else(|| Error::InvalidConfig("frame size is not configured".to_string()))?;
+        bitstream::validate(jpeg, width, height)
+    }
+
+    /// エンコードし、メタデータ(JFIF/EXIF/コメント)を埋め込んで返す
+    pub fn encode_with_metadata(&mut self,img_data: &[u8],meta: &Metadata) -> Result<Vec<u8>>{
+        let out = self.encode(img_data)?;
+        metadata::inject(&out, meta)
+    }
+
+    pub fn encode_file(&mut self,img_data: &[u8],o_file_name:&str)->Result<()>{
+        let out = self.encode(img_data)?;
 
         //ファイル出力
-        let mut file=File::create(o_file_name).context("Failed open jpeg file")?;       
-        file.write_all(&out).context("Failed output jpeg file")?;
+        let mut file=File::create(o_file_name)?;       
+        file.write_all(&out)?;
 
         Ok(())
-        
-
     }
 
 }
+
+fn check_max(what: &'static str, size: usize, max: Option<u32>) -> Result<()>{
+    match max {
+        Some(max) if size > max as usize => Err(Error::SizeExceeded { what, size, limit: max as usize }),
+        _ => Ok(()),
+    }
+}
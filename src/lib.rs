@@ -2,4 +2,33 @@ pub mod uio;
 pub mod udma;
 pub mod axidma;
 pub mod vfrmbuf;
+pub mod vfrmbuf_wr;
 pub mod jpeg_encoder;
+pub mod devlock;
+pub mod encoder;
+pub mod soft_jpeg;
+pub mod error;
+pub mod hwinfo;
+pub mod capabilities;
+pub mod encoder_pool;
+pub mod quant;
+pub mod rate_control;
+pub mod bitstream;
+pub mod metadata;
+pub mod avi;
+pub mod frame;
+pub mod input;
+pub mod convert;
+pub mod tiled;
+pub mod thumbnail;
+pub mod scaler;
+
+//MJPEGのHTTP配信
+#[cfg(feature = "mjpeg-server")]
+pub mod mjpeg_server;
+
+//imageクレートとの連携
+#[cfg(feature = "image")]
+pub mod image_support;
+
+pub use error::{Error, Result};
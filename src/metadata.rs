@@ -0,0 +1,397 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bitstream::{self, APP0, APP1, COM};
+use crate::error::{Error, Result};
+
+//セグメントに入るデータの最大長(長さフィールドの2バイトを除く)
+const MAX_SEGMENT_PAYLOAD: usize = 0xFFFF - 2;
+
+const JFIF_ID: &[u8] = b"JFIF\0";
+const EXIF_ID: &[u8] = b"Exif\0\0";
+
+// TIFFのタグ
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_DATETIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATETIME_ORIGINAL: u16 = 0x9003;
+const TAG_BODY_SERIAL: u16 = 0xA431;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+
+//Compressionの値(JPEG圧縮のサムネイル)
+const COMPRESSION_JPEG: u16 = 6;
+
+// TIFFの型
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+
+/// EXIFの画像の向き
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
+pub enum Orientation {
+    #[default]
+    Normal = 1,
+    FlipHorizontal = 2,
+    Rotate180 = 3,
+    FlipVertical = 4,
+    Transpose = 5,
+    Rotate90 = 6,
+    Transverse = 7,
+    Rotate270 = 8,
+}
+
+/// JPEGに埋め込むメタデータ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    /// JFIFのAPP0が無ければ追加する
+    pub jfif: bool,
+    /// 撮影時刻(UTCでDateTime/DateTimeOriginalに書き込む)
+    pub timestamp: Option<SystemTime>,
+    /// カメラのシリアル番号(BodySerialNumber)
+    pub serial: Option<String>,
+    pub orientation: Option<Orientation>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// EXIFのIFD1に埋め込むサムネイル(JPEG)
+    pub thumbnail: Option<Vec<u8>>,
+    /// COMセグメントとして追加するコメント
+    pub comments: Vec<String>,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            jfif: true,
+            timestamp: None,
+            serial: None,
+            orientation: None,
+            make: None,
+            model: None,
+            thumbnail: None,
+            comments: Vec::new(),
+        }
+    }
+}
+
+impl Metadata {
+    /// EXIFに書き込む項目があるかどうか
+    pub fn has_exif(&self) -> bool {
+        self.timestamp.is_some()
+            || self.serial.is_some()
+            || self.orientation.is_some()
+            || self.make.is_some()
+            || self.model.is_some()
+            || self.thumbnail.is_some()
+    }
+}
+
+/// エンコード済みのJPEGにメタデータのセグメントを挿入する(再エンコードはしない)
+///
+/// SOIの直後にAPP0(JFIF)、APP1(EXIF)、COMの順に並べる。
+/// 元のJFIF APP0はそのまま残し、元のEXIF APP1は置き換える
+pub fn inject(jpeg: &[u8], meta: &Metadata) -> Result<Vec<u8>> {
+    let info = bitstream::parse(jpeg)?;
+
+    let is_jfif = |s: &bitstream::Segment| s.marker == APP0 && jpeg[s.payload()].starts_with(JFIF_ID);
+    let is_exif = |s: &bitstream::Segment| s.marker == APP1 && jpeg[s.payload()].starts_with(EXIF_ID);
+
+    let mut out = Vec::with_capacity(jpeg.len() + 0x200);
+    out.extend_from_slice(&jpeg[..2]);
+
+    //APP0 (JFIF)
+    match info.segments.iter().find(|s| is_jfif(s)) {
+        Some(s) => out.extend_from_slice(&jpeg[s.range()]),
+        None if meta.jfif => {
+            write_segment(&mut out, APP0, &[
+                b'J', b'F', b'I', b'F', 0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+            ])?;
+        }
+        None => {}
+    }
+
+    //APP1 (EXIF)
+    if meta.has_exif() {
+        let mut payload = EXIF_ID.to_vec();
+        payload.extend(build_tiff(meta));
+        write_segment(&mut out, APP1, &payload)?;
+    }
+
+    //COM
+    for comment in &meta.comments {
+        write_segment(&mut out, COM, comment.as_bytes())?;
+    }
+
+    //残りのセグメントとスキャンデータ
+    for s in info.segments.iter().filter(|s| !(is_jfif(s) || (meta.has_exif() && is_exif(s)))) {
+        out.extend_from_slice(&jpeg[s.range()]);
+    }
+    let sos_end = info.segments.last().map_or(2, |s| s.range().end);
+    out.extend_from_slice(&jpeg[sos_end..]);
+
+    Ok(out)
+}
+
+fn write_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<()> {
+    if payload.len() > MAX_SEGMENT_PAYLOAD {
+        return Err(Error::SizeExceeded { what: "metadata segment", size: payload.len(), limit: MAX_SEGMENT_PAYLOAD });
+    }
+    out.extend_from_slice(&[0xFF, marker]);
+    out.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+//IFDのエントリ(値はビッグエンディアンで格納済み)
+struct Entry {
+    tag: u16,
+    typ: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn ascii(tag: u16, s: &str) -> Self {
+        let mut data = s.as_bytes().to_vec();
+        data.push(0);
+        Entry { tag, typ: TYPE_ASCII, count: data.len() as u32, data }
+    }
+
+    fn short(tag: u16, v: u16) -> Self {
+        Entry { tag, typ: TYPE_SHORT, count: 1, data: v.to_be_bytes().to_vec() }
+    }
+
+    fn long(tag: u16, v: u32) -> Self {
+        Entry { tag, typ: TYPE_LONG, count: 1, data: v.to_be_bytes().to_vec() }
+    }
+
+    //IFDの外に置くデータのサイズ(偶数にそろえる)
+    fn extra_len(&self) -> usize {
+        if self.data.len() > 4 { (self.data.len() + 1) & !1 } else { 0 }
+    }
+}
+
+fn ifd_len(entries: &[Entry]) -> usize {
+    2 + entries.len() * 12 + 4 + entries.iter().map(Entry::extra_len).sum::<usize>()
+}
+
+//TIFF先頭からoffsetの位置にIFDを書き込む
+fn write_ifd(out: &mut Vec<u8>, entries: &mut [Entry], next_ifd: u32) {
+    entries.sort_by_key(|e| e.tag);
+
+    let offset = out.len();
+    let mut extra = offset + 2 + entries.len() * 12 + 4;
+    let mut extra_data = Vec::new();
+
+    out.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+    for e in entries.iter() {
+        out.extend_from_slice(&e.tag.to_be_bytes());
+        out.extend_from_slice(&e.typ.to_be_bytes());
+        out.extend_from_slice(&e.count.to_be_bytes());
+        if e.data.len() > 4 {
+            out.extend_from_slice(&(extra as u32).to_be_bytes());
+            extra_data.extend_from_slice(&e.data);
+            extra_data.resize(extra_data.len() + e.extra_len() - e.data.len(), 0);
+            extra += e.extra_len();
+        } else {
+            let mut value = [0u8; 4];
+            value[..e.data.len()].copy_from_slice(&e.data);
+            out.extend_from_slice(&value);
+        }
+    }
+    out.extend_from_slice(&next_ifd.to_be_bytes());
+    out.extend(extra_data);
+}
+
+//EXIFのTIFF構造(ビッグエンディアン)を作る
+fn build_tiff(meta: &Metadata) -> Vec<u8> {
+    let datetime = meta.timestamp.map(exif_datetime);
+
+    let mut ifd0 = Vec::new();
+    if let Some(make) = &meta.make {
+        ifd0.push(Entry::ascii(TAG_MAKE, make));
+    }
+    if let Some(model) = &meta.model {
+        ifd0.push(Entry::ascii(TAG_MODEL, model));
+    }
+    if let Some(orientation) = meta.orientation {
+        ifd0.push(Entry::short(TAG_ORIENTATION, orientation as u16));
+    }
+    if let Some(datetime) = &datetime {
+        ifd0.push(Entry::ascii(TAG_DATETIME, datetime));
+    }
+
+    let mut exif_ifd = Vec::new();
+    if let Some(datetime) = &datetime {
+        exif_ifd.push(Entry::ascii(TAG_DATETIME_ORIGINAL, datetime));
+    }
+    if let Some(serial) = &meta.serial {
+        exif_ifd.push(Entry::ascii(TAG_BODY_SERIAL, serial));
+    }
+    if !exif_ifd.is_empty() {
+        //オフセットはサイズが決まってから入れる
+        ifd0.push(Entry::long(TAG_EXIF_IFD, 0));
+    }
+
+    //IFD0, Exif IFD, IFD1, サムネイルの順に配置
+    let ifd0_offset = 8;
+    let exif_offset = ifd0_offset + ifd_len(&ifd0);
+    let ifd1_offset = exif_offset + if exif_ifd.is_empty() { 0 } else { ifd_len(&exif_ifd) };
+
+    if let Some(e) = ifd0.iter_mut().find(|e| e.tag == TAG_EXIF_IFD) {
+        e.data = (exif_offset as u32).to_be_bytes().to_vec();
+    }
+
+    let mut ifd1 = Vec::new();
+    if let Some(thumbnail) = &meta.thumbnail {
+        ifd1.push(Entry::short(TAG_COMPRESSION, COMPRESSION_JPEG));
+        ifd1.push(Entry::long(TAG_JPEG_OFFSET, 0));
+        ifd1.push(Entry::long(TAG_JPEG_LENGTH, thumbnail.len() as u32));
+        let thumb_offset = ifd1_offset + ifd_len(&ifd1);
+        if let Some(e) = ifd1.iter_mut().find(|e| e.tag == TAG_JPEG_OFFSET) {
+            e.data = (thumb_offset as u32).to_be_bytes().to_vec();
+        }
+    }
+
+    let mut tiff = vec![b'M', b'M', 0x00, 0x2A];
+    tiff.extend_from_slice(&(ifd0_offset as u32).to_be_bytes());
+
+    let next_ifd = if ifd1.is_empty() { 0 } else { ifd1_offset as u32 };
+    write_ifd(&mut tiff, &mut ifd0, next_ifd);
+    if !exif_ifd.is_empty() {
+        write_ifd(&mut tiff, &mut exif_ifd, 0);
+    }
+    if let Some(thumbnail) = &meta.thumbnail {
+        write_ifd(&mut tiff, &mut ifd1, 0);
+        tiff.extend_from_slice(thumbnail);
+    }
+    tiff
+}
+
+//EXIFの日時形式("YYYY:MM:DD HH:MM:SS", UTC)
+fn exif_datetime(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    //1970-01-01からの日数をグレゴリオ暦に変換
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+        year, month, day, rem / 3600, rem % 3600 / 60, rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    //IFDのエントリ(タグ, 型, 個数, 値またはオフセット)
+    type RawEntry = (u16, u16, u32, [u8; 4]);
+
+    //TIFFのIFDを読み出す(エントリと次のIFDのオフセット)
+    fn read_ifd(tiff: &[u8], offset: usize) -> (Vec<RawEntry>, u32) {
+        let be16 = |p: usize| u16::from_be_bytes([tiff[p], tiff[p + 1]]);
+        let count = be16(offset) as usize;
+        let entries = (0..count)
+            .map(|i| {
+                let p = offset + 2 + i * 12;
+                let count = u32::from_be_bytes(tiff[p + 4..p + 8].try_into().unwrap());
+                (be16(p), be16(p + 2), count, tiff[p + 8..p + 12].try_into().unwrap())
+            })
+            .collect();
+        let p = offset + 2 + count * 12;
+        (entries, u32::from_be_bytes(tiff[p..p + 4].try_into().unwrap()))
+    }
+
+    fn value(entries: &[RawEntry], tag: u16) -> [u8; 4] {
+        entries.iter().find(|e| e.0 == tag).unwrap_or_else(|| panic!("tag {:#06x} missing", tag)).3
+    }
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn exif_datetime_converts_utc() {
+        assert_eq!(exif_datetime(at(0)), "1970:01:01 00:00:00");
+        assert_eq!(exif_datetime(at(951_782_400)), "2000:02:29 00:00:00");
+        assert_eq!(exif_datetime(at(1_700_000_000)), "2023:11:14 22:13:20");
+        assert_eq!(exif_datetime(at(4_107_542_399)), "2100:02:28 23:59:59");
+    }
+
+    #[test]
+    fn tiff_has_sorted_ifd0_and_exif_ifd() {
+        let meta = Metadata {
+            timestamp: Some(at(1_700_000_000)),
+            serial: Some("SN1".to_string()),
+            orientation: Some(Orientation::Rotate90),
+            ..Metadata::default()
+        };
+        let tiff = build_tiff(&meta);
+        assert_eq!(&tiff[..8], b"MM\x00\x2A\x00\x00\x00\x08");
+
+        let (ifd0, next) = read_ifd(&tiff, 8);
+        let tags: Vec<u16> = ifd0.iter().map(|e| e.0).collect();
+        assert_eq!(tags, [TAG_ORIENTATION, TAG_DATETIME, TAG_EXIF_IFD]);
+        assert_eq!(next, 0);
+        assert_eq!(value(&ifd0, TAG_ORIENTATION)[..2], 6u16.to_be_bytes());
+
+        let datetime = u32::from_be_bytes(value(&ifd0, TAG_DATETIME)) as usize;
+        assert_eq!(&tiff[datetime..datetime + 20], b"2023:11:14 22:13:20\0");
+
+        let (exif, _) = read_ifd(&tiff, u32::from_be_bytes(value(&ifd0, TAG_EXIF_IFD)) as usize);
+        assert_eq!(value(&exif, TAG_BODY_SERIAL), *b"SN1\0");
+        assert!(exif.iter().any(|e| e.0 == TAG_DATETIME_ORIGINAL));
+    }
+
+    #[test]
+    fn tiff_ifd1_describes_jpeg_thumbnail() {
+        let thumbnail = vec![0xFF, 0xD8, 0x01, 0x02, 0x03, 0xFF, 0xD9];
+        let meta = Metadata { thumbnail: Some(thumbnail.clone()), ..Metadata::default() };
+        let tiff = build_tiff(&meta);
+
+        let (_, ifd1_offset) = read_ifd(&tiff, 8);
+        let (ifd1, next) = read_ifd(&tiff, ifd1_offset as usize);
+        let tags: Vec<u16> = ifd1.iter().map(|e| e.0).collect();
+        assert_eq!(tags, [TAG_COMPRESSION, TAG_JPEG_OFFSET, TAG_JPEG_LENGTH]);
+        assert_eq!(next, 0);
+        assert_eq!(ifd1[0].1, TYPE_SHORT);
+        assert_eq!(value(&ifd1, TAG_COMPRESSION)[..2], COMPRESSION_JPEG.to_be_bytes());
+
+        let offset = u32::from_be_bytes(value(&ifd1, TAG_JPEG_OFFSET)) as usize;
+        let len = u32::from_be_bytes(value(&ifd1, TAG_JPEG_LENGTH)) as usize;
+        assert_eq!(&tiff[offset..offset + len], &thumbnail[..]);
+    }
+
+    #[test]
+    fn inject_replaces_exif_and_keeps_scan() {
+        let mut encoder = crate::soft_jpeg::SoftJpegEncoder::new();
+        encoder.config(16, 16);
+        let jpeg = encoder.encode(&[0x80; 16 * 16 * 3]).unwrap();
+        let meta = Metadata { serial: Some("A".to_string()), ..Metadata::default() };
+
+        //2回目は元のEXIFを置き換えるので結果は変わらない
+        let once = inject(&jpeg, &meta).unwrap();
+        assert_eq!(inject(&once, &meta).unwrap(), once);
+
+        let meta = Metadata { comments: vec!["c".to_string()], ..meta };
+        let out = inject(&once, &meta).unwrap();
+        let info = bitstream::parse(&out).unwrap();
+        let markers: Vec<u8> = info.segments.iter().take(3).map(|s| s.marker).collect();
+        assert_eq!(markers, [APP0, APP1, COM]);
+        assert_eq!(&out[info.scan_data.clone()], &jpeg[bitstream::parse(&jpeg).unwrap().scan_data]);
+    }
+}
@@ -0,0 +1,344 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::encoder::Encoder;
+use crate::error::{Error, Result};
+
+const BOUNDARY: &str = "jpegframe";
+
+//ストリームのスレッドが停止を確認する間隔
+const WAIT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// サーバーの設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// 同時に処理する接続数(超えた接続には503を返して閉じる)
+    pub max_connections: usize,
+    /// 1回の読み書きのタイムアウト(止まったビューアはこの時間で切断する)
+    pub io_timeout: Duration,
+    /// リクエスト行とヘッダの最大長(バイト)
+    pub max_request_len: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_connections: 16,
+            io_timeout: Duration::from_secs(10),
+            max_request_len: 8192,
+        }
+    }
+}
+
+/// エンコードする画像データの供給元
+pub trait FrameSource {
+    /// 次のフレームの画像データ(終わりならNone)
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+impl<F: FnMut() -> Result<Option<Vec<u8>>>> FrameSource for F {
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        self()
+    }
+}
+
+//全ビューアで共有する最新フレーム
+struct Shared {
+    latest: Mutex<Option<(u64, Arc<Vec<u8>>)>>,
+    updated: Condvar,
+    running: AtomicBool,
+    viewers: AtomicUsize,
+    connections: AtomicUsize,
+    config: ServerConfig,
+}
+
+impl Shared {
+    //seqより新しいフレームを待つ(停止したらNone)
+    fn wait_newer(&self, seq: Option<u64>) -> Option<(u64, Arc<Vec<u8>>)> {
+        let mut latest = self.latest.lock().unwrap();
+        loop {
+            if !self.running.load(Ordering::SeqCst) {
+                return None;
+            }
+            if let Some((s, frame)) = latest.as_ref() {
+                if seq.is_none_or(|seq| *s > seq) {
+                    return Some((*s, frame.clone()));
+                }
+            }
+            latest = self.updated.wait_timeout(latest, WAIT_INTERVAL).unwrap().0;
+        }
+    }
+}
+
+/// エンコード結果をHTTPで配信するMJPEGサーバー
+///
+/// - `GET /stream` : `multipart/x-mixed-replace`のMJPEGストリーム
+/// - `GET /snapshot` : 最新の1フレーム
+///
+/// フレームは1回だけエンコードし、すべてのビューアで共有する。
+/// 遅いビューアは途中のフレームを飛ばして最新のフレームを受け取る。
+/// 接続数と読み書きの時間、リクエストの長さは`ServerConfig`で制限する
+pub struct MjpegServer {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    accept_thread: Option<JoinHandle<()>>,
+}
+
+impl MjpegServer {
+    /// 指定したアドレスで待ち受けを開始
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        MjpegServer::bind_with_config(addr, ServerConfig::default())
+    }
+
+    /// 設定を指定して待ち受けを開始
+    pub fn bind_with_config<A: ToSocketAddrs>(addr: A, config: ServerConfig) -> Result<Self> {
+        if config.max_connections == 0 || config.io_timeout.is_zero() || config.max_request_len == 0 {
+            return Err(Error::InvalidConfig(format!("invalid server config {:?}", config)));
+        }
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+
+        let shared = Arc::new(Shared {
+            latest: Mutex::new(None),
+            updated: Condvar::new(),
+            running: AtomicBool::new(true),
+            viewers: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            config,
+        });
+
+        let accept_shared = shared.clone();
+        let accept_thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !accept_shared.running.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::warn!("failed to accept connection: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = set_timeouts(&stream, accept_shared.config.io_timeout) {
+                    log::warn!("failed to set socket timeouts: {}", e);
+                    continue;
+                }
+
+                //接続数が上限なら処理用のスレッドを作らずに断る
+                if accept_shared.connections.fetch_add(1, Ordering::SeqCst) >= accept_shared.config.max_connections {
+                    accept_shared.connections.fetch_sub(1, Ordering::SeqCst);
+                    let mut stream = stream;
+                    let _ = write_status(&mut stream, "503 Service Unavailable", "too many connections\n");
+                    continue;
+                }
+
+                let shared = accept_shared.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &shared) {
+                        log::debug!("client disconnected: {}", e);
+                    }
+                    shared.connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Ok(MjpegServer { shared, addr, accept_thread: Some(accept_thread) })
+    }
+
+    /// 待ち受けているアドレス
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// ストリームを見ているビューアの数
+    pub fn viewers(&self) -> usize {
+        self.shared.viewers.load(Ordering::SeqCst)
+    }
+
+    /// エンコード済みのJPEGを最新フレームとして配信する
+    pub fn publish(&self, jpeg: Vec<u8>) {
+        let mut latest = self.shared.latest.lock().unwrap();
+        let seq = latest.as_ref().map_or(0, |(seq, _)| seq + 1);
+        *latest = Some((seq, Arc::new(jpeg)));
+        self.shared.updated.notify_all();
+    }
+
+    /// フレームの供給元が終わるまで、エンコードして配信し続ける
+    ///
+    /// エンコーダは`config`済みであること
+    pub fn run<E: Encoder, S: FrameSource>(&self, encoder: &mut E, source: &mut S) -> Result<()> {
+        while let Some(img_data) = source.next_frame()? {
+            let jpeg = encoder.encode(&img_data)?;
+            self.publish(jpeg);
+        }
+        Ok(())
+    }
+
+    /// 待ち受けを停止し、ストリームを閉じる
+    pub fn shutdown(&mut self) {
+        if !self.shared.running.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        self.shared.updated.notify_all();
+
+        //acceptで止まっているスレッドを起こす
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.accept_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MjpegServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Duration) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))
+}
+
+fn handle_client(mut stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    //リクエスト行だけを見て、ヘッダは読み捨てる。読む量は上限までにする
+    let limit = shared.config.max_request_len as u64;
+    let mut reader = BufReader::new(stream.try_clone()?.take(limit));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            //上限に達したか、ヘッダの途中で切断された
+            if reader.get_ref().limit() == 0 {
+                return write_status(&mut stream, "431 Request Header Fields Too Large", "request too large\n");
+            }
+            return Ok(());
+        }
+        if line.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or("");
+
+    match (method, path) {
+        ("GET", "/stream") | ("GET", "/") => {
+            shared.viewers.fetch_add(1, Ordering::SeqCst);
+            let result = stream_frames(&mut stream, shared);
+            shared.viewers.fetch_sub(1, Ordering::SeqCst);
+            result
+        }
+        ("GET", "/snapshot") => {
+            let latest = shared.latest.lock().unwrap().as_ref().map(|(_, frame)| frame.clone());
+            match latest {
+                Some(frame) => {
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\
+                         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+                        frame.len()
+                    )?;
+                    stream.write_all(&frame)?;
+                }
+                None => write_status(&mut stream, "503 Service Unavailable", "no frame yet\n")?,
+            }
+            stream.shutdown(Shutdown::Both)
+        }
+        ("GET", _) => write_status(&mut stream, "404 Not Found", "not found\n"),
+        _ => write_status(&mut stream, "405 Method Not Allowed", "method not allowed\n"),
+    }
+}
+
+fn stream_frames(stream: &mut TcpStream, shared: &Shared) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: multipart/x-mixed-replace; boundary={}\r\n\
+         Cache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        BOUNDARY
+    )?;
+
+    let mut seq = None;
+    while let Some((s, frame)) = shared.wait_newer(seq) {
+        seq = Some(s);
+        write!(
+            stream,
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            BOUNDARY,
+            frame.len()
+        )?;
+        stream.write_all(&frame)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+fn write_status(stream: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(addr: SocketAddr, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(request).unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        status
+    }
+
+    #[test]
+    fn snapshot_returns_latest_frame() {
+        let server = MjpegServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+        assert!(request(addr, b"GET /snapshot HTTP/1.1\r\n\r\n").contains("503"));
+        server.publish(vec![0xFF, 0xD8, 0xFF, 0xD9]);
+        assert!(request(addr, b"GET /snapshot HTTP/1.1\r\n\r\n").contains("200"));
+        assert!(request(addr, b"GET /other HTTP/1.1\r\n\r\n").contains("404"));
+    }
+
+    #[test]
+    fn oversized_request_is_rejected() {
+        let config = ServerConfig { max_request_len: 64, ..ServerConfig::default() };
+        let server = MjpegServer::bind_with_config("127.0.0.1:0", config).unwrap();
+        let mut req = b"GET /snapshot HTTP/1.1\r\nX-Long: ".to_vec();
+        req.extend_from_slice(&[b'a'; 100]);
+        req.extend_from_slice(b"\r\n\r\n");
+        assert!(request(server.local_addr(), &req).contains("431"));
+    }
+
+    #[test]
+    fn connections_over_limit_are_refused() {
+        let config = ServerConfig { max_connections: 1, ..ServerConfig::default() };
+        let server = MjpegServer::bind_with_config("127.0.0.1:0", config).unwrap();
+        server.publish(vec![0xFF, 0xD8, 0xFF, 0xD9]);
+
+        //1つ目の接続がストリームを見ている間は2つ目を断る
+        let mut viewer = TcpStream::connect(server.local_addr()).unwrap();
+        viewer.write_all(b"GET /stream HTTP/1.1\r\n\r\n").unwrap();
+        let mut status = String::new();
+        BufReader::new(viewer.try_clone().unwrap()).read_line(&mut status).unwrap();
+        assert!(status.contains("200"));
+
+        assert!(request(server.local_addr(), b"GET /snapshot HTTP/1.1\r\n\r\n").contains("503"));
+        assert_eq!(server.viewers(), 1);
+    }
+}
@@ -0,0 +1,194 @@
+use crate::bitstream::{self, DQT};
+use crate::error::{Error, Result};
+
+// 量子化テーブルとIJG方式の品質スケーリング
+// テーブルはすべて自然順(8x8のラスタ順)で扱い、DQTに書くときだけジグザグ順にする
+
+/// デフォルトの品質
+pub const DEFAULT_QUALITY: u32 = 75;
+
+// 標準輝度量子化テーブル(ITU-T T.81 Annex K, 自然順)
+pub const STD_LUMA_QTABLE: [u8; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61,
+    12, 12, 14, 19, 26, 58, 60, 55,
+    14, 13, 16, 24, 40, 57, 69, 56,
+    14, 17, 22, 29, 51, 87, 80, 62,
+    18, 22, 37, 56, 68, 109, 103, 77,
+    24, 35, 55, 64, 81, 104, 113, 92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+// 標準色差量子化テーブル(自然順)
+pub const STD_CHROMA_QTABLE: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+// ジグザグ順 -> 自然順のインデックス
+pub const ZIGZAG: [usize; 64] = [
+    0, 1, 8, 16, 9, 2, 3, 10,
+    17, 24, 32, 25, 18, 11, 4, 5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13, 6, 7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// IJG方式で品質(1〜100)に応じて量子化テーブルをスケーリング
+pub fn scale_table(base: &[u8; 64], quality: u32) -> [u8; 64] {
+    let quality = quality.clamp(1, 100);
+    let scale = if quality < 50 { 5000 / quality } else { 200 - quality * 2 };
+
+    let mut table = [0u8; 64];
+    for (dst, &src) in table.iter_mut().zip(base.iter()) {
+        *dst = ((src as u32 * scale + 50) / 100).clamp(1, 255) as u8;
+    }
+    table
+}
+
+/// 輝度・色差の量子化テーブル(自然順)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantTables {
+    pub luma: [u8; 64],
+    pub chroma: [u8; 64],
+}
+
+impl QuantTables {
+    /// 品質(1〜100)から標準テーブルをスケーリングして作成
+    pub fn from_quality(quality: u32) -> Self {
+        QuantTables {
+            luma: scale_table(&STD_LUMA_QTABLE, quality),
+            chroma: scale_table(&STD_CHROMA_QTABLE, quality),
+        }
+    }
+
+    /// テーブルIDに対応するテーブル(0: 輝度, 1: 色差)
+    pub fn table(&self, id: u8) -> Option<&[u8; 64]> {
+        match id {
+            0 => Some(&self.luma),
+            1 => Some(&self.chroma),
+            _ => None,
+        }
+    }
+}
+
+impl Default for QuantTables {
+    fn default() -> Self {
+        QuantTables::from_quality(DEFAULT_QUALITY)
+    }
+}
+
+/// 品質が1〜100の範囲か確認する
+pub fn check_quality(quality: u32) -> Result<()> {
+    if !(1..=100).contains(&quality) {
+        return Err(Error::InvalidConfig(format!("quality {} is out of range 1-100", quality)));
+    }
+    Ok(())
+}
+
+/// JPEGのDQTセグメントから8bit精度のテーブルID 0, 1を読み出す(自然順)
+///
+/// 該当するテーブルがなければNone
+pub fn read_dqt(jpeg: &[u8]) -> Result<[Option<[u8; 64]>; 2]> {
+    let info = bitstream::parse(jpeg)?;
+    let mut tables = [None, None];
+
+    for segment in info.find(DQT) {
+        //1つのDQTに複数のテーブルが入っていることがある
+        let payload = &jpeg[segment.payload()];
+        let mut p = 0;
+        while p < payload.len() {
+            let precision = payload[p] >> 4;
+            let id = (payload[p] & 0x0F) as usize;
+            let size = if precision == 0 { 64 } else { 128 };
+            if p + 1 + size > payload.len() {
+                return Err(Error::InvalidBitstream(format!(
+                    "DQT at offset {} is truncated", segment.offset
+                )));
+            }
+            if precision == 0 && id < tables.len() {
+                let mut table = [0u8; 64];
+                for (k, &n) in ZIGZAG.iter().enumerate() {
+                    table[n] = payload[p + 1 + k];
+                }
+                tables[id] = Some(table);
+            }
+            p += 1 + size;
+        }
+    }
+    Ok(tables)
+}
+
+/// 出力のDQTが設定したテーブルと一致するか確認する
+///
+/// ヘッダを固定で出力するコアや、テーブルのレジスタを無視するコアを検出するため、
+/// 出力を書き換えずに食い違っていればエラーにする
+pub fn verify_dqt(jpeg: &[u8], tables: &QuantTables) -> Result<()> {
+    let found = read_dqt(jpeg)?;
+    for (id, expected) in [(0u8, &tables.luma), (1, &tables.chroma)] {
+        match &found[id as usize] {
+            Some(table) if table == expected => {}
+            Some(_) => {
+                return Err(Error::IncompatibleHardware(format!(
+                    "DQT table {} in the output does not match the programmed table", id
+                )))
+            }
+            None => {
+                return Err(Error::IncompatibleHardware(format!("output has no 8-bit DQT table {}", id)))
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soft_jpeg::SoftJpegEncoder;
+
+    fn encode(tables: &QuantTables) -> Vec<u8> {
+        let mut encoder = SoftJpegEncoder::new();
+        encoder.config(16, 16);
+        encoder.set_quant_tables(tables);
+        encoder.encode(&[0x40; 16 * 16 * 3]).unwrap()
+    }
+
+    #[test]
+    fn verify_dqt_accepts_programmed_tables() {
+        let tables = QuantTables::from_quality(90);
+        let jpeg = encode(&tables);
+        assert_eq!(read_dqt(&jpeg).unwrap(), [Some(tables.luma), Some(tables.chroma)]);
+        verify_dqt(&jpeg, &tables).unwrap();
+    }
+
+    #[test]
+    fn verify_dqt_rejects_other_tables() {
+        let jpeg = encode(&QuantTables::from_quality(90));
+        let err = verify_dqt(&jpeg, &QuantTables::from_quality(30)).unwrap_err();
+        assert!(matches!(err, Error::IncompatibleHardware(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn verify_dqt_reports_corrupt_stream_as_bitstream_error() {
+        let tables = QuantTables::default();
+        let jpeg = encode(&tables);
+        let err = verify_dqt(&jpeg[..jpeg.len() - 2], &tables).unwrap_err();
+        assert!(matches!(err, Error::InvalidBitstream(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn check_quality_rejects_out_of_range() {
+        assert!(check_quality(0).is_err());
+        assert!(check_quality(101).is_err());
+        assert!(check_quality(1).is_ok() && check_quality(100).is_ok());
+    }
+}
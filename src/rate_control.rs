@@ -0,0 +1,311 @@
+use crate::encoder::Encoder;
+use crate::error::{Error, Result};
+
+/// レート制御の設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateControlConfig {
+    /// 1フレームの目標サイズ(バイト)
+    pub target_bytes: usize,
+    /// 品質の下限
+    pub min_quality: u32,
+    /// 品質の上限
+    pub max_quality: u32,
+    /// 最初のフレームの品質
+    pub initial_quality: u32,
+    /// 目標との誤差がこの割合以内なら品質を変えない
+    pub tolerance: f64,
+    /// 目標を超えたフレームを品質を下げて再エンコードする最大回数(0で再エンコードしない)
+    pub max_reencodes: u32,
+}
+
+impl RateControlConfig {
+    /// 1フレームの目標サイズから設定を作成
+    pub fn new(target_bytes: usize) -> Self {
+        RateControlConfig {
+            target_bytes,
+            min_quality: 5,
+            max_quality: 95,
+            initial_quality: 75,
+            tolerance: 0.05,
+            max_reencodes: 0,
+        }
+    }
+
+    /// ビットレート(bps)とフレームレートから設定を作成
+    pub fn from_bitrate(bits_per_sec: u64, fps: f64) -> Self {
+        RateControlConfig::new((bits_per_sec as f64 / 8.0 / fps) as usize)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.target_bytes == 0 {
+            return Err(Error::InvalidConfig("target size must be non-zero".to_string()));
+        }
+        if self.min_quality < 1 || self.max_quality > 100 || self.min_quality > self.max_quality {
+            return Err(Error::InvalidConfig(format!(
+                "invalid quality range {}-{}", self.min_quality, self.max_quality
+            )));
+        }
+        if !(0.0..1.0).contains(&self.tolerance) {
+            return Err(Error::InvalidConfig(format!("invalid tolerance {}", self.tolerance)));
+        }
+        Ok(())
+    }
+}
+
+/// 目標サイズに対する誤差の統計
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateStats {
+    /// 出力したフレーム数
+    pub frames: u64,
+    /// 再エンコードした回数
+    pub reencodes: u64,
+    /// 出力サイズの合計(バイト)
+    pub total_bytes: u64,
+    /// 目標を超えたフレーム数
+    pub overshoots: u64,
+    /// 目標との誤差(出力 - 目標)の合計(バイト)
+    pub sum_error: i64,
+    /// 目標との誤差の絶対値の合計(バイト)
+    pub sum_abs_error: u64,
+    /// 目標を最も大きく超えたときの超過量(バイト)
+    pub max_overshoot: usize,
+    /// 最後に使った品質
+    pub last_quality: u32,
+}
+
+impl RateStats {
+    /// 平均誤差(バイト、正なら目標超過)
+    pub fn mean_error(&self) -> f64 {
+        if self.frames == 0 { 0.0 } else { self.sum_error as f64 / self.frames as f64 }
+    }
+
+    /// 平均絶対誤差(バイト)
+    pub fn mean_abs_error(&self) -> f64 {
+        if self.frames == 0 { 0.0 } else { self.sum_abs_error as f64 / self.frames as f64 }
+    }
+
+    /// 平均フレームサイズ(バイト)
+    pub fn mean_bytes(&self) -> f64 {
+        if self.frames == 0 { 0.0 } else { self.total_bytes as f64 / self.frames as f64 }
+    }
+}
+
+/// フレームごとに品質を調整して目標サイズに近づけるレート制御
+pub struct RateController<E: Encoder> {
+    encoder: E,
+    config: RateControlConfig,
+    quality: u32,
+    stats: RateStats,
+}
+
+impl<E: Encoder> RateController<E> {
+    /// 設定済み(`config`済み)のエンコーダからレート制御を作成
+    pub fn new(mut encoder: E, config: RateControlConfig) -> Result<Self> {
+        config.validate()?;
+
+        let quality = config.initial_quality.clamp(config.min_quality, config.max_quality);
+        encoder.set_quality(quality)?;
+
+        Ok(RateController {
+            encoder,
+            config,
+            quality,
+            stats: RateStats { last_quality: quality, ..RateStats::default() },
+        })
+    }
+
+    /// フレームをエンコードし、結果のサイズから次のフレームの品質を決める
+    pub fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>> {
+        let target = self.config.target_bytes;
+        let mut out = self.encoder.encode(img_data)?;
+
+        //目標を超えたら品質を下げて同じフレームを再エンコード
+        let mut reencodes = 0;
+        while out.len() > target && reencodes < self.config.max_reencodes {
+            //許容範囲内の超過でも少なくとも1は下げる
+            let quality = self.next_quality(out.len())
+                .min(self.quality.saturating_sub(1))
+                .max(self.config.min_quality);
+            if quality == self.quality {
+                break;
+            }
+            self.apply_quality(quality)?;
+            out = self.encoder.encode(img_data)?;
+            reencodes += 1;
+        }
+
+        self.record(out.len(), reencodes);
+
+        //次のフレームの品質
+        let quality = self.next_quality(out.len());
+        if quality != self.quality {
+            self.apply_quality(quality)?;
+        }
+
+        Ok(out)
+    }
+
+    /// 現在の品質
+    pub fn quality(&self) -> u32 {
+        self.quality
+    }
+
+    /// 統計
+    pub fn stats(&self) -> &RateStats {
+        &self.stats
+    }
+
+    /// 統計をリセット
+    pub fn reset_stats(&mut self) {
+        self.stats = RateStats { last_quality: self.quality, ..RateStats::default() };
+    }
+
+    /// 目標サイズを変更
+    pub fn set_target_bytes(&mut self, target_bytes: usize) -> Result<()> {
+        let config = RateControlConfig { target_bytes, ..self.config.clone() };
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// 中のエンコーダ
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    /// レート制御を外してエンコーダを取り出す
+    pub fn into_inner(self) -> E {
+        self.encoder
+    }
+
+    fn apply_quality(&mut self, quality: u32) -> Result<()> {
+        self.encoder.set_quality(quality)?;
+        self.quality = quality;
+        Ok(())
+    }
+
+    //出力サイズと目標の比から次の品質を決める
+    fn next_quality(&self, size: usize) -> u32 {
+        let ratio = size as f64 / self.config.target_bytes as f64;
+        if (ratio - 1.0).abs() <= self.config.tolerance {
+            return self.quality;
+        }
+
+        //サイズは品質に対して非線形なので、誤差に応じて段階的に動かす
+        //超過時は大きめ、不足時は小さめに動かして振動を抑える
+        let step = if ratio > 1.0 {
+            -(((ratio - 1.0) * 20.0).ceil().min(20.0) as i32)
+        } else {
+            ((1.0 - ratio) * 10.0).ceil().min(10.0) as i32
+        };
+
+        (self.quality as i32 + step)
+            .clamp(self.config.min_quality as i32, self.config.max_quality as i32) as u32
+    }
+
+    fn record(&mut self, size: usize, reencodes: u32) {
+        let target = self.config.target_bytes;
+        let error = size as i64 - target as i64;
+
+        self.stats.frames += 1;
+        self.stats.reencodes += reencodes as u64;
+        self.stats.total_bytes += size as u64;
+        self.stats.sum_error += error;
+        self.stats.sum_abs_error += error.unsigned_abs();
+        if size > target {
+            self.stats.overshoots += 1;
+            self.stats.max_overshoot = self.stats.max_overshoot.max(size - target);
+        }
+        self.stats.last_quality = self.quality;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::soft_jpeg::SoftJpegEncoder;
+
+    const WIDTH: usize = 64;
+    const HEIGHT: usize = 64;
+
+    //品質でサイズが大きく変わるように細かい模様にする
+    fn image() -> Vec<u8> {
+        (0..WIDTH * HEIGHT * 3).map(|i| ((i * 37) ^ ((i >> 5) * 11)) as u8).collect()
+    }
+
+    fn controller(config: RateControlConfig) -> RateController<SoftJpegEncoder> {
+        let mut encoder = SoftJpegEncoder::new();
+        encoder.config(WIDTH, HEIGHT);
+        RateController::new(encoder, config).unwrap()
+    }
+
+    //初期品質でのサイズ
+    fn initial_size() -> usize {
+        controller(RateControlConfig::new(1)).encoder_mut().encode(&image()).unwrap().len()
+    }
+
+    #[test]
+    fn quality_follows_target() {
+        let size = initial_size();
+
+        let mut over = controller(RateControlConfig::new(size / 2));
+        over.encode(&image()).unwrap();
+        assert!(over.quality() < 75);
+
+        let mut under = controller(RateControlConfig::new(size * 2));
+        under.encode(&image()).unwrap();
+        assert!(under.quality() > 75);
+
+        //許容範囲内なら変えない
+        let mut within = controller(RateControlConfig::new(size));
+        within.encode(&image()).unwrap();
+        assert_eq!(within.quality(), 75);
+        assert_eq!(within.stats().last_quality, 75);
+    }
+
+    #[test]
+    fn reencodes_are_limited() {
+        let config = RateControlConfig { max_reencodes: 3, ..RateControlConfig::new(1) };
+        let mut rc = controller(config);
+        let out = rc.encode(&image()).unwrap();
+        assert!(out.len() > 1);
+        assert_eq!(rc.stats().reencodes, 3);
+        assert_eq!(rc.stats().frames, 1);
+
+        //再エンコードしない設定
+        let mut rc = controller(RateControlConfig::new(1));
+        rc.encode(&image()).unwrap();
+        assert_eq!(rc.stats().reencodes, 0);
+
+        //目標を下回れば再エンコードしない
+        let config = RateControlConfig { max_reencodes: 3, ..RateControlConfig::new(initial_size() * 2) };
+        let mut rc = controller(config);
+        rc.encode(&image()).unwrap();
+        assert_eq!(rc.stats().reencodes, 0);
+    }
+
+    #[test]
+    fn stats_means_match_outputs() {
+        assert_eq!(RateStats::default().mean_error(), 0.0);
+        let stats = RateStats { frames: 4, total_bytes: 4000, sum_error: -20, sum_abs_error: 60, ..RateStats::default() };
+        assert_eq!(stats.mean_error(), -5.0);
+        assert_eq!(stats.mean_abs_error(), 15.0);
+        assert_eq!(stats.mean_bytes(), 1000.0);
+
+        let target = initial_size();
+        let mut rc = controller(RateControlConfig { tolerance: 0.0, ..RateControlConfig::new(target) });
+        let sizes: Vec<i64> = (0..3).map(|_| rc.encode(&image()).unwrap().len() as i64).collect();
+        let errors: Vec<i64> = sizes.iter().map(|&size| size - target as i64).collect();
+
+        let stats = rc.stats();
+        assert_eq!(stats.frames, 3);
+        assert_eq!(stats.mean_error(), errors.iter().sum::<i64>() as f64 / 3.0);
+        assert_eq!(stats.mean_abs_error(), errors.iter().map(|e| e.abs()).sum::<i64>() as f64 / 3.0);
+        assert_eq!(stats.mean_bytes(), sizes.iter().sum::<i64>() as f64 / 3.0);
+        assert_eq!(stats.overshoots, errors.iter().filter(|&&e| e > 0).count() as u64);
+
+        rc.reset_stats();
+        assert_eq!(rc.stats().frames, 0);
+        assert_eq!(rc.stats().last_quality, rc.quality());
+    }
+}
@@ -0,0 +1,669 @@
+use std::f64::consts::PI;
+
+use crate::devlock::LockMode;
+use crate::error::{Error, Result};
+use crate::hwinfo::{param, IpInfo};
+use crate::uio::Uio;
+
+//サブシステム全体のアドレス範囲のデフォルト
+const DEFAULT_ADDR_RANGE: usize = 0x40000;
+
+//フィルタのタップ数の上限(係数がHSC_PHASESに重ならない範囲)
+const MAX_TAPS: u32 = 12;
+//位相の数の上限
+const MAX_PHASES: usize = 64;
+
+// HLSコアの共通レジスタ
+const CTRL: usize = 0x00;
+const CTRL_AP_START: u32 = 1 << 0;
+const CTRL_AUTO_RESTART: u32 = 1 << 7;
+
+// v_hscalerのレジスタ
+const HSC_HEIGHT: usize = 0x10;
+const HSC_WIDTH_IN: usize = 0x18;
+const HSC_WIDTH_OUT: usize = 0x20;
+const HSC_COLOR_MODE: usize = 0x28;
+const HSC_PIXEL_RATE: usize = 0x30;
+const HSC_COLOR_MODE_OUT: usize = 0x38;
+const HSC_COEFF: usize = 0x800;
+const HSC_PHASES: usize = 0x2000;
+
+// v_vscalerのレジスタ
+const VSC_HEIGHT_IN: usize = 0x10;
+const VSC_WIDTH: usize = 0x18;
+const VSC_HEIGHT_OUT: usize = 0x20;
+const VSC_LINE_RATE: usize = 0x28;
+const VSC_COLOR_MODE: usize = 0x30;
+const VSC_COEFF: usize = 0x800;
+
+// v_cscのレジスタ
+const CSC_IN_FORMAT: usize = 0x10;
+const CSC_OUT_FORMAT: usize = 0x18;
+const CSC_WIDTH: usize = 0x20;
+const CSC_HEIGHT: usize = 0x28;
+const CSC_COL_START: usize = 0x30;
+const CSC_COL_END: usize = 0x38;
+const CSC_ROW_START: usize = 0x40;
+const CSC_ROW_END: usize = 0x48;
+const CSC_K11: usize = 0x50;
+const CSC_ROFFSET: usize = 0x98;
+const CSC_CLAMP_MIN: usize = 0xb0;
+const CSC_CLIP_MAX: usize = 0xb8;
+
+//リセットのレジスタ
+const RESET_REG_LEN: usize = 4;
+
+// リセット用GPIOのビット(ビデオIO, AXI Stream上のコア)
+const RESET_RELEASE_ALL: u32 = 0x03;
+
+//拡大縮小率の固定小数点の精度
+const STEP_PRECISION_SHIFT: u32 = 16;
+//フィルタ係数の固定小数点の精度
+const COEFF_PRECISION_SHIFT: u32 = 12;
+//CSCの行列の固定小数点の精度
+const CSC_PRECISION_SHIFT: u32 = 12;
+
+/// 色空間(v_hscaler/v_vscaler/v_cscのカラーモードの値)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    #[default]
+    Rgb = 0,
+    YCbCr444 = 1,
+    YCbCr422 = 2,
+    YCbCr420 = 3,
+}
+
+/// スケーラのフィルタ(`SCALE_MODE`パラメータ)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScaleMode {
+    Bilinear,
+    Bicubic,
+    Polyphase,
+}
+
+/// 入出力の解像度と色空間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScalerConfig {
+    pub in_width: usize,
+    pub in_height: usize,
+    pub out_width: usize,
+    pub out_height: usize,
+    /// フレームバッファから入力される色空間(拡大縮小はこの色空間で行う)
+    pub in_color: ColorSpace,
+    /// エンコーダへ出力する色空間(`in_color`と異なる場合はCSCが必要)
+    pub out_color: ColorSpace,
+}
+
+impl ScalerConfig {
+    /// 解像度も色空間も変えない設定
+    pub fn passthrough(width: usize, height: usize) -> Self {
+        ScalerConfig::new(width, height, width, height)
+    }
+
+    /// RGBのまま解像度を変える設定
+    pub fn new(in_width: usize, in_height: usize, out_width: usize, out_height: usize) -> Self {
+        ScalerConfig {
+            in_width,
+            in_height,
+            out_width,
+            out_height,
+            in_color: ColorSpace::Rgb,
+            out_color: ColorSpace::Rgb,
+        }
+    }
+
+    fn is_scaled(&self) -> bool {
+        (self.in_width, self.in_height) != (self.out_width, self.out_height)
+    }
+}
+
+/// Video Processing Subsystem(スケーラ/CSC)
+///
+/// サブシステム全体を1つのUIOとしてマップし、hwinfoのパラメータで
+/// 各コア(`HSC_OFFSET`, `VSC_OFFSET`, `CSC_OFFSET`, `RESET_OFFSET`)のオフセットを指定する
+pub struct Scaler {
+    pub uio: Uio,
+    reset: Option<usize>,
+    hsc: Option<usize>,
+    vsc: Option<usize>,
+    csc: Option<usize>,
+    samples_per_clock: usize,
+    scale_mode: ScaleMode,
+    h_taps: usize,
+    v_taps: usize,
+    phases: usize,
+    max_width: Option<usize>,
+    max_height: Option<usize>,
+    addr_range: usize,
+    config: Option<ScalerConfig>,
+}
+
+impl Scaler {
+    pub fn new(hw_info: &serde_json::Value) -> Result<Self> {
+        Scaler::new_with_lock(hw_info, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    pub fn new_with_lock(hw_info: &serde_json::Value, lock_mode: LockMode) -> Result<Self> {
+        //ハードウェア情報を検証して取得
+        let info = IpInfo::parse_entry(hw_info, false)?;
+        Scaler::from_info(&info, lock_mode)
+    }
+
+    /// 検証済みのハードウェア情報からオープン
+    ///
+    /// 各コアのオフセットはそのコアのレジスタがすべて`ADDR_RANGE`に収まるかを確認する
+    pub fn from_info(info: &IpInfo, lock_mode: LockMode) -> Result<Self> {
+        let offset = |key: &str| info.param_u32(key).map(|v| v as usize);
+        let (hsc, vsc, csc) = (offset(param::HSC_OFFSET), offset(param::VSC_OFFSET), offset(param::CSC_OFFSET));
+        if hsc.is_none() && vsc.is_none() && csc.is_none() {
+            return Err(Error::InvalidConfig(format!(
+                "{} has none of {}, {}, {}", info.uio, param::HSC_OFFSET, param::VSC_OFFSET, param::CSC_OFFSET
+            )));
+        }
+        if hsc.is_some() != vsc.is_some() {
+            return Err(Error::InvalidConfig(format!(
+                "{} needs both {} and {}", info.uio, param::HSC_OFFSET, param::VSC_OFFSET
+            )));
+        }
+
+        let scale_mode = match info.param_u32(param::SCALE_MODE).unwrap_or(2) {
+            0 => ScaleMode::Bilinear,
+            1 => ScaleMode::Bicubic,
+            2 => ScaleMode::Polyphase,
+            mode => return Err(Error::InvalidConfig(format!("unknown SCALE_MODE {}", mode))),
+        };
+        let phases = info.param_u32(param::PHASES).unwrap_or(64) as usize;
+        if !phases.is_power_of_two() || phases > MAX_PHASES {
+            return Err(Error::InvalidConfig(format!("PHASES {} must be a power of two up to {}", phases, MAX_PHASES)));
+        }
+        let samples_per_clock = info.param_u32(param::SAMPLES_PER_CLOCK).unwrap_or(1).max(1) as usize;
+        if ![1, 2, 4].contains(&samples_per_clock) {
+            return Err(Error::InvalidConfig(format!("SAMPLES_PER_CLOCK {} is not supported", samples_per_clock)));
+        }
+
+        let (h_taps, v_taps) = (info.param_u32(param::H_SCALER_TAPS).unwrap_or(6), info.param_u32(param::V_SCALER_TAPS).unwrap_or(6));
+        if [h_taps, v_taps].iter().any(|&taps| taps < 2 || !taps.is_multiple_of(2) || taps > MAX_TAPS) {
+            return Err(Error::InvalidConfig(format!(
+                "scaler taps {}/{} must be even and at most {}", h_taps, v_taps, MAX_TAPS
+            )));
+        }
+        let (h_taps, v_taps) = (h_taps as usize, v_taps as usize);
+
+        //各コアのレジスタがアドレス範囲に収まるか
+        let addr_range = info.param_u32(param::ADDR_RANGE).map_or(DEFAULT_ADDR_RANGE, |v| v as usize);
+        let max_width = info.max_width().map(|v| v as usize);
+        let hsc_len = HSC_PHASES + phase_table_len(max_width.unwrap_or(1), samples_per_clock);
+        let blocks = [
+            (param::RESET_OFFSET, RESET_REG_LEN),
+            (param::HSC_OFFSET, hsc_len),
+            (param::VSC_OFFSET, coeff_end(VSC_COEFF, v_taps, phases)),
+            (param::CSC_OFFSET, CSC_CLIP_MAX + 4),
+        ];
+        for (key, len) in blocks {
+            check_block(key, offset(key), len, addr_range)?;
+        }
+
+        let uio = Uio::new_with_lock(&info.uio, addr_range, lock_mode)?;
+
+        Ok(Scaler {
+            uio,
+            reset: offset(param::RESET_OFFSET),
+            hsc,
+            vsc,
+            csc,
+            samples_per_clock,
+            scale_mode,
+            h_taps,
+            v_taps,
+            phases,
+            max_width,
+            max_height: info.max_height().map(|v| v as usize),
+            addr_range,
+            config: None,
+        })
+    }
+
+    /// メモリをクローズ
+    pub fn close(&self) {
+        self.uio.close();
+    }
+
+    /// 拡大縮小ができるかどうか
+    pub fn has_scaler(&self) -> bool {
+        self.hsc.is_some()
+    }
+
+    /// 色空間の変換ができるかどうか
+    pub fn has_csc(&self) -> bool {
+        self.csc.is_some()
+    }
+
+    /// 設定した入出力(未設定ならNone)
+    pub fn config(&self) -> Option<&ScalerConfig> {
+        self.config.as_ref()
+    }
+
+    /// サブシステム内のコアをリセットする
+    pub fn reset(&mut self) {
+        if let Some(reset) = self.reset {
+            self.uio.write_mem32(reset, 0);
+            self.uio.write_mem32(reset, RESET_RELEASE_ALL);
+        }
+        self.config = None;
+    }
+
+    /// 入出力の解像度と色空間を設定する
+    ///
+    /// 設定の前に各コアを停止する。`start`で動作を開始する
+    pub fn configure(&mut self, config: &ScalerConfig) -> Result<()> {
+        self.check(config)?;
+        self.stop();
+
+        //縦、横の順に拡大縮小する
+        if let (Some(vsc), Some(hsc)) = (self.vsc, self.hsc) {
+            self.configure_vscaler(vsc, config);
+            self.configure_hscaler(hsc, config);
+        }
+        if let Some(csc) = self.csc {
+            self.configure_csc(csc, config);
+        }
+
+        self.config = Some(*config);
+        Ok(())
+    }
+
+    fn check(&self, config: &ScalerConfig) -> Result<()> {
+        let sizes = [config.in_width, config.in_height, config.out_width, config.out_height];
+        if sizes.contains(&0) {
+            return Err(Error::InvalidConfig(format!("invalid scaler size {:?}", config)));
+        }
+        if let Some(max_width) = self.max_width {
+            let width = config.in_width.max(config.out_width);
+            if width > max_width {
+                return Err(Error::SizeExceeded { what: "scaler width", size: width, limit: max_width });
+            }
+        }
+        if let Some(max_height) = self.max_height {
+            let height = config.in_height.max(config.out_height);
+            if height > max_height {
+                return Err(Error::SizeExceeded { what: "scaler height", size: height, limit: max_height });
+            }
+        }
+        if config.is_scaled() && !self.has_scaler() {
+            return Err(Error::InvalidConfig("the video processing subsystem has no scaler".to_string()));
+        }
+        //v_hscalerは4:2:0を扱えない
+        if self.has_scaler() && config.in_color == ColorSpace::YCbCr420 {
+            return Err(Error::InvalidConfig("the horizontal scaler does not support YCbCr 4:2:0 input".to_string()));
+        }
+        if config.in_color != config.out_color && !self.has_csc() {
+            return Err(Error::InvalidConfig(format!(
+                "converting {:?} to {:?} needs a CSC", config.in_color, config.out_color
+            )));
+        }
+        let ppc = self.samples_per_clock;
+        if !config.in_width.is_multiple_of(ppc) || !config.out_width.is_multiple_of(ppc) {
+            return Err(Error::InvalidConfig(format!(
+                "scaler widths {} and {} must be multiples of {} pixels per clock", config.in_width, config.out_width, ppc
+            )));
+        }
+        //位相テーブルは幅で大きさが変わるので、ここでアドレス範囲を確認する
+        if self.hsc.is_some() {
+            let width = config.in_width.max(config.out_width);
+            check_block(param::HSC_OFFSET, self.hsc, HSC_PHASES + phase_table_len(width, ppc), self.addr_range)?;
+        }
+        Ok(())
+    }
+
+    fn configure_vscaler(&self, base: usize, config: &ScalerConfig) {
+        let line_rate = ((config.in_height as u64) << STEP_PRECISION_SHIFT) / config.out_height as u64;
+
+        self.uio.write_mem32(base + VSC_HEIGHT_IN, config.in_height as u32);
+        self.uio.write_mem32(base + VSC_WIDTH, config.in_width as u32);
+        self.uio.write_mem32(base + VSC_HEIGHT_OUT, config.out_height as u32);
+        self.uio.write_mem32(base + VSC_LINE_RATE, line_rate as u32);
+        self.uio.write_mem32(base + VSC_COLOR_MODE, config.in_color as u32);
+
+        if self.scale_mode == ScaleMode::Polyphase {
+            let coeffs = filter_coeffs(self.v_taps, self.phases, config.in_height, config.out_height);
+            self.write_coeffs(base + VSC_COEFF, &coeffs);
+        }
+    }
+
+    fn configure_hscaler(&self, base: usize, config: &ScalerConfig) {
+        let pixel_rate = ((config.in_width as u64) << STEP_PRECISION_SHIFT) / config.out_width as u64;
+
+        self.uio.write_mem32(base + HSC_HEIGHT, config.out_height as u32);
+        self.uio.write_mem32(base + HSC_WIDTH_IN, config.in_width as u32);
+        self.uio.write_mem32(base + HSC_WIDTH_OUT, config.out_width as u32);
+        self.uio.write_mem32(base + HSC_COLOR_MODE, config.in_color as u32);
+        self.uio.write_mem32(base + HSC_PIXEL_RATE, pixel_rate as u32);
+        self.uio.write_mem32(base + HSC_COLOR_MODE_OUT, config.in_color as u32);
+
+        if self.scale_mode == ScaleMode::Polyphase {
+            let coeffs = filter_coeffs(self.h_taps, self.phases, config.in_width, config.out_width);
+            self.write_coeffs(base + HSC_COEFF, &coeffs);
+        }
+
+        //出力画素ごとの位相と読み出し位置
+        let phases = horizontal_phases(
+            config.in_width, config.out_width, pixel_rate, self.samples_per_clock, self.phases.trailing_zeros(),
+        );
+        if self.samples_per_clock == 4 {
+            for (i, &phase) in phases.iter().enumerate() {
+                self.uio.write_mem32(base + HSC_PHASES + i * 8, phase as u32);
+                self.uio.write_mem32(base + HSC_PHASES + i * 8 + 4, (phase >> 32) as u32);
+            }
+        } else {
+            for (i, pair) in phases.chunks(2).enumerate() {
+                let hi = pair.get(1).copied().unwrap_or(0);
+                self.uio.write_mem32(base + HSC_PHASES + i * 4, ((hi << 16) | (pair[0] & 0xffff)) as u32);
+            }
+        }
+    }
+
+    fn configure_csc(&self, base: usize, config: &ScalerConfig) {
+        let (width, height) = (config.out_width, config.out_height);
+        self.uio.write_mem32(base + CSC_IN_FORMAT, config.in_color as u32);
+        self.uio.write_mem32(base + CSC_OUT_FORMAT, config.out_color as u32);
+        self.uio.write_mem32(base + CSC_WIDTH, width as u32);
+        self.uio.write_mem32(base + CSC_HEIGHT, height as u32);
+        self.uio.write_mem32(base + CSC_COL_START, 0);
+        self.uio.write_mem32(base + CSC_COL_END, width as u32 - 1);
+        self.uio.write_mem32(base + CSC_ROW_START, 0);
+        self.uio.write_mem32(base + CSC_ROW_END, height as u32 - 1);
+
+        let (matrix, offsets) = csc_matrix(config.in_color, config.out_color);
+        for (i, &k) in matrix.iter().enumerate() {
+            self.uio.write_mem32(base + CSC_K11 + i * 8, k as u32);
+        }
+        for (i, &offset) in offsets.iter().enumerate() {
+            self.uio.write_mem32(base + CSC_ROFFSET + i * 8, offset as u32);
+        }
+        self.uio.write_mem32(base + CSC_CLAMP_MIN, 0);
+        self.uio.write_mem32(base + CSC_CLIP_MAX, 255);
+    }
+
+    //2つの係数を1ワードにまとめて書き込む
+    fn write_coeffs(&self, base: usize, coeffs: &[i16]) {
+        for (i, pair) in coeffs.chunks(2).enumerate() {
+            let val = ((pair[1] as u16 as u32) << 16) | pair[0] as u16 as u32;
+            self.uio.write_mem32(base + i * 4, val);
+        }
+    }
+
+    //存在するコアのオフセット
+    fn cores(&self) -> impl Iterator<Item = usize> {
+        [self.vsc, self.hsc, self.csc].into_iter().flatten()
+    }
+
+    /// 各コアを自動再スタートで開始(フレームごとに処理を続ける)
+    pub fn start(&self) -> Result<()> {
+        if self.config.is_none() {
+            return Err(Error::InvalidConfig("scaler is not configured".to_string()));
+        }
+        //出力側から開始する
+        for base in self.cores().collect::<Vec<_>>().into_iter().rev() {
+            self.uio.write_mem32(base + CTRL, CTRL_AUTO_RESTART | CTRL_AP_START);
+        }
+        Ok(())
+    }
+
+    /// 各コアを停止
+    pub fn stop(&self) {
+        for base in self.cores() {
+            self.uio.write_mem32(base + CTRL, 0);
+        }
+    }
+}
+
+//コアのオフセットが4バイト境界で、使うレジスタ(`len`バイト)がアドレス範囲に収まるか
+fn check_block(key: &str, offset: Option<usize>, len: usize, addr_range: usize) -> Result<()> {
+    match offset {
+        Some(offset) if !offset.is_multiple_of(4) || offset.checked_add(len).is_none_or(|end| end > addr_range) => {
+            Err(Error::InvalidConfig(format!(
+                "{} {:#x} with {:#x} bytes of registers does not fit in the address range {:#x}",
+                key, offset, len, addr_range
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+//係数のブロックの終わり(2つの係数を1ワードにまとめる)
+fn coeff_end(base: usize, taps: usize, phases: usize) -> usize {
+    base + taps * phases * 2
+}
+
+//v_hscalerの位相テーブルのバイト数
+fn phase_table_len(width: usize, ppc: usize) -> usize {
+    let words = width.div_ceil(ppc);
+    if ppc == 4 { words * 8 } else { words.div_ceil(2) * 4 }
+}
+
+//ウィンドウ付きsinc(Lanczos)のフィルタ係数を位相ごとに作る
+//
+//縮小時は帯域を出力に合わせて狭める。各位相の係数の和は1(12bit固定小数点)
+fn filter_coeffs(taps: usize, phases: usize, size_in: usize, size_out: usize) -> Vec<i16> {
+    let cutoff = (size_out as f64 / size_in as f64).min(1.0);
+    let half = taps as f64 / 2.0;
+    let one = 1i32 << COEFF_PRECISION_SHIFT;
+
+    let sinc = |x: f64| if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+    let mut out = Vec::with_capacity(taps * phases);
+    for phase in 0..phases {
+        let frac = phase as f64 / phases as f64;
+        let weights: Vec<f64> = (0..taps)
+            .map(|t| {
+                let x = t as f64 - (half - 1.0) - frac;
+                if x.abs() >= half { 0.0 } else { sinc(x * cutoff) * sinc(x / half) }
+            })
+            .collect();
+        let sum: f64 = weights.iter().sum();
+
+        //丸め誤差は中央のタップで吸収する
+        let mut coeffs: Vec<i32> = weights.iter().map(|w| (w / sum * one as f64).round() as i32).collect();
+        let err = one - coeffs.iter().sum::<i32>();
+        coeffs[taps / 2 - 1] += err;
+        out.extend(coeffs.iter().map(|&c| c as i16));
+    }
+    out
+}
+
+//v_hscalerの位相テーブル
+//
+//1クロック分の各画素について、位相・読み出し位置・出力有効をビットにまとめる
+fn horizontal_phases(width_in: usize, width_out: usize, pixel_rate: u64, ppc: usize, phase_shift: u32) -> Vec<u64> {
+    let loop_width = width_in.max(width_out).div_ceil(ppc);
+    let max_phases = 1u64 << phase_shift;
+    //1画素あたりのビット数と各フィールドの位置
+    let (bits, write_en_bit) = if ppc == 4 { (11, 10) } else { (10, 9) };
+
+    let mut phases = Vec::with_capacity(loop_width);
+    let mut offset = 0u64;
+    let mut x_write = 0;
+    let mut array_idx = 0u64;
+    for _ in 0..loop_width {
+        let mut word = 0u64;
+        for s in 0..ppc {
+            let phase = (offset >> (STEP_PRECISION_SHIFT - phase_shift)) & (max_phases - 1);
+            let mut write_en = 0;
+            if offset >> STEP_PRECISION_SHIFT != 0 {
+                //入力画素を読み進める
+                offset -= 1 << STEP_PRECISION_SHIFT;
+                array_idx += 1;
+            }
+            if offset >> STEP_PRECISION_SHIFT == 0 && x_write < width_out {
+                //出力画素を作る
+                offset += pixel_rate;
+                write_en = 1;
+                x_write += 1;
+            }
+            let shift = s * bits;
+            word |= phase << shift;
+            word |= array_idx << (shift + 6);
+            word |= write_en << (shift + write_en_bit);
+        }
+        array_idx &= ppc as u64 - 1;
+        phases.push(word);
+    }
+    phases
+}
+
+//色空間の変換行列(K11〜K33)とオフセット(R, G, B)
+//
+//YCbCr同士(サブサンプリングのみ異なる)とRGB同士は単位行列にする
+fn csc_matrix(from: ColorSpace, to: ColorSpace) -> ([i32; 9], [i32; 3]) {
+    let fixed = |v: f64| (v * (1 << CSC_PRECISION_SHIFT) as f64).round() as i32;
+    let is_rgb = |c: ColorSpace| c == ColorSpace::Rgb;
+
+    match (is_rgb(from), is_rgb(to)) {
+        //BT.601 YCbCr → RGB(リミテッドレンジ)
+        (false, true) => (
+            [
+                fixed(1.164), 0, fixed(1.596),
+                fixed(1.164), fixed(-0.392), fixed(-0.813),
+                fixed(1.164), fixed(2.017), 0,
+            ],
+            [
+                (-16.0 * 1.164 - 128.0 * 1.596) as i32,
+                (-16.0 * 1.164 + 128.0 * 0.392 + 128.0 * 0.813) as i32,
+                (-16.0 * 1.164 - 128.0 * 2.017) as i32,
+            ],
+        ),
+        //RGB → BT.601 YCbCr(リミテッドレンジ)
+        (true, false) => (
+            [
+                fixed(0.257), fixed(0.504), fixed(0.098),
+                fixed(-0.148), fixed(-0.291), fixed(0.439),
+                fixed(0.439), fixed(-0.368), fixed(-0.071),
+            ],
+            [16, 128, 128],
+        ),
+        _ => ([fixed(1.0), 0, 0, 0, fixed(1.0), 0, 0, 0, fixed(1.0)], [0, 0, 0]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //出力有効のビットが立っている画素の数
+    fn write_count(phases: &[u64], ppc: usize) -> usize {
+        let (bits, write_en_bit) = if ppc == 4 { (11, 10) } else { (10, 9) };
+        phases
+            .iter()
+            .map(|word| (0..ppc).filter(|s| word >> (s * bits + write_en_bit) & 1 == 1).count())
+            .sum()
+    }
+
+    #[test]
+    fn filter_coeffs_sum_to_one_per_phase() {
+        for (taps, size_in, size_out) in [(6, 1920, 640), (6, 640, 1920), (8, 1000, 999), (2, 64, 64)] {
+            let coeffs = filter_coeffs(taps, 64, size_in, size_out);
+            assert_eq!(coeffs.len(), taps * 64);
+            for phase in coeffs.chunks(taps) {
+                assert_eq!(phase.iter().map(|&c| c as i32).sum::<i32>(), 1 << COEFF_PRECISION_SHIFT);
+            }
+        }
+    }
+
+    #[test]
+    fn horizontal_phases_write_every_output_pixel() {
+        for ppc in [1, 2, 4] {
+            for (width_in, width_out) in [(1920, 640), (640, 1920), (1280, 1280), (800, 600)] {
+                let rate = ((width_in as u64) << STEP_PRECISION_SHIFT) / width_out as u64;
+                let phases = horizontal_phases(width_in, width_out, rate, ppc, 6);
+                assert_eq!(phases.len(), width_in.max(width_out).div_ceil(ppc));
+                assert_eq!(write_count(&phases, ppc), width_out, "{}->{} at {} ppc", width_in, width_out, ppc);
+            }
+        }
+    }
+
+    //行列を1画素に適用する
+    fn apply(matrix: &[i32; 9], offsets: &[i32; 3], pixel: [i32; 3]) -> [i32; 3] {
+        let mut out = [0; 3];
+        for (i, o) in out.iter_mut().enumerate() {
+            let sum: i32 = (0..3).map(|j| matrix[i * 3 + j] * pixel[j]).sum();
+            *o = ((sum + (1 << (CSC_PRECISION_SHIFT - 1))) >> CSC_PRECISION_SHIFT) + offsets[i];
+        }
+        out
+    }
+
+    #[test]
+    fn csc_identity_keeps_values() {
+        for (from, to) in [(ColorSpace::Rgb, ColorSpace::Rgb), (ColorSpace::YCbCr444, ColorSpace::YCbCr422)] {
+            let (matrix, offsets) = csc_matrix(from, to);
+            for pixel in [[0, 0, 0], [255, 128, 1], [17, 200, 255]] {
+                assert_eq!(apply(&matrix, &offsets, pixel), pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn csc_round_trip_is_close() {
+        let (to_ycc, to_ycc_offsets) = csc_matrix(ColorSpace::Rgb, ColorSpace::YCbCr444);
+        let (to_rgb, to_rgb_offsets) = csc_matrix(ColorSpace::YCbCr444, ColorSpace::Rgb);
+        assert_eq!(apply(&to_ycc, &to_ycc_offsets, [0, 0, 0]), [16, 128, 128]);
+        for pixel in [[16, 16, 16], [235, 235, 235], [200, 50, 100]] {
+            let back = apply(&to_rgb, &to_rgb_offsets, apply(&to_ycc, &to_ycc_offsets, pixel));
+            for (a, b) in back.iter().zip(pixel.iter()) {
+                assert!((a - b).abs() <= 2, "{:?} -> {:?}", pixel, back);
+            }
+        }
+    }
+
+    fn open(params: serde_json::Value) -> Result<Scaler> {
+        let info = IpInfo::parse_entry(&serde_json::json!({ "uio": "vpss", "params": params }), false).unwrap();
+        Scaler::from_info(&info, LockMode::NoWait)
+    }
+
+    fn is_range_error(result: Result<Scaler>) -> bool {
+        matches!(result, Err(Error::InvalidConfig(msg)) if msg.contains("address range"))
+    }
+
+    #[test]
+    fn offsets_must_fit_in_address_range() {
+        let cores = serde_json::json!({ "HSC_OFFSET": 0x0, "VSC_OFFSET": 0x10000, "CSC_OFFSET": 0x20000, "RESET_OFFSET": 0x30000 });
+        //範囲内ならデバイスのオープンまで進む
+        assert!(!matches!(open(cores.clone()), Err(Error::InvalidConfig(_))));
+
+        let mut outside = cores.clone();
+        outside["CSC_OFFSET"] = serde_json::json!(DEFAULT_ADDR_RANGE - 0x40);
+        assert!(is_range_error(open(outside)));
+
+        let mut unaligned = cores.clone();
+        unaligned["RESET_OFFSET"] = serde_json::json!(0x30002);
+        assert!(is_range_error(open(unaligned)));
+
+        //位相テーブルが範囲を超える
+        let mut small = cores.clone();
+        small["ADDR_RANGE"] = serde_json::json!(0x40000);
+        small["HSC_OFFSET"] = serde_json::json!(0x3e000);
+        small["MAX_COLS"] = serde_json::json!(4096);
+        assert!(is_range_error(open(small)));
+
+        let mut huge = cores;
+        huge["VSC_OFFSET"] = serde_json::json!(u32::MAX - 3);
+        assert!(is_range_error(open(huge)));
+    }
+
+    #[test]
+    fn taps_are_capped() {
+        assert!(coeff_end(HSC_COEFF, MAX_TAPS as usize, MAX_PHASES) <= HSC_PHASES);
+        let params = serde_json::json!({ "HSC_OFFSET": 0x0, "VSC_OFFSET": 0x10000, "H_SCALER_TAPS": 14 });
+        assert!(matches!(open(params), Err(Error::InvalidConfig(msg)) if msg.contains("taps")));
+    }
+
+    #[test]
+    fn phase_table_len_matches_writes() {
+        for ppc in [1, 2, 4] {
+            let (width_in, width_out) = (1920, 1280);
+            let pixel_rate = ((width_in as u64) << STEP_PRECISION_SHIFT) / width_out as u64;
+            let phases = horizontal_phases(width_in, width_out, pixel_rate, ppc, 6);
+            let bytes = if ppc == 4 { phases.len() * 8 } else { phases.len().div_ceil(2) * 4 };
+            assert_eq!(phase_table_len(width_in, ppc), bytes);
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use crate::error::{Error, Result};
+use crate::quant::{self, QuantTables, DEFAULT_QUALITY, ZIGZAG};
+
+// ベースラインJPEG(4:2:0)のソフトウェアエンコーダ
+// ハードウェアが使えない環境でのフォールバック用
+
+// 標準ハフマンテーブル(ITU-T T.81 Annex K.3)
+pub(crate) const DC_LUMA_BITS: [u8; 16] = [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0];
+pub(crate) const DC_LUMA_VALS: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+pub(crate) const DC_CHROMA_BITS: [u8; 16] = [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0];
+pub(crate) const DC_CHROMA_VALS: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+
+pub(crate) const AC_LUMA_BITS: [u8; 16] = [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d];
+pub(crate) const AC_LUMA_VALS: [u8; 162] = [
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07,
+    0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42, 0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0,
+    0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28,
+    0x29, 0x2a, 0x34, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49,
+    0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68, 0x69,
+    0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89,
+    0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7,
+    0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2,
+    0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+pub(crate) const AC_CHROMA_BITS: [u8; 16] = [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77];
+pub(crate) const AC_CHROMA_VALS: [u8; 162] = [
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71,
+    0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1, 0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0,
+    0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26,
+    0x27, 0x28, 0x29, 0x2a, 0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48,
+    0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67, 0x68,
+    0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87,
+    0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5,
+    0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3,
+    0xc4, 0xc5, 0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda,
+    0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8,
+    0xf9, 0xfa,
+];
+
+// ハフマン符号表(シンボル -> (符号, 符号長))
+pub(crate) struct HuffTable {
+    codes: [(u16, u8); 256],
+}
+
+impl HuffTable {
+    pub(crate) fn new(bits: &[u8; 16], vals: &[u8]) -> Self {
+        let mut codes = [(0u16, 0u8); 256];
+        let mut code = 0u16;
+        let mut k = 0;
+        for (i, &n) in bits.iter().enumerate() {
+            for _ in 0..n {
+                codes[vals[k] as usize] = (code, (i + 1) as u8);
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        HuffTable { codes }
+    }
+}
+
+// バイトスタッフィング付きのビット書き込み
+pub(crate) struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    pub(crate) fn new(out: Vec<u8>) -> Self {
+        BitWriter { out, acc: 0, nbits: 0 }
+    }
+
+    fn put(&mut self, code: u16, len: u8) {
+        self.acc = (self.acc << len) | (code as u32 & ((1 << len) - 1));
+        self.nbits += len as u32;
+        while self.nbits >= 8 {
+            let byte = (self.acc >> (self.nbits - 8)) as u8;
+            self.out.push(byte);
+            if byte == 0xFF {
+                self.out.push(0x00);
+            }
+            self.nbits -= 8;
+        }
+        self.acc &= (1 << self.nbits) - 1;
+    }
+
+    //残りのビットを1で埋める
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            let pad = 8 - self.nbits as u8;
+            self.put((1 << pad) - 1, pad);
+        }
+    }
+
+    //バイト境界にそろえてRSTnマーカーを出力
+    pub(crate) fn restart(&mut self, n: u8) {
+        self.align();
+        self.out.extend_from_slice(&[0xFF, 0xD0 + (n & 7)]);
+    }
+
+    //残りのビットを1で埋めて出力
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.align();
+        self.out
+    }
+}
+
+// 値のビット数(JPEGのカテゴリ)
+fn category(v: i32) -> u8 {
+    (32 - v.unsigned_abs().leading_zeros()) as u8
+}
+
+// カテゴリ内での値の表現
+fn magnitude_bits(v: i32, cat: u8) -> u16 {
+    if v < 0 {
+        (v - 1) as u16 & ((1u32 << cat) - 1) as u16
+    } else {
+        v as u16
+    }
+}
+
+// DCTの係数表
+fn dct_table() -> [[f32; 8]; 8] {
+    let mut cos_table = [[0f32; 8]; 8];
+    for (u, row) in cos_table.iter_mut().enumerate() {
+        let cu = if u == 0 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+        for (x, c) in row.iter_mut().enumerate() {
+            *c = 0.5 * cu * (((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI) / 16.0).cos();
+        }
+    }
+    cos_table
+}
+
+// 8x8の順方向DCT(浮動小数点, 分離型)
+fn fdct(cos_table: &[[f32; 8]; 8], block: &[f32; 64]) -> [f32; 64] {
+    let mut tmp = [0f32; 64];
+    for y in 0..8 {
+        for u in 0..8 {
+            tmp[y * 8 + u] = (0..8).map(|x| cos_table[u][x] * block[y * 8 + x]).sum();
+        }
+    }
+
+    let mut out = [0f32; 64];
+    for u in 0..8 {
+        for v in 0..8 {
+            out[v * 8 + u] = (0..8).map(|y| cos_table[v][y] * tmp[y * 8 + u]).sum();
+        }
+    }
+    out
+}
+
+pub struct SoftJpegEncoder {
+    width: usize,
+    height: usize,
+    qtables: QuantTables,
+}
+
+impl Default for SoftJpegEncoder {
+    fn default() -> Self {
+        SoftJpegEncoder::new()
+    }
+}
+
+impl SoftJpegEncoder {
+    pub fn new() -> Self {
+        SoftJpegEncoder {
+            width: 0,
+            height: 0,
+            qtables: QuantTables::from_quality(DEFAULT_QUALITY),
+        }
+    }
+
+    /// 画像サイズを設定
+    pub fn config(&mut self, frame_width: usize, frame_height: usize) {
+        self.width = frame_width;
+        self.height = frame_height;
+    }
+
+    /// 品質(1〜100)を設定
+    ///
+    /// 範囲外ならエラー
+    pub fn set_quality(&mut self, quality: u32) -> Result<()> {
+        quant::check_quality(quality)?;
+        self.qtables = QuantTables::from_quality(quality);
+        Ok(())
+    }
+
+    /// 量子化テーブルを直接設定
+    pub fn set_quant_tables(&mut self, tables: &QuantTables) {
+        self.qtables = *tables;
+    }
+
+    /// 現在の量子化テーブルを取得
+    pub fn quant_tables(&self) -> &QuantTables {
+        &self.qtables
+    }
+
+    /// RGB8の画像データをJPEGにエンコード
+    pub fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>> {
+        let (width, height) = (self.width, self.height);
+        if width == 0 || height == 0 || width > 0xFFFF || height > 0xFFFF {
+            return Err(Error::InvalidConfig(format!(
+                "invalid frame size {}x{}", width, height
+            )));
+        }
+        if img_data.len() < width * height * 3 {
+            return Err(Error::InvalidConfig(format!(
+                "image data too small: {} bytes for {}x{}", img_data.len(), width, height
+            )));
+        }
+
+        let mut out = Vec::with_capacity(width * height / 4);
+        self.write_headers(&mut out);
+
+        let dc_luma = HuffTable::new(&DC_LUMA_BITS, &DC_LUMA_VALS);
+        let ac_luma = HuffTable::new(&AC_LUMA_BITS, &AC_LUMA_VALS);
+        let dc_chroma = HuffTable::new(&DC_CHROMA_BITS, &DC_CHROMA_VALS);
+        let ac_chroma = HuffTable::new(&AC_CHROMA_BITS, &AC_CHROMA_VALS);
+
+        let cos_table = dct_table();
+        let mut writer = BitWriter::new(out);
+        let mut prev_dc = [0i32; 3];
+
+        //MCU(16x16)単位で処理
+        for mcu_y in (0..height).step_by(16) {
+            for mcu_x in (0..width).step_by(16) {
+                let (y_blocks, cb, cr) = self.load_mcu(img_data, mcu_x, mcu_y);
+
+                for block in y_blocks.iter() {
+                    Self::encode_block(&mut writer, &cos_table, block, &self.qtables.luma,
+                                       &mut prev_dc[0], &dc_luma, &ac_luma);
+                }
+                Self::encode_block(&mut writer, &cos_table, &cb, &self.qtables.chroma,
+                                   &mut prev_dc[1], &dc_chroma, &ac_chroma);
+                Self::encode_block(&mut writer, &cos_table, &cr, &self.qtables.chroma,
+                                   &mut prev_dc[2], &dc_chroma, &ac_chroma);
+            }
+        }
+
+        let mut out = writer.finish();
+        out.extend_from_slice(&[0xFF, 0xD9]);
+        Ok(out)
+    }
+
+    /// RGB8の画像データをJPEGにエンコードしてファイルに出力
+    pub fn encode_file(&mut self, img_data: &[u8], o_file_name: &str) -> Result<()> {
+        let out = self.encode(img_data)?;
+        std::fs::write(o_file_name, out)?;
+        Ok(())
+    }
+
+    //16x16のMCUを読み込み、YCbCrに変換(端は複製で埋める)
+    fn load_mcu(&self, img: &[u8], mcu_x: usize, mcu_y: usize) -> ([[f32; 64]; 4], [f32; 64], [f32; 64]) {
+        let mut y_blocks = [[0f32; 64]; 4];
+        let mut cb = [0f32; 64];
+        let mut cr = [0f32; 64];
+
+        for dy in 0..16 {
+            let y = (mcu_y + dy).min(self.height - 1);
+            for dx in 0..16 {
+                let x = (mcu_x + dx).min(self.width - 1);
+                let p = (y * self.width + x) * 3;
+                let (r, g, b) = (img[p] as f32, img[p + 1] as f32, img[p + 2] as f32);
+
+                let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+                let block = (dy / 8) * 2 + dx / 8;
+                y_blocks[block][(dy % 8) * 8 + dx % 8] = luma - 128.0;
+
+                //4:2:0 なので2x2画素の平均をとる
+                let ci = (dy / 2) * 8 + dx / 2;
+                cb[ci] += (-0.168736 * r - 0.331264 * g + 0.5 * b) / 4.0;
+                cr[ci] += (0.5 * r - 0.418688 * g - 0.081312 * b) / 4.0;
+            }
+        }
+
+        (y_blocks, cb, cr)
+    }
+
+    fn encode_block(writer: &mut BitWriter, cos_table: &[[f32; 8]; 8], block: &[f32; 64],
+                    qtable: &[u8; 64], prev_dc: &mut i32, dc_table: &HuffTable, ac_table: &HuffTable) {
+        let coef = fdct(cos_table, block);
+
+        let mut zz = [0i32; 64];
+        for (k, &n) in ZIGZAG.iter().enumerate() {
+            zz[k] = (coef[n] / qtable[n] as f32).round() as i32;
+        }
+
+        write_block(writer, &zz, prev_dc, dc_table, ac_table);
+    }
+
+    fn write_headers(&self, out: &mut Vec<u8>) {
+        //SOI
+        out.extend_from_slice(&[0xFF, 0xD8]);
+
+        //APP0 (JFIF)
+        out.extend_from_slice(&[
+            0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00,
+            0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+        ]);
+
+        //DQT
+        for (id, table) in [(0u8, &self.qtables.luma), (1u8, &self.qtables.chroma)] {
+            out.extend_from_slice(&[0xFF, 0xDB, 0x00, 0x43, id]);
+            out.extend(ZIGZAG.iter().map(|&n| table[n]));
+        }
+
+        //SOF0
+        let (w, h) = (self.width as u16, self.height as u16);
+        out.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 0x08]);
+        out.extend_from_slice(&h.to_be_bytes());
+        out.extend_from_slice(&w.to_be_bytes());
+        out.extend_from_slice(&[0x03, 0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01]);
+
+        //DHT
+        write_std_dht(out);
+
+        //SOS
+        out.extend_from_slice(&[
+            0xFF, 0xDA, 0x00, 0x0C, 0x03, 0x01, 0x00, 0x02, 0x11, 0x03, 0x11, 0x00, 0x3F, 0x00,
+        ]);
+    }
+}
+
+//量子化済みの係数(ジグザグ順)をハフマン符号化して書き込む
+pub(crate) fn write_block(writer: &mut BitWriter, zz: &[i32; 64], prev_dc: &mut i32,
+                          dc_table: &HuffTable, ac_table: &HuffTable) {
+    //DC成分は前ブロックとの差分
+    let diff = zz[0] - *prev_dc;
+    *prev_dc = zz[0];
+    let cat = category(diff);
+    let (code, len) = dc_table.codes[cat as usize];
+    writer.put(code, len);
+    if cat > 0 {
+        writer.put(magnitude_bits(diff, cat), cat);
+    }
+
+    //AC成分はランレングス
+    let mut run = 0;
+    for &v in zz[1..].iter() {
+        if v == 0 {
+            run += 1;
+            continue;
+        }
+        while run >= 16 {
+            let (code, len) = ac_table.codes[0xF0];
+            writer.put(code, len);
+            run -= 16;
+        }
+        let cat = category(v);
+        let (code, len) = ac_table.codes[(run << 4) | cat as usize];
+        writer.put(code, len);
+        writer.put(magnitude_bits(v, cat), cat);
+        run = 0;
+    }
+    if run > 0 {
+        //EOB
+        let (code, len) = ac_table.codes[0x00];
+        writer.put(code, len);
+    }
+}
+
+//標準ハフマンテーブル(輝度: ID 0, 色差: ID 1)のDHTを書き込む
+pub(crate) fn write_std_dht(out: &mut Vec<u8>) {
+    let tables: [(u8, &[u8; 16], &[u8]); 4] = [
+        (0x00, &DC_LUMA_BITS, &DC_LUMA_VALS),
+        (0x10, &AC_LUMA_BITS, &AC_LUMA_VALS),
+        (0x01, &DC_CHROMA_BITS, &DC_CHROMA_VALS),
+        (0x11, &AC_CHROMA_BITS, &AC_CHROMA_VALS),
+    ];
+    for (class_id, bits, vals) in tables {
+        let len = (2 + 1 + 16 + vals.len()) as u16;
+        out.extend_from_slice(&[0xFF, 0xC4]);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.push(class_id);
+        out.extend_from_slice(bits);
+        out.extend_from_slice(vals);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream;
+
+    //座標で値が変わるテスト画像
+    fn pattern(width: usize, height: usize) -> Vec<u8> {
+        (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 13) as u8, (y * 7) as u8, ((x + y) * 5) as u8]
+            })
+            .collect()
+    }
+
+    fn encode(width: usize, height: usize, img: &[u8]) -> Vec<u8> {
+        let mut encoder = SoftJpegEncoder::new();
+        encoder.config(width, height);
+        encoder.encode(img).unwrap()
+    }
+
+    #[test]
+    fn odd_sizes_are_padded_to_mcus() {
+        for (width, height) in [(1, 1), (17, 9), (33, 31)] {
+            let out = encode(width, height, &pattern(width, height));
+            let info = bitstream::validate(&out, width, height).unwrap();
+            assert_eq!((info.frame.width as usize, info.frame.height as usize), (width, height));
+        }
+
+        //端の画素を複製して16の倍数にした画像と同じスキャンデータになる
+        let (width, height) = (17, 9);
+        let img = pattern(width, height);
+        let padded: Vec<u8> = (0..32 * 16)
+            .flat_map(|i| {
+                let (x, y) = ((i % 32).min(width - 1), (i / 32).min(height - 1));
+                img[(y * width + x) * 3..][..3].to_vec()
+            })
+            .collect();
+        let out = encode(width, height, &img);
+        let padded_out = encode(32, 16, &padded);
+        let info = bitstream::parse(&out).unwrap();
+        let padded_info = bitstream::parse(&padded_out).unwrap();
+        assert_eq!(out[info.scan_data], padded_out[padded_info.scan_data]);
+    }
+
+    #[test]
+    fn quality_changes_dqt() {
+        let img = pattern(16, 16);
+        let mut encoder = SoftJpegEncoder::new();
+        encoder.config(16, 16);
+
+        let mut tables = Vec::new();
+        for quality in [10, 90] {
+            encoder.set_quality(quality).unwrap();
+            let dqt = quant::read_dqt(&encoder.encode(&img).unwrap()).unwrap();
+            let expected = QuantTables::from_quality(quality);
+            assert_eq!(dqt, [Some(expected.luma), Some(expected.chroma)]);
+            tables.push(dqt);
+        }
+        assert_ne!(tables[0], tables[1]);
+        assert!(encoder.set_quality(0).is_err());
+        assert!(encoder.set_quality(101).is_err());
+    }
+
+    #[test]
+    fn bit_writer_stuffs_ff_bytes() {
+        let mut writer = BitWriter::new(Vec::new());
+        writer.put(0xFF, 8);
+        writer.put(0x0F, 4);
+        writer.put(0x0F, 4);
+        writer.put(0x12, 8);
+        assert_eq!(writer.finish(), [0xFF, 0x00, 0xFF, 0x00, 0x12]);
+
+        //1で埋めた結果が0xFFになる場合もスタッフィングする
+        let mut writer = BitWriter::new(Vec::new());
+        writer.put(0x7F, 7);
+        assert_eq!(writer.finish(), [0xFF, 0x00]);
+
+        //RSTマーカーはスタッフィングしない
+        let mut writer = BitWriter::new(Vec::new());
+        writer.put(0b101, 3);
+        writer.restart(9);
+        assert_eq!(writer.finish(), [0xBF, 0xFF, 0xD1]);
+    }
+}
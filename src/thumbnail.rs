@@ -0,0 +1,163 @@
+use crate::encoder::Encoder;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, PixelFormat};
+use crate::metadata::{self, Metadata};
+use crate::quant;
+use crate::soft_jpeg::SoftJpegEncoder;
+
+/// サムネイルの設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThumbnailConfig {
+    /// 最大の幅(縦横比を保って縮小する)
+    pub max_width: usize,
+    /// 最大の高さ
+    pub max_height: usize,
+    pub quality: u32,
+    /// メインのJPEGのEXIF(APP1)にサムネイルを埋め込む
+    pub embed_exif: bool,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig { max_width: 160, max_height: 120, quality: 75, embed_exif: false }
+    }
+}
+
+/// メインのJPEGとサムネイル
+#[derive(Debug, Clone, PartialEq)]
+pub struct WithThumbnail {
+    pub jpeg: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+}
+
+/// 縦横比を保って最大サイズに収まるように縮小する(面積平均)
+///
+/// 最大サイズより小さい画像は拡大せずにRGB8に変換するだけ
+pub fn downscale(frame: &Frame, max_width: usize, max_height: usize) -> Result<Frame<'static>> {
+    if max_width == 0 || max_height == 0 {
+        return Err(Error::InvalidConfig(format!("invalid thumbnail size {}x{}", max_width, max_height)));
+    }
+    let (w, h) = (frame.width(), frame.height());
+    let scale = (max_width as f64 / w as f64).min(max_height as f64 / h as f64).min(1.0);
+    let tw = ((w as f64 * scale).round() as usize).clamp(1, max_width);
+    let th = ((h as f64 * scale).round() as usize).clamp(1, max_height);
+
+    let src = frame.to_rgb8();
+    let mut out = Vec::with_capacity(tw * th * 3);
+    for ty in 0..th {
+        //出力画素に対応する元画像の範囲
+        let y0 = ty * h / th;
+        let y1 = ((ty + 1) * h / th).max(y0 + 1);
+        for tx in 0..tw {
+            let x0 = tx * w / tw;
+            let x1 = ((tx + 1) * w / tw).max(x0 + 1);
+
+            let mut sum = [0u32; 3];
+            for y in y0..y1 {
+                let row = &src[(y * w + x0) * 3..(y * w + x1) * 3];
+                for p in row.chunks_exact(3) {
+                    sum[0] += p[0] as u32;
+                    sum[1] += p[1] as u32;
+                    sum[2] += p[2] as u32;
+                }
+            }
+            let n = ((y1 - y0) * (x1 - x0)) as u32;
+            out.extend(sum.iter().map(|&s| ((s + n / 2) / n) as u8));
+        }
+    }
+
+    Frame::new(tw, th, PixelFormat::Rgb8, out)
+}
+
+/// サムネイルのJPEGを作成(縮小とエンコードはソフトウェアで行う)
+pub fn thumbnail(frame: &Frame, config: &ThumbnailConfig) -> Result<Vec<u8>> {
+    quant::check_quality(config.quality)?;
+    let small = downscale(frame, config.max_width, config.max_height)?;
+
+    let mut encoder = SoftJpegEncoder::new();
+    encoder.config(small.width(), small.height());
+    encoder.set_quality(config.quality)?;
+    encoder.encode(small.data())
+}
+
+/// フレームをエンコードし、サムネイルも作成して一緒に返す
+///
+/// `embed_exif`ならメインのJPEGのEXIFのIFD1にもサムネイルを入れる
+pub fn encode_with_thumbnail<E: Encoder>(encoder: &mut E, frame: &Frame, config: &ThumbnailConfig) -> Result<WithThumbnail> {
+    let thumbnail = thumbnail(frame, config)?;
+    let mut jpeg = encoder.encode_frame(frame)?;
+
+    if config.embed_exif {
+        let meta = Metadata { thumbnail: Some(thumbnail.clone()), ..Metadata::default() };
+        jpeg = metadata::inject(&jpeg, &meta)?;
+    }
+
+    Ok(WithThumbnail { jpeg, thumbnail })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{self, APP1};
+
+    fn gray(width: usize, height: usize) -> Frame<'static> {
+        let data: Vec<u8> = (0..width * height).map(|i| (i % 251) as u8).collect();
+        Frame::new(width, height, PixelFormat::Gray8, data).unwrap()
+    }
+
+    fn size(frame: &Frame) -> (usize, usize) {
+        (frame.width(), frame.height())
+    }
+
+    #[test]
+    fn downscale_keeps_aspect_within_limits() {
+        assert_eq!(size(&downscale(&gray(640, 480), 160, 120).unwrap()), (160, 120));
+        assert_eq!(size(&downscale(&gray(640, 240), 160, 120).unwrap()), (160, 60));
+        assert_eq!(size(&downscale(&gray(100, 400), 160, 120).unwrap()), (30, 120));
+        assert_eq!(size(&downscale(&gray(1000, 3), 160, 120).unwrap()), (160, 1));
+        assert!(downscale(&gray(4, 4), 0, 120).is_err());
+    }
+
+    #[test]
+    fn downscale_never_upscales() {
+        let frame = gray(40, 30);
+        let small = downscale(&frame, 160, 120).unwrap();
+        assert_eq!(size(&small), (40, 30));
+        assert_eq!(small.format(), PixelFormat::Rgb8);
+        assert_eq!(small.data(), &frame.to_rgb8()[..]);
+    }
+
+    #[test]
+    fn downscale_averages_areas() {
+        //4x2を2x1に縮小すると2x2の平均(四捨五入)になる
+        let data: Vec<u8> = vec![
+            0, 0, 0,       10, 20, 30,   100, 100, 100, 200, 200, 200,
+            20, 40, 60,    1, 2, 3,      100, 100, 100, 201, 201, 201,
+        ];
+        let frame = Frame::new(4, 2, PixelFormat::Rgb8, data).unwrap();
+        let small = downscale(&frame, 2, 2).unwrap();
+        assert_eq!(size(&small), (2, 1));
+        assert_eq!(small.data(), [8, 16, 23, 150, 150, 150]);
+    }
+
+    #[test]
+    fn embedded_thumbnail_is_valid_exif() {
+        let frame = gray(64, 48);
+        let mut encoder = SoftJpegEncoder::new();
+        encoder.config(64, 48);
+        let config = ThumbnailConfig { max_width: 16, max_height: 16, embed_exif: true, ..ThumbnailConfig::default() };
+
+        let out = encode_with_thumbnail(&mut encoder, &frame, &config).unwrap();
+        bitstream::validate(&out.thumbnail, 16, 12).unwrap();
+        let info = bitstream::validate(&out.jpeg, 64, 48).unwrap();
+        let app1 = info.find(APP1).next().expect("APP1");
+        let payload = &out.jpeg[app1.payload()];
+        assert!(payload.starts_with(b"Exif\0\0"));
+        assert!(payload.windows(out.thumbnail.len()).any(|w| w == &out.thumbnail[..]));
+
+        //embed_exifでなければAPP1を入れない
+        let config = ThumbnailConfig { embed_exif: false, ..config };
+        let out = encode_with_thumbnail(&mut encoder, &frame, &config).unwrap();
+        assert!(bitstream::parse(&out.jpeg).unwrap().find(APP1).next().is_none());
+    }
+}
@@ -0,0 +1,675 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::bitstream::{self, FrameHeader, JpegInfo, DHT, DQT, DRI, SOS};
+use crate::encoder::Encoder;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, Roi};
+use crate::soft_jpeg::{
+    self, BitWriter, HuffTable, AC_CHROMA_BITS, AC_CHROMA_VALS, AC_LUMA_BITS, AC_LUMA_VALS,
+    DC_CHROMA_BITS, DC_CHROMA_VALS, DC_LUMA_BITS, DC_LUMA_VALS,
+};
+
+//タイルの幅・高さの単位(4:2:0のMCUの大きさ)
+const TILE_ALIGN: usize = 16;
+
+/// エンコードした1枚のタイル
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncodedTile {
+    pub row: usize,
+    pub col: usize,
+    /// 元画像の中の位置
+    pub roi: Roi,
+    pub data: Vec<u8>,
+}
+
+/// タイルごとのJPEGと、その並び
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileSet {
+    /// 元画像の幅
+    pub width: usize,
+    /// 元画像の高さ
+    pub height: usize,
+    pub rows: usize,
+    pub cols: usize,
+    pub tiles: Vec<EncodedTile>,
+}
+
+impl TileSet {
+    /// タイルのファイル名
+    pub fn file_name(tile: &EncodedTile) -> String {
+        format!("tile_{}_{}.jpg", tile.row, tile.col)
+    }
+
+    /// タイルの並びを表すJSON
+    pub fn manifest(&self) -> Value {
+        let tiles: Vec<Value> = self
+            .tiles
+            .iter()
+            .map(|tile| {
+                json!({
+                    "file": TileSet::file_name(tile),
+                    "row": tile.row,
+                    "col": tile.col,
+                    "x": tile.roi.x,
+                    "y": tile.roi.y,
+                    "width": tile.roi.width,
+                    "height": tile.roi.height,
+                })
+            })
+            .collect();
+        json!({
+            "width": self.width,
+            "height": self.height,
+            "rows": self.rows,
+            "cols": self.cols,
+            "tiles": tiles,
+        })
+    }
+
+    /// ディレクトリにタイルとmanifest.jsonを書き出す
+    pub fn write_dir<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        for tile in &self.tiles {
+            fs::write(dir.join(TileSet::file_name(tile)), &tile.data)?;
+        }
+        let manifest = serde_json::to_string_pretty(&self.manifest()).map_err(std::io::Error::from)?;
+        fs::write(dir.join("manifest.json"), manifest)?;
+        Ok(())
+    }
+}
+
+/// エンコーダの最大サイズより大きい画像をタイルに分けてエンコードする
+///
+/// タイルの幅・高さは(最後のタイルを除いて)16の倍数にそろえる。
+/// `encode`はタイルのハフマン符号を復号して係数をつなぎ直し、1枚のベースラインJPEGにする
+/// (再量子化はしないので画質は変わらない)。MCU行ごとにリスタートマーカーを入れる
+pub struct TiledEncoder<E: Encoder> {
+    encoder: E,
+    max_width: usize,
+    max_height: usize,
+}
+
+impl<E: Encoder> TiledEncoder<E> {
+    /// タイルの最大サイズを指定して作成
+    ///
+    /// ハードウェアエンコーダでは`JpegEncoder::hw_info`の`max_width`/`max_height`を渡す
+    pub fn new(encoder: E, max_width: usize, max_height: usize) -> Result<Self> {
+        if max_width < TILE_ALIGN || max_height < TILE_ALIGN {
+            return Err(Error::InvalidConfig(format!(
+                "tile size {}x{} must be at least {}x{}", max_width, max_height, TILE_ALIGN, TILE_ALIGN
+            )));
+        }
+        Ok(TiledEncoder { encoder, max_width, max_height })
+    }
+
+    /// 画像をどのように分割するか(行ごとのタイルの領域)
+    pub fn layout(&self, width: usize, height: usize) -> Vec<Vec<Roi>> {
+        let cols = spans(width, self.max_width);
+        spans(height, self.max_height)
+            .into_iter()
+            .map(|(y, h)| cols.iter().map(|&(x, w)| Roi::new(x, y, w, h)).collect())
+            .collect()
+    }
+
+    /// タイルごとにエンコードする
+    pub fn encode_tiles(&mut self, frame: &Frame) -> Result<TileSet> {
+        let layout = self.layout(frame.width(), frame.height());
+        let mut tiles = Vec::new();
+        for (row, rois) in layout.iter().enumerate() {
+            for (col, &roi) in rois.iter().enumerate() {
+                let data = self.encode_tile(frame, roi)?;
+                tiles.push(EncodedTile { row, col, roi, data });
+            }
+        }
+
+        Ok(TileSet {
+            width: frame.width(),
+            height: frame.height(),
+            rows: layout.len(),
+            cols: layout.first().map_or(0, |r| r.len()),
+            tiles,
+        })
+    }
+
+    /// タイルごとにエンコードし、1枚のJPEGにつなぐ
+    pub fn encode(&mut self, frame: &Frame) -> Result<Vec<u8>> {
+        if frame.width() > 0xFFFF || frame.height() > 0xFFFF {
+            return Err(Error::SizeExceeded { what: "JPEG frame", size: frame.width().max(frame.height()), limit: 0xFFFF });
+        }
+
+        let layout = self.layout(frame.width(), frame.height());
+        if layout.len() == 1 && layout[0].len() == 1 {
+            return self.encode_tile(frame, layout[0][0]);
+        }
+
+        let mut stitcher: Option<Stitcher> = None;
+        //タイルの行(帯)ごとに復号してつなぐ
+        for rois in &layout {
+            let mut band = Vec::with_capacity(rois.len());
+            for &roi in rois {
+                let jpeg = self.encode_tile(frame, roi)?;
+                let info = bitstream::validate(&jpeg, roi.width, roi.height)?;
+                let stitcher = match &mut stitcher {
+                    Some(stitcher) => stitcher,
+                    None => stitcher.insert(Stitcher::new(&jpeg, &info, frame.width(), frame.height())?),
+                };
+                band.push(stitcher.decode_tile(&jpeg, &info, roi)?);
+            }
+            if let Some(stitcher) = &mut stitcher {
+                stitcher.write_band(&band);
+            }
+        }
+
+        stitcher.map(Stitcher::finish).ok_or_else(|| Error::InvalidConfig("empty frame".to_string()))
+    }
+
+    /// 中のエンコーダ
+    pub fn encoder_mut(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    pub fn into_inner(self) -> E {
+        self.encoder
+    }
+
+    fn encode_tile(&mut self, frame: &Frame, roi: Roi) -> Result<Vec<u8>> {
+        let tile = crop(frame, roi)?;
+        self.encoder.config(tile.width(), tile.height())?;
+        self.encoder.encode_frame(&tile)
+    }
+}
+
+//長さtotalをmax以下の区間に分ける(最後以外はTILE_ALIGNの倍数)
+fn spans(total: usize, max: usize) -> Vec<(usize, usize)> {
+    if total <= max {
+        return vec![(0, total)];
+    }
+    let step = max / TILE_ALIGN * TILE_ALIGN;
+    (0..total).step_by(step).map(|start| (start, step.min(total - start))).collect()
+}
+
+//フレームから領域を切り出す
+fn crop(frame: &Frame, roi: Roi) -> Result<Frame<'static>> {
+    if !roi.fits(frame.width(), frame.height()) {
+        return Err(Error::InvalidConfig(format!(
+            "region {:?} is outside the {}x{} frame", roi, frame.width(), frame.height()
+        )));
+    }
+    let bpp = frame.format().bytes_per_pixel();
+    let row = roi.width * bpp;
+    let mut data = Vec::with_capacity(row * roi.height);
+    for y in roi.y..roi.y + roi.height {
+        let start = y * frame.stride() + roi.x * bpp;
+        data.extend_from_slice(&frame.data()[start..start + row]);
+    }
+    Frame::new(roi.width, roi.height, frame.format(), data)
+}
+
+fn invalid(msg: String) -> Error {
+    Error::InvalidBitstream(msg)
+}
+
+//ハフマン復号表(ITU-T T.81 F.2.2.3)
+struct HuffDecoder {
+    maxcode: [i32; 18],
+    mincode: [i32; 17],
+    valptr: [usize; 17],
+    vals: Vec<u8>,
+}
+
+impl HuffDecoder {
+    fn new(bits: &[u8], vals: &[u8]) -> Self {
+        let mut maxcode = [-1i32; 18];
+        let mut mincode = [0i32; 17];
+        let mut valptr = [0usize; 17];
+        let mut code = 0i32;
+        let mut k = 0;
+        for l in 1..=16 {
+            let n = bits[l - 1] as usize;
+            if n > 0 {
+                valptr[l] = k;
+                mincode[l] = code;
+                code += n as i32;
+                k += n;
+                maxcode[l] = code - 1;
+            }
+            code <<= 1;
+        }
+        //番兵
+        maxcode[17] = i32::MAX;
+        HuffDecoder { maxcode, mincode, valptr, vals: vals.to_vec() }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u8> {
+        let mut code = reader.bit() as i32;
+        let mut l = 1;
+        while code > self.maxcode[l] {
+            code = (code << 1) | reader.bit() as i32;
+            l += 1;
+            if l > 16 {
+                return Err(invalid(format!("invalid Huffman code at scan byte {}", reader.pos)));
+            }
+        }
+        self.vals
+            .get(self.valptr[l] + (code - self.mincode[l]) as usize)
+            .copied()
+            .ok_or_else(|| invalid(format!("Huffman code out of table at scan byte {}", reader.pos)))
+    }
+}
+
+//スタッフィングを取り除きながらビットを読む
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    acc: u32,
+    nbits: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, acc: 0, nbits: 0 }
+    }
+
+    fn bit(&mut self) -> u32 {
+        if self.nbits == 0 {
+            //マーカーに当たったら0を補う
+            let byte = match self.data.get(self.pos) {
+                Some(0xFF) if self.data.get(self.pos + 1) == Some(&0x00) => {
+                    self.pos += 2;
+                    0xFF
+                }
+                Some(0xFF) | None => 0,
+                Some(&b) => {
+                    self.pos += 1;
+                    b
+                }
+            };
+            self.acc = byte as u32;
+            self.nbits = 8;
+        }
+        self.nbits -= 1;
+        (self.acc >> self.nbits) & 1
+    }
+
+    fn receive(&mut self, s: u8) -> i32 {
+        let mut v = 0;
+        for _ in 0..s {
+            v = (v << 1) | self.bit() as i32;
+        }
+        v
+    }
+
+    //カテゴリsの値を符号付きに戻す
+    fn receive_extend(&mut self, s: u8) -> i32 {
+        if s == 0 {
+            return 0;
+        }
+        let v = self.receive(s);
+        if v < 1 << (s - 1) { v - (1 << s) + 1 } else { v }
+    }
+
+    //残りのビットを捨ててRSTマーカーを読み飛ばす
+    fn restart(&mut self) -> Result<()> {
+        self.nbits = 0;
+        match self.data.get(self.pos..self.pos + 2) {
+            Some([0xFF, m]) if (0xD0..=0xD7).contains(m) => {
+                self.pos += 2;
+                Ok(())
+            }
+            _ => Err(invalid(format!("expected RST marker at scan byte {}", self.pos))),
+        }
+    }
+}
+
+//タイルを復号した量子化済み係数(MCU順、ジグザグ順)
+struct DecodedTile {
+    mcus_x: usize,
+    blocks: Vec<[i32; 64]>,
+}
+
+//タイルの係数を1つのスキャンに書き直す
+struct Stitcher {
+    width: usize,
+    height: usize,
+    frame: FrameHeader,
+    dqt: Vec<u8>,
+    //成分ごとの(水平, 垂直)ブロック数
+    sampling: Vec<(usize, usize)>,
+    mcu_width: usize,
+    mcu_height: usize,
+    writer: BitWriter,
+    tables: [HuffTable; 4],
+    mcu_rows: usize,
+}
+
+impl Stitcher {
+    //最初のタイルからヘッダを作る
+    fn new(jpeg: &[u8], info: &JpegInfo, width: usize, height: usize) -> Result<Self> {
+        let frame = info.frame.clone();
+        if frame.marker != 0xC0 && frame.marker != 0xC1 {
+            return Err(invalid(format!("only sequential Huffman JPEGs can be stitched (SOF {:#04x})", frame.marker)));
+        }
+        if frame.components.len() > 4 {
+            return Err(invalid(format!("too many components: {}", frame.components.len())));
+        }
+
+        let (sampling, (mcu_width, mcu_height)) = if frame.components.len() == 1 {
+            (vec![(1, 1)], (8, 8))
+        } else {
+            (
+                frame.components.iter().map(|c| (c.h as usize, c.v as usize)).collect(),
+                frame.mcu_size(),
+            )
+        };
+        if !TILE_ALIGN.is_multiple_of(mcu_width) || !TILE_ALIGN.is_multiple_of(mcu_height) {
+            return Err(invalid(format!("MCU size {}x{} does not divide the tile alignment", mcu_width, mcu_height)));
+        }
+
+        let dqt: Vec<u8> = info.find(DQT).flat_map(|s| jpeg[s.range()].to_vec()).collect();
+
+        let mut out = vec![0xFF, 0xD8];
+        out.extend_from_slice(&dqt);
+
+        //SOF(サイズだけ書き換える)
+        let sof_len = 8 + frame.components.len() * 3;
+        out.extend_from_slice(&[0xFF, frame.marker]);
+        out.extend_from_slice(&(sof_len as u16).to_be_bytes());
+        out.push(frame.precision);
+        out.extend_from_slice(&(height as u16).to_be_bytes());
+        out.extend_from_slice(&(width as u16).to_be_bytes());
+        out.push(frame.components.len() as u8);
+        for c in &frame.components {
+            out.extend_from_slice(&[c.id, (c.h << 4) | c.v, c.tq]);
+        }
+
+        soft_jpeg::write_std_dht(&mut out);
+
+        //MCU行ごとにリスタート
+        let mcus_x = width.div_ceil(mcu_width);
+        if mcus_x > 0xFFFF {
+            return Err(Error::SizeExceeded { what: "restart interval", size: mcus_x, limit: 0xFFFF });
+        }
+        out.extend_from_slice(&[0xFF, DRI, 0x00, 0x04]);
+        out.extend_from_slice(&(mcus_x as u16).to_be_bytes());
+
+        //SOS(最初の成分は輝度、それ以外は色差のテーブル)
+        let ns = frame.components.len();
+        out.extend_from_slice(&[0xFF, SOS]);
+        out.extend_from_slice(&((6 + ns * 2) as u16).to_be_bytes());
+        out.push(ns as u8);
+        for (i, c) in frame.components.iter().enumerate() {
+            out.extend_from_slice(&[c.id, if i == 0 { 0x00 } else { 0x11 }]);
+        }
+        out.extend_from_slice(&[0x00, 0x3F, 0x00]);
+
+        Ok(Stitcher {
+            width,
+            height,
+            frame,
+            dqt,
+            sampling,
+            mcu_width,
+            mcu_height,
+            writer: BitWriter::new(out),
+            tables: [
+                HuffTable::new(&DC_LUMA_BITS, &DC_LUMA_VALS),
+                HuffTable::new(&AC_LUMA_BITS, &AC_LUMA_VALS),
+                HuffTable::new(&DC_CHROMA_BITS, &DC_CHROMA_VALS),
+                HuffTable::new(&AC_CHROMA_BITS, &AC_CHROMA_VALS),
+            ],
+            mcu_rows: 0,
+        })
+    }
+
+    fn blocks_per_mcu(&self) -> usize {
+        self.sampling.iter().map(|(h, v)| h * v).sum()
+    }
+
+    //タイルのスキャンを係数に復号する
+    fn decode_tile(&self, jpeg: &[u8], info: &JpegInfo, roi: Roi) -> Result<DecodedTile> {
+        //すべてのタイルが同じ形式・量子化テーブルであること
+        let frame = &info.frame;
+        if frame.components != self.frame.components || frame.marker != self.frame.marker {
+            return Err(invalid(format!("tile at {:?} has a different frame layout", roi)));
+        }
+        let dqt: Vec<u8> = info.find(DQT).flat_map(|s| jpeg[s.range()].to_vec()).collect();
+        if dqt != self.dqt {
+            return Err(invalid(format!("tile at {:?} has different quantization tables", roi)));
+        }
+        //右端・下端以外のタイルはMCUの倍数
+        if (roi.x + roi.width < self.width && !roi.width.is_multiple_of(self.mcu_width))
+            || (roi.y + roi.height < self.height && !roi.height.is_multiple_of(self.mcu_height))
+        {
+            return Err(invalid(format!("tile at {:?} is not MCU aligned", roi)));
+        }
+
+        //ハフマンテーブル
+        let mut dc: [Option<HuffDecoder>; 4] = Default::default();
+        let mut ac: [Option<HuffDecoder>; 4] = Default::default();
+        for s in info.find(DHT) {
+            let mut p = &jpeg[s.payload()];
+            while !p.is_empty() {
+                if p.len() < 17 {
+                    return Err(invalid("DHT is truncated".to_string()));
+                }
+                let (class, id) = (p[0] >> 4, (p[0] & 0x0F) as usize);
+                let count: usize = p[1..17].iter().map(|&n| n as usize).sum();
+                if id > 3 || p.len() < 17 + count {
+                    return Err(invalid("invalid DHT".to_string()));
+                }
+                let table = HuffDecoder::new(&p[1..17], &p[17..17 + count]);
+                if class == 0 { dc[id] = Some(table) } else { ac[id] = Some(table) }
+                p = &p[17 + count..];
+            }
+        }
+
+        //SOSの成分とテーブルの対応
+        let sos = info.find(SOS).next().ok_or_else(|| invalid("missing SOS".to_string()))?;
+        let p = &jpeg[sos.payload()];
+        let ns = p[0] as usize;
+        if ns != frame.components.len() || p.len() < 1 + ns * 2 + 3 {
+            return Err(invalid(format!("tile at {:?} has {} scan components, expected one interleaved scan", roi, ns)));
+        }
+        let (ss, se, a) = (p[1 + ns * 2], p[2 + ns * 2], p[3 + ns * 2]);
+        if ss != 0 || se != 63 || a != 0 {
+            return Err(invalid("only baseline scans can be stitched".to_string()));
+        }
+        let mut selectors = Vec::with_capacity(ns);
+        for (i, c) in frame.components.iter().enumerate() {
+            let (cs, t) = (p[1 + i * 2], p[2 + i * 2]);
+            if cs != c.id {
+                return Err(invalid(format!("scan component order differs from frame (id {})", cs)));
+            }
+            let d = dc[(t >> 4) as usize & 3].as_ref();
+            let a = ac[(t & 0x0F) as usize & 3].as_ref();
+            match (d, a) {
+                (Some(d), Some(a)) => selectors.push((d, a)),
+                _ => return Err(invalid(format!("missing Huffman table for component {}", c.id))),
+            }
+        }
+
+        let mcus_x = roi.width.div_ceil(self.mcu_width);
+        let mcus_y = roi.height.div_ceil(self.mcu_height);
+        let mut blocks = Vec::with_capacity(mcus_x * mcus_y * self.blocks_per_mcu());
+        let mut reader = BitReader::new(&jpeg[info.scan_data.clone()]);
+        let mut pred = vec![0i32; ns];
+
+        for mcu in 0..mcus_x * mcus_y {
+            if let Some(interval) = info.restart_interval {
+                if mcu > 0 && mcu % interval as usize == 0 {
+                    reader.restart()?;
+                    pred.iter_mut().for_each(|p| *p = 0);
+                }
+            }
+            for (c, &(h, v)) in self.sampling.iter().enumerate() {
+                let (dc, ac) = selectors[c];
+                for _ in 0..h * v {
+                    let mut zz = [0i32; 64];
+                    let s = dc.decode(&mut reader)?;
+                    pred[c] += reader.receive_extend(s);
+                    zz[0] = pred[c];
+
+                    let mut k = 1;
+                    while k < 64 {
+                        let rs = ac.decode(&mut reader)?;
+                        let (r, s) = ((rs >> 4) as usize, rs & 0x0F);
+                        if s == 0 {
+                            if r == 15 {
+                                k += 16;
+                                continue;
+                            }
+                            break;
+                        }
+                        k += r;
+                        if k > 63 {
+                            return Err(invalid(format!("AC run overflows block in tile at {:?}", roi)));
+                        }
+                        zz[k] = reader.receive_extend(s);
+                        k += 1;
+                    }
+                    blocks.push(zz);
+                }
+            }
+        }
+
+        Ok(DecodedTile { mcus_x, blocks })
+    }
+
+    //1行分のタイルを、MCU行ごとにつないで書き込む
+    fn write_band(&mut self, band: &[DecodedTile]) {
+        let bpm = self.blocks_per_mcu();
+        let rows = band.first().map_or(0, |t| t.blocks.len() / bpm / t.mcus_x.max(1));
+
+        for row in 0..rows {
+            if self.mcu_rows > 0 {
+                self.writer.restart(((self.mcu_rows - 1) % 8) as u8);
+            }
+            let mut pred = vec![0i32; self.sampling.len()];
+
+            for tile in band {
+                for mx in 0..tile.mcus_x {
+                    let mut b = (row * tile.mcus_x + mx) * bpm;
+                    for (c, &(h, v)) in self.sampling.iter().enumerate() {
+                        let (dc, ac) = if c == 0 { (&self.tables[0], &self.tables[1]) } else { (&self.tables[2], &self.tables[3]) };
+                        for _ in 0..h * v {
+                            soft_jpeg::write_block(&mut self.writer, &tile.blocks[b], &mut pred[c], dc, ac);
+                            b += 1;
+                        }
+                    }
+                }
+            }
+            self.mcu_rows += 1;
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        let mut out = self.writer.finish();
+        out.extend_from_slice(&[0xFF, 0xD9]);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::PixelFormat;
+    use crate::soft_jpeg::SoftJpegEncoder;
+
+    //タイルの境界をまたいで変化する画像
+    fn gradient(width: usize, height: usize) -> Frame<'static> {
+        let data = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| [(x * 7) as u8, (y * 5) as u8, ((x + y) * 3) as u8]))
+            .collect::<Vec<u8>>();
+        Frame::new(width, height, PixelFormat::Rgb8, data).unwrap()
+    }
+
+    //スキャンデータ中のRSTマーカーの番号
+    fn restart_markers(jpeg: &[u8], info: &JpegInfo) -> Vec<u8> {
+        jpeg[info.scan_data.clone()]
+            .windows(2)
+            .filter(|w| w[0] == 0xFF && (0xD0..=0xD7).contains(&w[1]))
+            .map(|w| w[1] - 0xD0)
+            .collect()
+    }
+
+    //JPEG全体を量子化済み係数に復号する
+    fn coefficients(jpeg: &[u8], width: usize, height: usize) -> Vec<[i32; 64]> {
+        let info = bitstream::validate(jpeg, width, height).unwrap();
+        let stitcher = Stitcher::new(jpeg, &info, width, height).unwrap();
+        stitcher.decode_tile(jpeg, &info, Roi::new(0, 0, width, height)).unwrap().blocks
+    }
+
+    //つないだ結果を検証し、フレームを1枚でエンコードした係数と比べる
+    fn check_stitched(width: usize, height: usize, max_width: usize, max_height: usize, rows: usize, cols: usize) {
+        let frame = gradient(width, height);
+        let mut tiled = TiledEncoder::new(SoftJpegEncoder::new(), max_width, max_height).unwrap();
+        let layout = tiled.layout(width, height);
+        assert_eq!((layout.len(), layout[0].len()), (rows, cols));
+
+        let out = tiled.encode(&frame).unwrap();
+        let info = bitstream::validate(&out, width, height).unwrap();
+        assert_eq!((info.frame.width as usize, info.frame.height as usize), (width, height));
+
+        //MCU行ごとにRST0から順に入る
+        let mcu_rows = height.div_ceil(16);
+        assert_eq!(info.restart_interval, Some(width.div_ceil(16) as u16));
+        let expected: Vec<u8> = (0..mcu_rows - 1).map(|i| (i % 8) as u8).collect();
+        assert_eq!(restart_markers(&out, &info), expected);
+
+        let mut whole = SoftJpegEncoder::new();
+        whole.config(width, height);
+        let reference = whole.encode(frame.data()).unwrap();
+        assert!(coefficients(&out, width, height) == coefficients(&reference, width, height));
+    }
+
+    #[test]
+    fn stitch_2x2_tiles() {
+        check_stitched(64, 48, 32, 32, 2, 2);
+    }
+
+    #[test]
+    fn stitch_3x1_tiles() {
+        //10 MCU行なのでRSTの番号が一周する
+        check_stitched(32, 160, 32, 64, 3, 1);
+    }
+
+    //タイルごとに品質を変えるエンコーダ
+    struct VaryingQuality {
+        inner: SoftJpegEncoder,
+        tiles: u32,
+    }
+
+    impl Encoder for VaryingQuality {
+        fn config(&mut self, frame_width: usize, frame_height: usize) -> Result<()> {
+            self.tiles += 1;
+            self.inner.set_quality(40 + self.tiles * 10)?;
+            Encoder::config(&mut self.inner, frame_width, frame_height)
+        }
+
+        fn set_quality(&mut self, quality: u32) -> Result<()> {
+            self.inner.set_quality(quality)
+        }
+
+        fn encode(&mut self, img_data: &[u8]) -> Result<Vec<u8>> {
+            self.inner.encode(img_data)
+        }
+
+        fn is_hardware(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn stitch_rejects_different_quant_tables() {
+        let encoder = VaryingQuality { inner: SoftJpegEncoder::new(), tiles: 0 };
+        let mut tiled = TiledEncoder::new(encoder, 32, 32).unwrap();
+        match tiled.encode(&gradient(64, 32)) {
+            Err(Error::InvalidBitstream(msg)) => assert!(msg.contains("quantization tables"), "{}", msg),
+            r => panic!("unexpected result {:?}", r.map(|out| out.len())),
+        }
+    }
+}
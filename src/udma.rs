@@ -7,14 +7,15 @@ use std::ptr;
 use std::os::unix::io::RawFd;
 use std::sync::atomic::{AtomicPtr, Ordering};
 use std::sync::{Arc,Mutex};
-use anyhow::{anyhow, Result, Context};
+use crate::error::{Error, Result};
 use std::path::Path;
 use log::info;
+use crate::devlock::{self, LockMode};
 
 //use std::sync::atomic::{AtomicPtr, Ordering};
 
 //u-dma-bufのOwner
-#[derive(PartialEq,Clone,Copy)]
+#[derive(Debug,PartialEq,Clone,Copy)]
 pub enum Owner{
     Cpu = 0,
     Device = 1
@@ -51,18 +52,37 @@ impl Udma {
     }
     
     pub fn open(buf_name: &str) -> Result<Self> {
+        Udma::open_with_lock(buf_name, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    pub fn open_with_lock(buf_name: &str, lock_mode: LockMode) -> Result<Self> {
         info!("{}",buf_name);
-        let phys_addr = Udma::get_phys_addr(buf_name)?;
-        let size = Udma::get_udma_size(buf_name)?;
+        //sysfsに属性が無ければデバイスが存在しない
+        let not_found = |e: io::Error| {
+            if e.kind() == io::ErrorKind::NotFound {
+                Error::DeviceNotFound(buf_name.to_string())
+            } else {
+                Error::Io(e)
+            }
+        };
+        let phys_addr = Udma::get_phys_addr(buf_name).map_err(not_found)?;
+        let size = Udma::get_udma_size(buf_name).map_err(not_found)?;
         
         let filename = format!("/dev/{}", buf_name);
-        let c_filename = CString::new(filename).unwrap();
+        let c_filename = CString::new(filename.clone()).unwrap();
 
         //let fd = unsafe { open(c_filename.as_ptr(), O_RDWR | O_SYNC) };
         let fd = unsafe { open(c_filename.as_ptr(), O_RDWR) };
         if fd < 0 {
-            return Err(anyhow::Error::from(std::io::Error::last_os_error()));
+            return Err(Error::Open { path: filename, source: io::Error::last_os_error() });
        } 
+
+        // 他のプロセスが使用していないかロックで確認
+        if let Err(e) = devlock::lock_device(fd, &filename, lock_mode) {
+            unsafe { close(fd) };
+            return Err(e);
+        }
         
         let buf = unsafe {
             mmap(
@@ -76,15 +96,17 @@ impl Udma {
         };
 
         if buf == libc::MAP_FAILED {
+            let source = io::Error::last_os_error();
             unsafe { close(fd) };
-            return Err(anyhow::Error::from(std::io::Error::last_os_error()));
+            return Err(Error::Mmap { path: filename, source });
         }
 
         //手動でのキャッシュ制御のためのファイル
         
         let direction_name = format!("/sys/class/u-dma-buf/{}/sync_direction", buf_name);
         let direction_path = Path::new(&direction_name);
-        let mut sync_direction = File::create(direction_path)?;
+        let cache_sync_err = |source| Error::CacheSync { name: buf_name.to_string(), source };
+        let mut sync_direction = File::create(direction_path).map_err(cache_sync_err)?;
 
         // let mut sync_direction = match File::create(direction_path)
         // {
@@ -97,11 +119,11 @@ impl Udma {
 
         let sfc_name = format!("/sys/class/u-dma-buf/{}/sync_for_cpu", buf_name);
         let sfc_path = Path::new(&sfc_name);
-        let mut sync_for_cpu = File::create(sfc_path)?;
+        let mut sync_for_cpu = File::create(sfc_path).map_err(cache_sync_err)?;
         
         let sfd_name = format!("/sys/class/u-dma-buf/{}/sync_for_device", buf_name);
         let sfd_path = Path::new(&sfd_name);
-        let mut sync_for_device = File::create(sfd_path)?;
+        let mut sync_for_device = File::create(sfd_path).map_err(cache_sync_err)?;
         
         Ok(Udma {
             name: buf_name.to_string(),
@@ -158,16 +180,28 @@ impl Udma {
     fn get_owner(&mut self) -> Result<Owner>{
         let so_name = format!("/sys/class/u-dma-buf/{}/sync_owner", self.name);
         let so_path = Path::new(&so_name);
-        let mut sync_owner = File::open(so_path)?;
+        let mut sync_owner = File::open(so_path).map_err(|e| self.cache_sync_err(e))?;
         
         let mut attr = String::new();
-        sync_owner.read_to_string(&mut attr).context("Failed to read sync_owner file")?;
-
-        let owner_value = attr.trim().parse::<u32>().context("Failed to parse owner as u32")?;
-        let owner = Owner::from(owner_value);
+        sync_owner.read_to_string(&mut attr).map_err(|e| self.cache_sync_err(e))?;
+
+        let owner = match attr.trim().parse::<u32>() {
+            Ok(0) => Owner::Cpu,
+            Ok(1) => Owner::Device,
+            _ => {
+                return Err(self.cache_sync_err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected sync_owner value {:?}", attr.trim()),
+                )));
+            }
+        };
         
         Ok(owner)
     }
+
+    fn cache_sync_err(&self, source: io::Error) -> Error {
+        Error::CacheSync { name: self.name.clone(), source }
+    }
  
 
     //バッファのオーナーを変更
@@ -180,16 +214,16 @@ impl Udma {
 //        let ow = self.get_owner()?;
         if owner == Owner::Device {
             write!(self.sync_for_device,"{}",1 as u8)
-                .context("Failed to write to sync_for_device")?;
+                .map_err(|e| self.cache_sync_err(e))?;
         }
         else{
             write!(self.sync_for_cpu,"{}",1 as u8)
-                .context("Failed to write to sync_for_cpu")?;
+                .map_err(|e| self.cache_sync_err(e))?;
         }
         let ow = self.get_owner()?;
         //オーナーが変わっているか確認
         if owner != ow{
-            return Err(anyhow::Error::msg("Failed to change owner"));
+            return Err(Error::OwnerChange { name: self.name.clone(), expected: owner, actual: ow });
         }
         Ok(())
     }
@@ -199,7 +233,7 @@ impl Udma {
     pub fn write_to_buf(&mut self, data: &[u8]) -> Result<()> {
         let data_len = data.len();
         if data_len > self.size {            
-            return Err(anyhow::Error::msg("Data size exceeds buffer size"));
+            return Err(Error::SizeExceeded { what: "data", size: data_len, limit: self.size });
         }
 
         //ownerをCPUにする
@@ -215,10 +249,26 @@ impl Udma {
         Ok(())
     }
 
+    /// バッファの先頭`len`バイトを直接書き込む
+    ///
+    /// 変換結果などを一時バッファを経由せずに書き込むときに使う
+    pub fn write_with<F: FnOnce(&mut [u8]) -> Result<()>>(&mut self, len: usize, f: F) -> Result<()> {
+        if len > self.size {
+            return Err(Error::SizeExceeded { what: "data", size: len, limit: self.size });
+        }
+
+        //ownerをCPUにする
+        self.change_owner(Owner::Cpu)?;
+
+        let buf = self.buf.lock().unwrap();
+        let dst = unsafe { std::slice::from_raw_parts_mut(buf.load(Ordering::SeqCst), len) };
+        f(dst)
+    }
+
 
     pub fn read_from_buf(&mut self, len: usize) -> Result<Vec<u8>> {        
         if len > self.size {
-            return Err(anyhow::Error::msg("Data size exceeds buffer size"));
+            return Err(Error::SizeExceeded { what: "data", size: len, limit: self.size });
         }
 
         let mut data = vec![0u8; len];
@@ -6,10 +6,15 @@ use std::io::{self, Read};
 use std::ptr;
 use std::os::unix::io::RawFd;
 use std::sync::{Arc, Mutex}; // Arc と Mutex をインポート
+use std::time::Duration;
+use crate::devlock::{self, LockMode};
 use serde::{Serialize, Deserialize};
-use anyhow::{anyhow, Result, Context};
+use crate::error::{Error, Result};
 use log::info;
 
+/// IPのレジスタ空間としてマップする大きさ
+pub const PAGE_SIZE: usize = 0x1000;
+
 
 
 // pub struct Uio{
@@ -121,15 +126,26 @@ pub struct Uio {
 
 impl Uio {
     pub fn new(uio_name: &str, page_size: usize) -> Result<Self> {
+        Uio::new_with_lock(uio_name, page_size, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    pub fn new_with_lock(uio_name: &str, page_size: usize, lock_mode: LockMode) -> Result<Self> {
         let dev_name = Uio::check_uio_num(uio_name)?;
 
         let filename = format!("/dev/{}", dev_name);
-        let c_filename = CString::new(filename).unwrap();
+        let c_filename = CString::new(filename.clone()).unwrap();
         
         // devファイルをオープン
         let fd = unsafe { open(c_filename.as_ptr(), O_RDWR) };
         if fd < 0 {
-            return Err(anyhow::Error::from(std::io::Error::last_os_error()));
+            return Err(Error::Open { path: filename, source: io::Error::last_os_error() });
+        }
+
+        // 他のプロセスが使用していないかロックで確認
+        if let Err(e) = devlock::lock_device(fd, &filename, lock_mode) {
+            unsafe { close(fd) };
+            return Err(e);
         }
          
         // mmap
@@ -145,8 +161,9 @@ impl Uio {
         };
 
         if mem == libc::MAP_FAILED {
+            let source = io::Error::last_os_error();
             unsafe { close(fd) };
-            return Err(anyhow::Error::from(std::io::Error::last_os_error()));
+            return Err(Error::Mmap { path: filename, source });
         }
 
         Ok(Uio {
@@ -165,7 +182,7 @@ impl Uio {
     }
 
     /// デバイスの名前を探す
-    fn check_uio_num(name: &str) -> io::Result<String> {
+    fn check_uio_num(name: &str) -> Result<String> {
         let dir = std::fs::read_dir("/sys/class/uio")?;
 
         for entry in dir {
@@ -183,7 +200,7 @@ impl Uio {
             }
         }
 
-        Err(io::Error::new(io::ErrorKind::NotFound, "no uio device found"))
+        Err(Error::DeviceNotFound(name.to_string()))
     }
 
     /// メモリに値を書き込み
@@ -199,4 +216,71 @@ impl Uio {
         let mem = self.mem.lock().unwrap();
         unsafe { ptr::read_volatile(mem.load(Ordering::SeqCst).add(addr / 4)) }  // AtomicPtrからポインタを取得して操作
     }
+
+    /// 割り込みを有効にする(UIOのデバイスファイルに1を書き込む)
+    ///
+    /// uio_pdrv_genirqでは割り込みのたびに無効になるので、待つ前に毎回呼ぶ
+    pub fn enable_irq(&self) -> Result<()> {
+        self.write_irq_control(1)
+    }
+
+    /// 割り込みを無効にする
+    pub fn disable_irq(&self) -> Result<()> {
+        self.write_irq_control(0)
+    }
+
+    fn write_irq_control(&self, val: u32) -> Result<()> {
+        let buf = val.to_ne_bytes();
+        let n = unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if n != buf.len() as isize {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    /// 割り込みを待ち、これまでの割り込みの回数を返す
+    pub fn wait_irq(&self, timeout: Duration) -> Result<u32> {
+        let mut pfd = libc::pollfd { fd: self.fd, events: libc::POLLIN, revents: 0 };
+        let ms = poll_timeout_ms(timeout);
+        let ret = loop {
+            let ret = unsafe { libc::poll(&mut pfd, 1, ms) };
+            //シグナルで中断された場合は待ち直す
+            if ret < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break ret;
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if ret == 0 {
+            return Err(Error::Timeout { what: "UIO interrupt", timeout });
+        }
+
+        let mut buf = [0u8; 4];
+        let n = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if n != buf.len() as isize {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(u32::from_ne_bytes(buf))
+    }
+}
+
+//pollのタイムアウト(ミリ秒)。1ミリ秒未満を切り捨てると早く戻るので切り上げる
+fn poll_timeout_ms(timeout: Duration) -> libc::c_int {
+    timeout.as_nanos().div_ceil(1_000_000).min(libc::c_int::MAX as u128) as libc::c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poll_timeout_rounds_up() {
+        assert_eq!(poll_timeout_ms(Duration::ZERO), 0);
+        assert_eq!(poll_timeout_ms(Duration::from_micros(1)), 1);
+        assert_eq!(poll_timeout_ms(Duration::from_millis(5)), 5);
+        assert_eq!(poll_timeout_ms(Duration::from_micros(5001)), 6);
+        assert_eq!(poll_timeout_ms(Duration::from_secs(u64::MAX)), libc::c_int::MAX);
+    }
 }
@@ -1,59 +1,182 @@
 use libc::{open, close, mmap, munmap, O_RDWR, PROT_READ, PROT_WRITE, MAP_SHARED};
+use std::cell::Cell;
 use std::ffi::CString;
 use std::fs::File;
 use std::io::{self, Read};
 use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::os::unix::io::RawFd;
 use serde::{Serialize, Deserialize};
-use anyhow::{anyhow, Result, Context};
+use crate::error::{Error, Result};
+use crate::convert;
+use crate::frame::{Frame, PixelFormat, Roi};
 use log::info;
 
 use crate::udma::{Udma,Owner};
-use crate::uio::Uio;
+use crate::uio::{Uio, PAGE_SIZE};
+use crate::devlock::LockMode;
+use crate::hwinfo::{param, IpInfo};
+
+
+// レジスタオフセット定義(v_frmbuf_wrと共通)
+pub(crate) const FRMBUF_CTRL: usize = 0x0000;
+pub(crate) const FRMBUF_WIDTH: usize = 0x0010;
+pub(crate) const FRMBUF_HEIGHT: usize = 0x0018;
+pub(crate) const FRMBUF_STRIDE: usize = 0x0020;
+pub(crate) const FRMBUF_FORMAT: usize = 0x0028;
+pub(crate) const FRMBUF_P1BUFFER: usize = 0x0030;
+
+//RGB8のメモリフォーマットID
+pub(crate) const FORMAT_RGB8: u32 = 20;
+
+// コントロールレジスタのビット
+pub(crate) const CTRL_AP_START: u32 = 1 << 0;
+const CTRL_AP_DONE: u32 = 1 << 1;
+const CTRL_AP_IDLE: u32 = 1 << 2;
+const CTRL_AP_READY: u32 = 1 << 3;
+const CTRL_AUTO_RESTART: u32 = 1 << 7;
+
+// 割り込みのレジスタ(グローバル有効, 有効, ステータス)
+const FRMBUF_GIER: usize = 0x0004;
+const FRMBUF_IER: usize = 0x0008;
+const FRMBUF_ISR: usize = 0x000C;
+
+/// 割り込み要因: ap_done
+pub const IRQ_AP_DONE: u32 = 1 << 0;
+/// 割り込み要因: ap_ready
+pub const IRQ_AP_READY: u32 = 1 << 1;
+
+//完了待ちのポーリング間隔
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+//AXI MMのデータ幅(バイト)。ストライドと開始アドレスはこの倍数にする
+pub(crate) const MM_WIDTH_BYTES: usize = 8;
+
+//RGB8で`width`画素の行を入れられる最小のストライド
+pub(crate) fn rgb8_stride(width: usize) -> usize {
+    (width * 3).div_ceil(MM_WIDTH_BYTES) * MM_WIDTH_BYTES
+}
+
+//ストライドが1行のバイト数以上で、AXI MMのデータ幅の倍数かどうか
+pub(crate) fn check_stride(stride: usize, width: usize) -> Result<()> {
+    if stride < width * 3 || !stride.is_multiple_of(MM_WIDTH_BYTES) {
+        return Err(Error::InvalidConfig(format!(
+            "stride {} must be at least {} and a multiple of {}", stride, width * 3, MM_WIDTH_BYTES
+        )));
+    }
+    Ok(())
+}
+
+//読み込みでクリアされたap_doneを完了待ちのために保持する
+#[derive(Debug, Default)]
+pub(crate) struct DoneLatch(Cell<bool>);
+
+impl DoneLatch {
+    //読んだ状態のap_doneを保持し、状態はそのまま返す
+    pub(crate) fn observe(&self, status: CtrlStatus) -> CtrlStatus {
+        if status.ap_done {
+            self.0.set(true);
+        }
+        status
+    }
 
-use xipdriver_rs::json_as_map;
-use xipdriver_rs::json_as_str;
-use xipdriver_rs::json_as_u32;
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.get()
+    }
 
+    pub(crate) fn clear(&self) {
+        self.0.set(false);
+    }
+}
 
-const PAGE_SIZE: usize = 0x1000;
+/// HLSのコントロールレジスタの状態
+///
+/// ap_doneはレジスタを読むとクリアされる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CtrlStatus {
+    pub ap_start: bool,
+    pub ap_done: bool,
+    pub ap_idle: bool,
+    pub ap_ready: bool,
+    pub auto_restart: bool,
+}
 
-// レジスタオフセット定義
-const FRMBUF_CTRL: usize = 0x0000;
-const FRMBUF_WIDTH: usize = 0x0010;
-const FRMBUF_HEIGHT: usize = 0x0018;
-const FRMBUF_STRIDE: usize = 0x0020;
-const FRMBUF_FORMAT: usize = 0x0028;
-const FRMBUF_P1BUFFER: usize = 0x0030;
+impl CtrlStatus {
+    pub fn from_bits(bits: u32) -> Self {
+        CtrlStatus {
+            ap_start: bits & CTRL_AP_START != 0,
+            ap_done: bits & CTRL_AP_DONE != 0,
+            ap_idle: bits & CTRL_AP_IDLE != 0,
+            ap_ready: bits & CTRL_AP_READY != 0,
+            auto_restart: bits & CTRL_AUTO_RESTART != 0,
+        }
+    }
+
+    pub fn bits(&self) -> u32 {
+        let bit = |set: bool, mask: u32| if set { mask } else { 0 };
+        bit(self.ap_start, CTRL_AP_START)
+            | bit(self.ap_done, CTRL_AP_DONE)
+            | bit(self.ap_idle, CTRL_AP_IDLE)
+            | bit(self.ap_ready, CTRL_AP_READY)
+            | bit(self.auto_restart, CTRL_AUTO_RESTART)
+    }
+}
 
 // vfb_t 構造体のRust版
 pub struct Vfb {
     // fd: RawFd,
     // mem: *mut u32,
     pub uio:Uio,
-    pub buf:Udma
+    pub buf:Udma,
+    width: usize,
+    height: usize,
+    stride: usize,
+    samples_per_clock: usize,
+    //連続モード(auto_restart)で開始したかどうか
+    continuous: bool,
+    //読み込みでクリアされたap_doneを`wait_done`のために保持する
+    done_latch: DoneLatch,
 }
 
 impl Vfb {
 
     pub fn new(hw_info: &serde_json::Value) -> Result<Self>{
-        //vfrmbufのハードウェア情報を取得
-        let hw_object = json_as_map!(hw_info);
-        let uio_name = json_as_str!(hw_object["uio"]);
-        let udmabuf_name = json_as_str!(hw_object["udmabuf"][0]);
-        
+        Vfb::new_with_lock(hw_info, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    pub fn new_with_lock(hw_info: &serde_json::Value, lock_mode: LockMode) -> Result<Self> {
+        //ハードウェア情報を検証して取得
+        let info = IpInfo::parse_entry(hw_info, true)?;
+        Vfb::from_info(&info, lock_mode)
+    }
+
+    /// 検証済みのハードウェア情報からオープン
+    pub fn from_info(info: &IpInfo, lock_mode: LockMode) -> Result<Self> {
+        let uio_name = &info.uio;
+        let udmabuf_name = info.first_udmabuf().ok_or_else(|| {
+            Error::InvalidConfig(format!("{} has no udmabuf", info.uio))
+        })?;
+
         //uioをオープン
         //let dev_name = Vfb::check_vfrmbuf_uio_num(uio_name)?;
-        let uio = Uio::new(&uio_name,PAGE_SIZE)?;
+        let uio = Uio::new_with_lock(&uio_name,PAGE_SIZE,lock_mode)?;
         
         //u-dma-bufferをオープン
-        let mut udmabuf = Udma::new(udmabuf_name)?;
+        let mut udmabuf = Udma::open_with_lock(udmabuf_name,lock_mode)?;
         
         Ok(Vfb {
             // fd,
             // mem: mem as *mut u32,
             uio: uio,
-            buf: udmabuf
+            buf: udmabuf,
+            width: 0,
+            height: 0,
+            stride: 0,
+            samples_per_clock: info.param_u32(param::SAMPLES_PER_CLOCK).unwrap_or(1).max(1) as usize,
+            continuous: false,
+            done_latch: DoneLatch::default(),
         })
 
         
@@ -105,20 +228,92 @@ impl Vfb {
     }
 
     /// 画像フォーマットを設定
-    pub fn set_format(&self, frame_width: usize, frame_height: usize) {
-        let fmd_id = 20; // RGB8
-        let mm_width_bytes = 1 * 8;
-        let bpp_numerator = 3;
-        let bpp_denominator = 1;
-
-        let stride = (((frame_width * bpp_numerator) / bpp_denominator + mm_width_bytes - 1)
-            / mm_width_bytes)
-            * mm_width_bytes;
+    pub fn set_format(&mut self, frame_width: usize, frame_height: usize) {
+        let stride = rgb8_stride(frame_width);
 
         self.write_mem32(FRMBUF_WIDTH, frame_width as u32);
         self.write_mem32(FRMBUF_HEIGHT, frame_height as u32);
         self.write_mem32(FRMBUF_STRIDE, stride as u32);
-        self.write_mem32(FRMBUF_FORMAT, fmd_id as u32);
+        self.write_mem32(FRMBUF_FORMAT, FORMAT_RGB8);
+
+        self.width = frame_width;
+        self.height = frame_height;
+        self.stride = stride;
+    }
+
+    /// ストライド(1行のバイト数)を設定
+    ///
+    /// `set_format`の後で、1行のバイト数以上かつ8バイトの倍数でなければエラー
+    pub fn set_stride(&mut self, stride: usize) -> Result<()> {
+        check_stride(stride, self.width)?;
+        self.write_mem32(FRMBUF_STRIDE, stride as u32);
+        self.stride = stride;
+        Ok(())
+    }
+
+    /// 設定したストライド
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// バッファ上のフレームのうち、`roi`の領域だけを読み出すように設定
+    ///
+    /// 幅・高さ・開始アドレスを書き換え、ストライドはフレーム全体のものを使う。
+    /// 開始アドレスが8バイト境界、幅が1クロックあたりの画素数の倍数でなければエラー
+    pub fn set_region(&mut self, roi: Roi) -> Result<()> {
+        if !roi.fits(self.width, self.height) {
+            return Err(Error::InvalidConfig(format!(
+                "region {:?} is outside the {}x{} frame", roi, self.width, self.height
+            )));
+        }
+        let offset = roi.y * self.stride + roi.x * 3;
+        if !offset.is_multiple_of(MM_WIDTH_BYTES) {
+            return Err(Error::InvalidConfig(format!(
+                "region {:?} starts at byte offset {}, which is not {}-byte aligned (x must be a multiple of {})",
+                roi, offset, MM_WIDTH_BYTES, MM_WIDTH_BYTES
+            )));
+        }
+        if !roi.width.is_multiple_of(self.samples_per_clock) {
+            return Err(Error::InvalidConfig(format!(
+                "region width {} must be a multiple of {} pixels per clock", roi.width, self.samples_per_clock
+            )));
+        }
+
+        self.write_mem32(FRMBUF_WIDTH, roi.width as u32);
+        self.write_mem32(FRMBUF_HEIGHT, roi.height as u32);
+        self.write_mem32(FRMBUF_P1BUFFER, self.buf.phys_addr + offset as u32);
+        Ok(())
+    }
+
+    /// `set_region`/`start_external`の設定を自分のバッファのフレーム全体に戻す
+    pub fn clear_region(&mut self) {
+        self.write_mem32(FRMBUF_WIDTH, self.width as u32);
+        self.write_mem32(FRMBUF_HEIGHT, self.height as u32);
+        self.write_mem32(FRMBUF_STRIDE, self.stride as u32);
+        self.set_phys_addr();
+    }
+
+    /// 別のバッファ(物理アドレス)にあるフレームを読み出して開始
+    ///
+    /// 取り込み用のu-dma-bufなどをCPUでコピーせずに直接読み出す。
+    /// サイズは`set_format`と同じであること。終わったら`clear_region`で戻す
+    pub fn start_external(&mut self, phys_addr: u32, stride: usize) -> Result<()> {
+        check_stride(stride, self.width)?;
+        if !(phys_addr as usize).is_multiple_of(MM_WIDTH_BYTES) {
+            return Err(Error::InvalidConfig(format!(
+                "buffer address {:#x} is not {}-byte aligned", phys_addr, MM_WIDTH_BYTES
+            )));
+        }
+
+        self.write_mem32(FRMBUF_STRIDE, stride as u32);
+        self.write_mem32(FRMBUF_P1BUFFER, phys_addr);
+        self.write_start();
+        Ok(())
+    }
+
+    //ストライドで並べたときのバッファのサイズ
+    fn frame_len(&self) -> usize {
+        if self.height == 0 { 0 } else { self.stride * (self.height - 1) + self.width * 3 }
     }
 
     /// コントロールレジスタを読み込む
@@ -141,22 +336,211 @@ impl Vfb {
         self.read_mem32(FRMBUF_P1BUFFER)
     }
 
+    /// コントロールレジスタの状態を読み込む
+    ///
+    /// レジスタのap_doneは読むとクリアされる(破壊的な読み出し)。
+    /// 読んだap_doneは内部に保持し、次の`wait_done`はそれを完了として扱う
+    pub fn status(&self) -> CtrlStatus {
+        self.done_latch.observe(CtrlStatus::from_bits(self.read_ctrl()))
+    }
+
+    /// アイドル状態かどうか(`status`と同じくap_doneをクリアする)
+    pub fn is_idle(&self) -> bool {
+        self.status().ap_idle
+    }
+
+    /// 連続モードで開始していて、まだ停止していないかどうか
+    ///
+    /// レジスタは読まない
+    pub fn is_continuous(&self) -> bool {
+        self.continuous
+    }
+
+    /// フレームの読み出しの完了(ap_done)をポーリングで待つ
+    ///
+    /// 前回の開始以降に`status`などで読んだap_doneも完了として扱う
+    pub fn wait_done(&self, timeout: Duration) -> Result<()> {
+        //すでに読んでクリアしたap_doneも見る
+        self.wait_status(timeout, "v_frmbuf_rd ap_done", |status| status.ap_done || self.done_latch.is_set())?;
+        self.done_latch.clear();
+        Ok(())
+    }
+
+    /// アイドル状態になるのをポーリングで待つ
+    pub fn wait_idle(&self, timeout: Duration) -> Result<()> {
+        self.wait_status(timeout, "v_frmbuf_rd ap_idle", |status| status.ap_idle)
+    }
+
+    fn wait_status(&self, timeout: Duration, what: &'static str, f: impl Fn(CtrlStatus) -> bool) -> Result<()> {
+        let started = Instant::now();
+        while !f(self.status()) {
+            if started.elapsed() > timeout {
+                return Err(Error::Timeout { what, timeout });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+
+    /// 割り込みを有効にする
+    ///
+    /// `mask`は`IRQ_AP_DONE`/`IRQ_AP_READY`の組み合わせ。UIOの割り込みも有効にする
+    pub fn enable_interrupts(&self, mask: u32) -> Result<()> {
+        self.write_mem32(FRMBUF_ISR, self.read_mem32(FRMBUF_ISR));
+        self.write_mem32(FRMBUF_IER, mask);
+        self.write_mem32(FRMBUF_GIER, 1);
+        self.uio.enable_irq()
+    }
+
+    /// 割り込みを無効にする
+    pub fn disable_interrupts(&self) -> Result<()> {
+        self.write_mem32(FRMBUF_GIER, 0);
+        self.write_mem32(FRMBUF_IER, 0);
+        self.uio.disable_irq()
+    }
+
+    /// 割り込みのステータス(ISR)を読み込む
+    pub fn interrupt_status(&self) -> u32 {
+        self.read_mem32(FRMBUF_ISR)
+    }
+
+    /// 割り込みのステータスをクリアする(1を書いたビットが反転する)
+    pub fn ack_interrupts(&self, mask: u32) {
+        self.write_mem32(FRMBUF_ISR, mask);
+    }
+
+    /// 割り込みを待ち、発生した要因をクリアして返す
+    ///
+    /// `enable_interrupts`で有効にしておくこと。返る前にUIOの割り込みを再度有効にする
+    pub fn wait_interrupt(&self, timeout: Duration) -> Result<u32> {
+        self.uio.wait_irq(timeout)?;
+        let status = self.interrupt_status();
+        self.ack_interrupts(status);
+        self.uio.enable_irq()?;
+        Ok(status)
+    }
+
+    /// フレームの読み出しの完了(ap_done)を割り込みで待つ
+    ///
+    /// タイムアウトのエラーには呼び出し側が指定した`timeout`を入れる
+    pub fn wait_done_irq(&self, timeout: Duration) -> Result<()> {
+        let timed_out = || Error::Timeout { what: "v_frmbuf_rd ap_done interrupt", timeout };
+        let started = Instant::now();
+        loop {
+            let remaining = timeout.checked_sub(started.elapsed()).ok_or_else(timed_out)?;
+            match self.wait_interrupt(remaining) {
+                Ok(status) if status & IRQ_AP_DONE != 0 => return Ok(()),
+                Ok(_) => {}
+                Err(Error::Timeout { .. }) => return Err(timed_out()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     /// フレームバッファを開始
     pub fn write_start(&self) {
-        self.write_mem32(FRMBUF_CTRL, 0x01);
+        self.done_latch.clear();
+        self.write_mem32(FRMBUF_CTRL, CTRL_AP_START);
     }
 
     /// フレームバッファを停止
-    pub fn stop(&self) {
+    ///
+    /// 連続モードも解除する。読み出し中のフレームは最後まで出力される
+    pub fn stop(&mut self) {
+        self.continuous = false;
         self.write_mem32(FRMBUF_CTRL, 0x00);
     }
 
+    /// バッファに書き込み済みのデータで連続モードを開始
+    ///
+    /// フレームの読み出しが終わるたびに自動で再スタートし、同じバッファを読み出し続ける。
+    /// バッファの内容は`update_frame`で更新できる
+    pub fn start_continuous(&mut self) -> Result<()> {
+        //ownerをDevice(PL)にする
+        self.buf.change_owner(Owner::Device)?;
+
+        self.done_latch.clear();
+        self.write_mem32(FRMBUF_CTRL, CTRL_AUTO_RESTART | CTRL_AP_START);
+        self.continuous = true;
+        Ok(())
+    }
 
+    /// 連続モードを解除し、読み出し中のフレームが終わるのを待つ
+    pub fn stop_continuous(&mut self, timeout: Duration) -> Result<()> {
+        self.stop();
+        self.wait_idle(timeout)
+    }
+
+    /// 連続モードで動作中にバッファのフレームを書き換える
+    ///
+    /// 再スタートはせず、書き込み後にキャッシュをフラッシュする。
+    /// 読み出し中のフレームには新旧のデータが混ざることがある
+    pub fn update_frame(&mut self, frame: &Frame, threads: usize) -> Result<()> {
+        self.write_frame(frame, threads)?;
+        self.buf.change_owner(Owner::Device)
+    }
 
+
+
+    /// 行を詰めたRGB8の画像データをバッファに書き込んで開始
+    ///
+    /// ストライドが1行のバイト数と異なる場合は1行ずつ並べ直す
     pub fn start(&mut self,img_buffer:&[u8]) -> Result<()>{
         //画像データをバッファに書き込み
-        self.buf.write_to_buf(&img_buffer)?;
+        let row = self.width * 3;
+        if self.stride == row || self.height == 0 {
+            self.buf.write_to_buf(img_buffer)?;
+        } else {
+            let (width, height, stride) = (self.width, self.height, self.stride);
+            self.buf.write_with(self.frame_len(), |dst| {
+                convert::convert_to_rgb8(img_buffer, PixelFormat::Rgb8, width, height, row, dst, stride, 1)
+            })?;
+        }
+
+        //ownerをDevice(PL)にする
+        self.buf.change_owner(Owner::Device)?;
+
+        self.write_start();
+
+        Ok(())
+    }
+
+    /// フレームをRGB8に変換しながらバッファに書き込んで開始
+    ///
+    /// フレームのサイズは`set_format`と同じであること。`threads`が2以上なら変換を並列に行う
+    pub fn start_frame(&mut self, frame: &Frame, threads: usize) -> Result<()>{
+        self.write_frame(frame, threads)?;
+        self.start_buffered()
+    }
+
+    /// フレームをRGB8に変換しながらバッファに書き込む(開始はしない)
+    ///
+    /// RGB8でストライドが8バイトの倍数ならそのストライドをハードウェアに設定してそのまま書き込み、
+    /// それ以外は設定したストライドに並べ直す
+    pub fn write_frame(&mut self, frame: &Frame, threads: usize) -> Result<()>{
+        if (frame.width(), frame.height()) != (self.width, self.height) {
+            return Err(Error::InvalidConfig(format!(
+                "frame size {}x{} does not match format {}x{}", frame.width(), frame.height(), self.width, self.height
+            )));
+        }
+
+        if frame.format() == PixelFormat::Rgb8 && frame.stride().is_multiple_of(MM_WIDTH_BYTES) {
+            if frame.stride() != self.stride {
+                self.set_stride(frame.stride())?;
+            }
+            self.buf.write_to_buf(&frame.data()[..self.frame_len()])
+        } else {
+            let stride = self.stride;
+            self.buf.write_with(self.frame_len(), |dst| {
+                convert::convert_to_rgb8(
+                    frame.data(), frame.format(), frame.width(), frame.height(), frame.stride(), dst, stride, threads,
+                )
+            })
+        }
+    }
 
+    /// バッファに書き込み済みのデータで開始
+    pub fn start_buffered(&mut self) -> Result<()>{
         //ownerをDevice(PL)にする
         self.buf.change_owner(Owner::Device)?;
 
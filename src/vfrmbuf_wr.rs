@@ -0,0 +1,225 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::devlock::LockMode;
+use crate::error::{Error, Result};
+use crate::frame::{Frame, PixelFormat};
+use crate::hwinfo::IpInfo;
+use crate::udma::{Owner, Udma};
+use crate::uio::{Uio, PAGE_SIZE};
+use crate::vfrmbuf::{
+    self, CtrlStatus, DoneLatch, CTRL_AP_START, FORMAT_RGB8, FRMBUF_CTRL, FRMBUF_FORMAT, FRMBUF_HEIGHT,
+    FRMBUF_P1BUFFER, FRMBUF_STRIDE, FRMBUF_WIDTH, POLL_INTERVAL,
+};
+
+/// Video Frame Buffer Write(カメラなどからDDRへの取り込み)
+pub struct Vfbw {
+    pub uio: Uio,
+    pub buf: Udma,
+    width: usize,
+    height: usize,
+    stride: usize,
+    //読み込みでクリアされたap_doneを`wait_done`のために保持する
+    done_latch: DoneLatch,
+}
+
+impl Vfbw {
+    pub fn new(hw_info: &serde_json::Value) -> Result<Self> {
+        Vfbw::new_with_lock(hw_info, LockMode::NoWait)
+    }
+
+    /// ロックの取得方法を指定してオープン
+    pub fn new_with_lock(hw_info: &serde_json::Value, lock_mode: LockMode) -> Result<Self> {
+        //ハードウェア情報を検証して取得
+        let info = IpInfo::parse_entry(hw_info, true)?;
+        Vfbw::from_info(&info, lock_mode)
+    }
+
+    /// 検証済みのハードウェア情報からオープン
+    pub fn from_info(info: &IpInfo, lock_mode: LockMode) -> Result<Self> {
+        let udmabuf_name = info.first_udmabuf().ok_or_else(|| {
+            Error::InvalidConfig(format!("{} has no udmabuf", info.uio))
+        })?;
+
+        //uioとu-dma-bufferをオープン
+        let uio = Uio::new_with_lock(&info.uio, PAGE_SIZE, lock_mode)?;
+        let buf = Udma::open_with_lock(udmabuf_name, lock_mode)?;
+
+        Ok(Vfbw { uio, buf, width: 0, height: 0, stride: 0, done_latch: DoneLatch::default() })
+    }
+
+    /// メモリとファイルディスクリプタをクローズ
+    pub fn close(&self) {
+        self.uio.close();
+        self.buf.close();
+    }
+
+    /// 物理アドレスを設定
+    pub fn set_phys_addr(&self) {
+        self.uio.write_mem32(FRMBUF_P1BUFFER, self.buf.phys_addr);
+    }
+
+    /// 画像フォーマット(RGB8)を設定
+    ///
+    /// ストライドは1行のバイト数を8バイトの倍数に切り上げたものにする
+    pub fn set_format(&mut self, frame_width: usize, frame_height: usize) -> Result<()> {
+        let stride = vfrmbuf::rgb8_stride(frame_width);
+        check_capture_size(stride, frame_height, self.buf.size)?;
+
+        self.uio.write_mem32(FRMBUF_WIDTH, frame_width as u32);
+        self.uio.write_mem32(FRMBUF_HEIGHT, frame_height as u32);
+        self.uio.write_mem32(FRMBUF_STRIDE, stride as u32);
+        self.uio.write_mem32(FRMBUF_FORMAT, FORMAT_RGB8);
+        self.set_phys_addr();
+
+        self.width = frame_width;
+        self.height = frame_height;
+        self.stride = stride;
+        Ok(())
+    }
+
+    /// ストライド(1行のバイト数)を設定
+    ///
+    /// `set_format`の後で、1行のバイト数以上かつ8バイトの倍数でなければエラー
+    pub fn set_stride(&mut self, stride: usize) -> Result<()> {
+        vfrmbuf::check_stride(stride, self.width)?;
+        check_capture_size(stride, self.height, self.buf.size)?;
+        self.uio.write_mem32(FRMBUF_STRIDE, stride as u32);
+        self.stride = stride;
+        Ok(())
+    }
+
+    /// 設定した画像サイズ
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// 設定したストライド
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// 取り込み先のu-dma-bufの物理アドレス
+    pub fn phys_addr(&self) -> u32 {
+        self.buf.phys_addr
+    }
+
+    /// コントロールレジスタを読み込む
+    pub fn read_ctrl(&self) -> u32 {
+        self.uio.read_mem32(FRMBUF_CTRL)
+    }
+
+    /// 1フレームの取り込みを開始
+    pub fn start(&mut self) -> Result<()> {
+        if self.height == 0 {
+            return Err(Error::InvalidConfig("capture format is not configured".to_string()));
+        }
+        //取り込み中はownerをDevice(PL)にする
+        self.buf.change_owner(Owner::Device)?;
+        self.done_latch.clear();
+        self.uio.write_mem32(FRMBUF_CTRL, CTRL_AP_START);
+        Ok(())
+    }
+
+    /// フレームバッファを停止
+    pub fn stop(&self) {
+        self.uio.write_mem32(FRMBUF_CTRL, 0x00);
+    }
+
+    /// コントロールレジスタの状態を読み込む
+    ///
+    /// レジスタのap_doneは読むとクリアされる(破壊的な読み出し)。
+    /// 読んだap_doneは内部に保持し、次の`is_done`/`wait_done`はそれを完了として扱う
+    pub fn status(&self) -> CtrlStatus {
+        self.done_latch.observe(CtrlStatus::from_bits(self.read_ctrl()))
+    }
+
+    /// 前回の開始以降に取り込みが完了したかどうか(ap_done)
+    pub fn is_done(&self) -> bool {
+        self.status().ap_done || self.done_latch.is_set()
+    }
+
+    /// 取り込みの完了をポーリングで待つ
+    ///
+    /// 前回の開始以降に`status`などで読んだap_doneも完了として扱う
+    pub fn wait_done(&self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        while !self.is_done() {
+            if started.elapsed() > timeout {
+                return Err(Error::Timeout { what: "v_frmbuf_wr ap_done", timeout });
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        self.done_latch.clear();
+        Ok(())
+    }
+
+    /// 1フレームを取り込んで完了を待つ
+    ///
+    /// 取り込んだデータはバッファに残り、`read_frame`で読み出すか
+    /// `JpegEncoder::encode_captured`でコピーせずにエンコードできる
+    pub fn capture(&mut self, timeout: Duration) -> Result<()> {
+        self.start()?;
+        self.wait_done(timeout)
+    }
+
+    /// 取り込んだフレームをCPU側に読み出す
+    pub fn read_frame(&mut self) -> Result<Frame<'static>> {
+        let len = if self.height == 0 { 0 } else { self.stride * (self.height - 1) + self.width * 3 };
+        let data = self.buf.read_from_buf(len)?;
+        Frame::with_stride(self.width, self.height, PixelFormat::Rgb8, self.stride, data)
+    }
+}
+
+//取り込むフレームがバッファに収まるかどうか
+fn check_capture_size(stride: usize, height: usize, limit: usize) -> Result<()> {
+    let size = stride.saturating_mul(height);
+    if size > limit {
+        return Err(Error::SizeExceeded { what: "capture frame", size, limit });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_stride_is_aligned_and_fits_buffer() {
+        //1行は8バイトの倍数に切り上げる
+        assert_eq!(vfrmbuf::rgb8_stride(640), 1920);
+        assert_eq!(vfrmbuf::rgb8_stride(642), 1928);
+
+        let stride = vfrmbuf::rgb8_stride(642);
+        assert!(check_capture_size(stride, 480, stride * 480).is_ok());
+        assert!(matches!(
+            check_capture_size(stride, 481, stride * 480),
+            Err(Error::SizeExceeded { what: "capture frame", size, limit }) if size == stride * 481 && limit == stride * 480
+        ));
+        assert!(matches!(check_capture_size(usize::MAX, 2, 1 << 20), Err(Error::SizeExceeded { .. })));
+    }
+
+    #[test]
+    fn stride_is_validated() {
+        assert!(vfrmbuf::check_stride(1920, 640).is_ok());
+        assert!(vfrmbuf::check_stride(2048, 640).is_ok());
+        //1行より短い
+        assert!(matches!(vfrmbuf::check_stride(1912, 640), Err(Error::InvalidConfig(_))));
+        //8バイトの倍数でない
+        assert!(matches!(vfrmbuf::check_stride(1924, 640), Err(Error::InvalidConfig(_))));
+        //大きなストライドはバッファに収まらない
+        assert!(check_capture_size(4096, 480, 1920 * 480).is_err());
+    }
+
+    #[test]
+    fn done_latch_keeps_cleared_ap_done() {
+        let latch = DoneLatch::default();
+        let done = CtrlStatus { ap_done: true, ..CtrlStatus::default() };
+        assert!(latch.observe(done).ap_done);
+        //次の読み込みではレジスタのap_doneはクリアされている
+        assert!(!latch.observe(CtrlStatus::default()).ap_done);
+        assert!(latch.is_set());
+        latch.clear();
+        assert!(!latch.is_set());
+    }
+}